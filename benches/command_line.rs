@@ -0,0 +1,68 @@
+//! compares writing `MAIL`/`RCPT` lines directly into the output buffer
+//! of `Io` with first collecting the parts of the line
+//!
+//! run with `cargo bench --features mock-impl`
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use new_tokio_smtp::command::{Mail, Recipient};
+use new_tokio_smtp::mock::MockSocket;
+use new_tokio_smtp::{ForwardPath, Io, ReversePath};
+
+fn mail() -> Mail {
+    let reverse_path = ReversePath::from_unchecked("sender@example.test");
+    Mail::new(reverse_path)
+        .extra_param("SIZE", Some("4096"))
+        .unwrap()
+        .extra_param("BODY", Some("8BITMIME"))
+        .unwrap()
+}
+
+fn recipient() -> Recipient {
+    Recipient::new(ForwardPath::from_unchecked("recipient@example.test"))
+}
+
+fn io() -> Io {
+    MockSocket::new_no_check_shutdown(vec![]).into()
+}
+
+/// the way lines with parameters were written before, collecting the parts first
+fn write_line_from_parts(io: &mut Io, cmd: &str, path: &str, mail: &Mail) {
+    let mut parts = vec![cmd, "<", path, ">"];
+    for (k, v) in mail.params.iter() {
+        parts.push(" ");
+        parts.push(k.as_str());
+        if let Some(v) = v.as_ref() {
+            parts.push("=");
+            parts.push(v.as_str());
+        }
+    }
+    io.write_line_from_parts(&parts)
+}
+
+fn bench_command_line(c: &mut Criterion) {
+    let mail = mail();
+    let recipient = recipient();
+
+    c.bench_function("write MAIL and RCPT into output buffer", |b| {
+        let mut io = io();
+        b.iter(|| {
+            mail.write_line(&mut io);
+            recipient.write_line(&mut io);
+            io.out_buffer(0).clear();
+        })
+    });
+
+    c.bench_function("write MAIL and RCPT from parts", |b| {
+        let mut io = io();
+        let reverse_path = mail.reverse_path.as_str();
+        let forward_path = recipient.forward_path.as_str();
+        b.iter(|| {
+            write_line_from_parts(&mut io, "MAIL FROM:", reverse_path, &mail);
+            io.write_line_from_parts(&["RCPT TO:<", forward_path, ">"]);
+            io.out_buffer(0).clear();
+        })
+    });
+}
+
+criterion_group!(benches, bench_command_line);
+criterion_main!(benches);
@@ -1,12 +1,19 @@
+use std::io as std_io;
+
 use base64::encode;
 use futures::future::{self, Either, Future};
 
 use super::validate_auth_capability;
 use crate::error::{LogicError, MissingCapabilities};
+use crate::event::ConnectionEvent;
 use crate::future_ext::ResultWithContextExt;
-use crate::{Cmd, EhloData, ExecFuture, Io};
+use crate::io::{emit_on_success, SmtpResult};
+use crate::{Cmd, EhloData, ExecFuture, Io, Response};
 
 /// Simple implementation of AUTH LOGIN for smtp.
+///
+/// Sends `AUTH LOGIN` and then answers the two `334` prompts of the server
+/// with the username and password (independent of the prompt text).
 #[derive(Debug, Clone)]
 pub struct Login {
     username: String,
@@ -40,26 +47,41 @@ impl Cmd for Login {
         validate_auth_capability(caps, "LOGIN")
     }
 
-    fn exec(self, mut io: Io) -> ExecFuture {
-        let Login { username, password } = self;
+    fn verb(&self) -> &'static str {
+        "AUTH"
+    }
 
-        io.write_line_from_parts(&["AUTH LOGIN", username.as_str()]);
+    fn is_sensitive(&self) -> bool {
+        true
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        let Login { username, password } = self;
 
+        // the text of the `334` prompts ("Username:"/"Password:") differs between
+        // servers (or is empty), so it's ignored and only the order is relied on
         let fut = io
-            .flush()
+            .flush_line_from_parts(&["AUTH LOGIN"])
             .and_then(Io::parse_response)
-            .ctx_and_then(move |io: Io, response| {
-                if !response.code().is_intermediate() {
-                    Either::A(future::ok((io, Err(LogicError::UnexpectedCode(response)))))
-                } else {
-                    let fut = io
-                        .flush_line_from_parts(&[password.as_str()])
-                        .and_then(Io::parse_response);
-
-                    Either::B(fut)
-                }
-            });
+            .ctx_and_then(move |io, response| answer_prompt(io, response, username))
+            .ctx_and_then(move |io, response| answer_prompt(io, response, password))
+            .map(emit_on_success(ConnectionEvent::Authenticated));
 
         Box::new(fut)
     }
 }
+
+fn answer_prompt(
+    io: Io,
+    prompt: Response,
+    answer: String,
+) -> impl Future<Item = (Io, SmtpResult), Error = std_io::Error> {
+    if !prompt.code().is_intermediate() {
+        Either::A(future::ok((io, Err(LogicError::UnexpectedCode(prompt)))))
+    } else {
+        let fut = io
+            .flush_line_from_parts(&[answer.as_str()])
+            .and_then(Io::parse_response);
+        Either::B(fut)
+    }
+}
@@ -1,5 +1,11 @@
-use crate::error::MissingCapabilities;
-use crate::{Capability, EhloData, EsmtpKeyword};
+use std::io as std_io;
+
+use futures::future::{self, Either, Future};
+
+use crate::error::{LogicError, MissingCapabilities};
+use crate::io::SmtpResult;
+use crate::response::codes;
+use crate::{Capability, Cmd, Connection, EhloData, EsmtpKeyword};
 
 mod login;
 pub use self::login::*;
@@ -7,7 +13,10 @@ pub use self::login::*;
 mod plain;
 pub use self::plain::*;
 
-const CAP_AUTH: &str = "AUTH";
+mod xoauth2;
+pub use self::xoauth2::*;
+
+pub(crate) const CAP_AUTH: &str = "AUTH";
 
 fn validate_auth_capability(
     caps: Option<&EhloData>,
@@ -26,3 +35,57 @@ fn validate_auth_capability(
             MissingCapabilities::new(vec![mcap])
         })
 }
+
+/// Provides the auth command to use, e.g. with a fresh access token for `XOAuth2`
+pub trait CredentialProvider: Send + 'static {
+    /// the auth command created from the credentials
+    type Cmd: Cmd + Send;
+
+    /// fetches the credentials
+    ///
+    /// `rejected` is true if the server rejected the previously fetched
+    /// credentials, i.e. they have to be refreshed.
+    fn fetch(
+        &mut self,
+        rejected: bool,
+    ) -> Box<dyn Future<Item = Self::Cmd, Error = LogicError> + Send>;
+}
+
+/// authenticates using the credentials from the provider, refreshing them once if they are rejected
+///
+/// If the server rejects the credentials with `535` the provider is asked
+/// for new credentials (with `rejected` set to true) and authentication is
+/// retried once. If fetching the credentials fails the error is returned
+/// as the result of the authentication.
+pub fn authenticate<P>(
+    con: Connection,
+    provider: P,
+) -> impl Future<Item = (Connection, SmtpResult), Error = std_io::Error> + Send
+where
+    P: CredentialProvider,
+{
+    let mut provider = provider;
+    let first = provider.fetch(false);
+    send_fetched(con, first).and_then(move |(con, result)| match result {
+        Err(LogicError::Code(ref response))
+            if response.code() == codes::AUTH_CREDENTIALS_INVALID =>
+        {
+            let refreshed = provider.fetch(true);
+            Either::A(send_fetched(con, refreshed))
+        }
+        result => Either::B(future::ok((con, result))),
+    })
+}
+
+fn send_fetched<C>(
+    con: Connection,
+    fetch: Box<dyn Future<Item = C, Error = LogicError> + Send>,
+) -> impl Future<Item = (Connection, SmtpResult), Error = std_io::Error> + Send
+where
+    C: Cmd + Send,
+{
+    fetch.then(move |fetched| match fetched {
+        Ok(cmd) => Either::A(con.send(cmd)),
+        Err(err) => Either::B(future::ok((con, Err(err)))),
+    })
+}
@@ -3,8 +3,11 @@ use std::fmt::{self, Display};
 use std::sync::Arc;
 
 use base64::encode;
+use futures::Future;
 
 use crate::error::MissingCapabilities;
+use crate::event::ConnectionEvent;
+use crate::io::emit_on_success;
 use crate::{Cmd, EhloData, ExecFuture, Io};
 
 use super::validate_auth_capability;
@@ -78,7 +81,10 @@ impl Plain {
             &self.authorization_identity, &self.authentication_identity, &self.password
         ));
 
-        io.exec_simple_cmd(&["AUTH PLAIN ", auth_str.as_str()])
+        let fut = io
+            .exec_simple_cmd(&["AUTH PLAIN ", auth_str.as_str()])
+            .map(emit_on_success(ConnectionEvent::Authenticated));
+        Box::new(fut)
     }
 }
 
@@ -87,6 +93,14 @@ impl Cmd for Plain {
         validate_auth_capability(caps, "PLAIN")
     }
 
+    fn verb(&self) -> &'static str {
+        "AUTH"
+    }
+
+    fn is_sensitive(&self) -> bool {
+        true
+    }
+
     fn exec(self, con: Io) -> ExecFuture {
         self.exec_ref(con)
     }
@@ -98,6 +112,14 @@ impl Cmd for Arc<Plain> {
         me.check_cmd_availability(caps)
     }
 
+    fn verb(&self) -> &'static str {
+        "AUTH"
+    }
+
+    fn is_sensitive(&self) -> bool {
+        true
+    }
+
     fn exec(self, con: Io) -> ExecFuture {
         self.exec_ref(con)
     }
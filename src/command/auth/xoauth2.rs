@@ -0,0 +1,83 @@
+use base64::encode;
+use futures::future::{self, Either, Future};
+
+use super::validate_auth_capability;
+use crate::error::{LogicError, MissingCapabilities};
+use crate::event::ConnectionEvent;
+use crate::future_ext::ResultWithContextExt;
+use crate::io::emit_on_success;
+use crate::{Cmd, EhloData, ExecFuture, Io};
+
+/// AUTH XOAUTH2 smtp authentication (as used by e.g. Gmail and Outlook)
+///
+/// Access tokens expire, see `CredentialProvider` for refreshing them.
+#[derive(Debug, Clone)]
+pub struct XOAuth2 {
+    user: String,
+    access_token: String,
+}
+
+impl XOAuth2 {
+    /// Create a new auth xoauth2 command from the user and the (not encoded) access token.
+    pub fn new<I1, I2>(user: I1, access_token: I2) -> Self
+    where
+        I1: Into<String>,
+        I2: Into<String>,
+    {
+        XOAuth2 {
+            user: user.into(),
+            access_token: access_token.into(),
+        }
+    }
+
+    /// Returns the user the command authenticates as.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    //intentionally no fn access_token(&self)!
+}
+
+impl Cmd for XOAuth2 {
+    fn check_cmd_availability(&self, caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        validate_auth_capability(caps, "XOAUTH2")
+    }
+
+    fn verb(&self) -> &'static str {
+        "AUTH"
+    }
+
+    fn is_sensitive(&self) -> bool {
+        true
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        let auth_str = encode(&format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.user, self.access_token
+        ));
+
+        let fut = io
+            .flush_line_from_parts(&["AUTH XOAUTH2 ", auth_str.as_str()])
+            .and_then(Io::parse_response)
+            .ctx_and_then(|io: Io, response| {
+                if !response.code().is_intermediate() {
+                    return Either::A(future::ok((io, Ok(response))));
+                }
+                // the 334 contains a (base64 encoded json) error description, the
+                // client has to answer it with an empty line to get the final error
+                let fut = io
+                    .flush_line_from_parts(&[])
+                    .and_then(Io::parse_response)
+                    .map(|(io, result)| {
+                        let result =
+                            result.and_then(|response| Err(LogicError::UnexpectedCode(response)));
+                        (io, result)
+                    });
+                Either::B(fut)
+            })
+            .map(emit_on_success(ConnectionEvent::Authenticated));
+
+        Box::new(fut)
+    }
+}
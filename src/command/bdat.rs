@@ -0,0 +1,94 @@
+use bytes::{BufMut, Bytes};
+use futures::Future;
+
+use crate::error::MissingCapabilities;
+use crate::event::ConnectionEvent;
+use crate::io::TransactionState;
+use crate::{Cmd, EhloData, ExecFuture, Io};
+
+/// the ehlo keyword of the CHUNKING extension
+pub const CAP_CHUNKING: &str = "CHUNKING";
+
+/// BDAT command of the CHUNKING extension (RFC 3030)
+///
+/// Sends one chunk of the mail, the last chunk has to be marked as last.
+/// Unlike `DATA` the chunk is send as is (no dot-stashing). Each chunk is
+/// answered with its own response, the response to the last chunk is the
+/// result of the mail transaction. See `pipelining::pipeline_chunks` for
+/// sending all chunks pipelined.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Bdat {
+    chunk: Bytes,
+    last: bool,
+}
+
+impl Bdat {
+    /// creates a new BDAT command, `last` marks the last chunk of the mail
+    pub fn new<B: Into<Bytes>>(chunk: B, last: bool) -> Self {
+        Bdat {
+            chunk: chunk.into(),
+            last,
+        }
+    }
+
+    /// the chunk send with this command
+    pub fn chunk(&self) -> &Bytes {
+        &self.chunk
+    }
+
+    /// true if this is the last chunk of the mail
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+
+    /// writes the command line followed by the chunk into the output buffer of `io`
+    pub(crate) fn write_cmd(&self, io: &mut Io) {
+        let size = self.chunk.len().to_string();
+        if self.last {
+            io.write_line_from_parts(&["BDAT ", size.as_str(), " LAST"]);
+        } else {
+            io.write_line_from_parts(&["BDAT ", size.as_str()]);
+        }
+        io.out_buffer(self.chunk.len()).put_slice(&self.chunk);
+    }
+}
+
+impl Cmd for Bdat {
+    fn check_cmd_availability(&self, caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        let has_chunking = caps
+            .map(|caps| caps.has_capability(CAP_CHUNKING))
+            .unwrap_or(false);
+        if has_chunking {
+            Ok(())
+        } else {
+            Err(MissingCapabilities::new_from_unchecked(CAP_CHUNKING))
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        "BDAT"
+    }
+
+    fn is_sensitive(&self) -> bool {
+        true
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        self.write_cmd(&mut io);
+        let is_last = self.is_last();
+        let fut = io
+            .flush()
+            .and_then(Io::parse_response)
+            .map(move |(mut io, result)| {
+                if is_last {
+                    // like with `DATA` the transaction ends with the response to the last chunk
+                    io.update_transaction_state(TransactionState::reset);
+                }
+                if is_last && result.is_ok() {
+                    io.emit_event(ConnectionEvent::TransactionCompleted);
+                }
+                (io, result)
+            });
+        Box::new(fut)
+    }
+}
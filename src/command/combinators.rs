@@ -50,6 +50,18 @@ where
             EitherCmd::B(b) => b.check_cmd_availability(caps),
         }
     }
+    fn verb(&self) -> &'static str {
+        match self {
+            EitherCmd::A(a) => a.verb(),
+            EitherCmd::B(b) => b.verb(),
+        }
+    }
+    fn is_sensitive(&self) -> bool {
+        match self {
+            EitherCmd::A(a) => a.is_sensitive(),
+            EitherCmd::B(b) => b.is_sensitive(),
+        }
+    }
     fn exec(self, con: Io) -> ExecFuture {
         match self {
             EitherCmd::A(a) => a.exec(con),
@@ -97,6 +109,13 @@ where
             .check_cmd_availability(caps)
             .or_else(|_| self.1.check_cmd_availability(caps))
     }
+    /// The verb of the first command, as it's not known which one will be used.
+    fn verb(&self) -> &'static str {
+        self.0.verb()
+    }
+    fn is_sensitive(&self) -> bool {
+        self.0.is_sensitive() || self.1.is_sensitive()
+    }
     fn exec(self, con: Io) -> ExecFuture {
         if self.0.check_cmd_availability(con.ehlo_data()).is_ok() {
             Box::new(self.0.exec(con))
@@ -1,17 +1,29 @@
-use std::io as std_io;
+use std::io::{self as std_io, Cursor};
 
-use bytes::{Buf, IntoBuf};
+use bytes::{Buf, Bytes, IntoBuf};
 use futures::future::{self, Either, Future};
 use futures::stream::{self, Stream};
+use futures::{try_ready, Async, Poll};
 
-use crate::error::{LogicError, MissingCapabilities};
+use crate::error::{LogicError, MissingCapabilities, NoAcceptedRecipients, Not7BitClean};
+use crate::event::ConnectionEvent;
 use crate::future_ext::ResultWithContextExt;
-use crate::response::codes;
-use crate::{Cmd, EhloData, ExecFuture, Io};
+use crate::io::{emit_on_success, DotStashedWrite, ProgressFn, SmtpResult, TransactionState};
+use crate::response::{codes, EnhancedStatusCode, ResponseCode};
+use crate::{Cmd, Connection, EhloData, ExecFuture, Io, Response};
 
+/// The `DATA` command sending a mail body
+///
+/// If transaction-state tracking is enabled (see `Io::enable_transaction_tracking`)
+/// and no `RCPT` was accepted it fails with a `NoAcceptedRecipients` error
+/// without writing anything. This check can't be done if `DATA` is pipelined,
+/// as it's written before the responses to the `RCPT` commands are read.
 pub struct Data<S> {
     //TODO add parameter support
     source: S,
+    total: Option<usize>,
+    progress: Option<ProgressFn>,
+    check_7bit: bool,
 }
 
 impl<BF> Data<stream::Once<BF, std_io::Error>>
@@ -19,7 +31,9 @@ where
     BF: Buf,
 {
     pub fn from_buf<B: IntoBuf<Buf = BF>>(buf: B) -> Self {
-        Data::new(stream::once(Ok(buf.into_buf())))
+        let buf = buf.into_buf();
+        let total = buf.remaining();
+        Data::new(stream::once(Ok(buf))).with_total(total)
     }
 }
 
@@ -29,7 +43,261 @@ where
     S::Item: Buf,
 {
     pub fn new(source: S) -> Self {
-        Data { source }
+        Data {
+            source,
+            total: None,
+            progress: None,
+            check_7bit: false,
+        }
+    }
+
+    /// sets the total size of the body, passed to the progress callback
+    pub fn with_total(mut self, total: usize) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    /// sets a callback which is called each time a chunk of the body was written
+    ///
+    /// It's called with the number of bytes written so far and the total size
+    /// (if known, see `with_total`). The number of bytes written excludes the
+    /// dots added by dot-stashing, so in the last call it's equal to the body size.
+    pub fn with_progress<F>(mut self, progress: F) -> Self
+    where
+        F: FnMut(usize, Option<usize>) + Send + 'static,
+    {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// makes sure the body is 7-bit clean if the server doesn't support `8BITMIME`
+    ///
+    /// The first chunk of the body (for `from_buf` the whole body) is checked
+    /// before `DATA` is send, if it contains a `NUL` or a byte > 127 the command
+    /// fails with a `LogicError::Custom` containing a `Not7BitClean` error (which
+    /// contains the offset of the byte) and the connection stays usable.
+    ///
+    /// All other chunks are checked before they are written, as the server
+    /// already accepted `DATA` then writing the body fails with an `io::Error`
+    /// wrapping a `Not7BitClean` error, which closes the connection. The same
+    /// applies to all chunks if `DATA` is pipelined.
+    ///
+    /// Checking requires copying each chunk, so it's disabled by default.
+    pub fn check_7bit_clean(mut self) -> Self {
+        self.check_7bit = true;
+        self
+    }
+
+    /// sends the (dot-stashed) mail body, expects the 354 response to `DATA` to be already read
+    pub(crate) fn exec_body(
+        self,
+        io: Io,
+    ) -> impl Future<Item = (Io, SmtpResult), Error = std_io::Error> {
+        let Data {
+            source,
+            total,
+            progress,
+            check_7bit,
+        } = self;
+        let write = if check_7bit && !io.has_capability("8BITMIME") {
+            let source = SevenBitChecked { source, offset: 0 };
+            Either::A(write_body(io, source, total, progress))
+        } else {
+            Either::B(write_body(io, source, total, progress))
+        };
+        write
+            .and_then(Io::parse_response)
+            .map(|(mut io, result)| {
+                // the transaction ends with the final response, even if it's a failure
+                io.update_transaction_state(TransactionState::reset);
+                (io, result)
+            })
+            .map(emit_on_success(ConnectionEvent::TransactionCompleted))
+    }
+
+    /// sends this command on the connection returning a `DataAccepted` on success
+    ///
+    /// Like `Connection::send` but a positive response is converted into a
+    /// `DataAccepted`, a non-positive one is returned as `LogicError::UnexpectedCode`.
+    pub fn send_accepted(
+        self,
+        con: Connection,
+    ) -> impl Future<Item = (Connection, Result<DataAccepted, LogicError>), Error = std_io::Error>
+    where
+        S: Send + 'static,
+    {
+        con.send(self).map(|(con, result)| {
+            let result = result.and_then(|response| {
+                DataAccepted::from_response(&response)
+                    .ok_or_else(|| LogicError::UnexpectedCode(response))
+            });
+            (con, result)
+        })
+    }
+}
+
+fn write_body<S>(
+    io: Io,
+    source: S,
+    total: Option<usize>,
+    progress: Option<ProgressFn>,
+) -> DotStashedWrite<S>
+where
+    S: Stream<Error = std_io::Error>,
+    S::Item: Buf,
+{
+    if let Some(progress) = progress {
+        io.write_dot_stashed_with_progress(source, total, progress)
+    } else {
+        io.write_dot_stashed(source)
+    }
+}
+
+/// a body stream failing on the first byte which is not 7-bit clean
+struct SevenBitChecked<S> {
+    source: S,
+    /// the offset of the next chunk in the body
+    offset: usize,
+}
+
+/// returns the index and value of the first byte which is not 7-bit clean
+fn find_non_7bit(chunk: &[u8]) -> Option<(usize, u8)> {
+    chunk
+        .iter()
+        .position(|&bch| bch == 0 || bch > 127)
+        .map(|idx| (idx, chunk[idx]))
+}
+
+/// checks the first chunk of the body and sends `DATA` only if it's 7-bit clean
+fn exec_prechecked<S>(data: Data<S>, io: Io) -> ExecFuture
+where
+    S: Stream<Error = std_io::Error> + Send + 'static,
+    S::Item: Buf,
+{
+    let Data {
+        source,
+        total,
+        progress,
+        check_7bit: _,
+    } = data;
+    let fut = source
+        .into_future()
+        .map_err(|(err, _source)| err)
+        .and_then(move |(first, rest)| {
+            let first: Option<Bytes> = first.map(Buf::collect);
+            let invalid = first.as_ref().and_then(|chunk| find_non_7bit(chunk));
+            if let Some((offset, byte)) = invalid {
+                let err = LogicError::Custom(Box::new(Not7BitClean::new(offset, byte)));
+                return Either::A(future::ok((io, Err(err))));
+            }
+            let offset = first.as_ref().map(Bytes::len).unwrap_or(0);
+            let source = stream::iter_ok(first.map(IntoBuf::into_buf)).chain(SevenBitChecked {
+                source: rest,
+                offset,
+            });
+            let data = Data {
+                source,
+                total,
+                progress,
+                check_7bit: false,
+            };
+            Either::B(exec_data(data, io))
+        });
+    Box::new(fut)
+}
+
+/// sends `DATA` and, after the `354` response, the body
+fn exec_data<S>(data: Data<S>, io: Io) -> ExecFuture
+where
+    S: Stream<Error = std_io::Error> + Send + 'static,
+    S::Item: Buf,
+{
+    let fut = io
+        .flush_line_from_parts(&["DATA"])
+        .and_then(Io::parse_response)
+        .ctx_and_then(move |io, response| {
+            if response.code() != codes::START_MAIL_DATA {
+                return Either::A(future::ok((io, Err(LogicError::UnexpectedCode(response)))));
+            }
+
+            Either::B(data.exec_body(io))
+        });
+
+    Box::new(fut)
+}
+
+impl<S> Stream for SevenBitChecked<S>
+where
+    S: Stream<Error = std_io::Error>,
+    S::Item: Buf,
+{
+    type Item = Cursor<Bytes>;
+    type Error = std_io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let chunk: Bytes = match try_ready!(self.source.poll()) {
+            Some(chunk) => chunk.collect(),
+            None => return Ok(Async::Ready(None)),
+        };
+        if let Some((idx, byte)) = find_non_7bit(&chunk) {
+            return Err(Not7BitClean::new_io_error(self.offset + idx, byte));
+        }
+        self.offset += chunk.len();
+        Ok(Async::Ready(Some(chunk.into_buf())))
+    }
+}
+
+/// The final (positive) response to `DATA` i.e. the server accepted the mail
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct DataAccepted {
+    /// the response code (normally `250`)
+    pub code: ResponseCode,
+    /// the enhanced status code, if the server send one
+    pub enhanced_status: Option<EnhancedStatusCode>,
+    /// the queue id the server assigned to the mail, if it could be found
+    ///
+    /// This is non-standard, it's extracted from messages like
+    /// `"Ok: queued as XYZ"` or `"OK id=XYZ"`.
+    pub queue_id: Option<String>,
+}
+
+impl DataAccepted {
+    /// creates a `DataAccepted` from the response, `None` if it isn't positive
+    pub fn from_response(response: &Response) -> Option<Self> {
+        let code = response.code();
+        if !code.is_positive() {
+            return None;
+        }
+        let queue_id = response
+            .msg()
+            .iter()
+            .filter_map(|line| find_queue_id(line))
+            .next();
+        Some(DataAccepted {
+            code,
+            enhanced_status: response.enhanced_status_code(),
+            queue_id,
+        })
+    }
+}
+
+fn find_queue_id(line: &str) -> Option<String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let candidate = words
+        .windows(3)
+        .find(|win| win[0].eq_ignore_ascii_case("queued") && win[1].eq_ignore_ascii_case("as"))
+        .map(|win| win[2])
+        .or_else(|| {
+            words
+                .iter()
+                .find(|word| word.len() > 3 && word[..3].eq_ignore_ascii_case("id="))
+                .map(|word| &word[3..])
+        })?;
+    let id = candidate.trim_matches(|ch: char| !ch.is_ascii_alphanumeric());
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_owned())
     }
 }
 
@@ -42,22 +310,28 @@ where
         Ok(())
     }
 
-    fn exec(self, io: Io) -> ExecFuture {
-        let Data { source } = self;
-
-        let fut = io
-            .flush_line_from_parts(&["DATA"])
-            .and_then(Io::parse_response)
-            .ctx_and_then(move |io, response| {
-                if response.code() != codes::START_MAIL_DATA {
-                    return Either::A(future::ok((io, Err(LogicError::UnexpectedCode(response)))));
-                }
+    fn verb(&self) -> &'static str {
+        "DATA"
+    }
 
-                let fut = io.write_dot_stashed(source).and_then(Io::parse_response);
+    fn is_sensitive(&self) -> bool {
+        true
+    }
 
-                Either::B(fut)
-            });
+    fn exec(self, io: Io) -> ExecFuture {
+        let no_recipients = io
+            .transaction_state()
+            .map(|state| state.accepted_recipients == 0)
+            .unwrap_or(false);
+        if no_recipients {
+            let err = LogicError::Custom(Box::new(NoAcceptedRecipients));
+            return Box::new(future::ok((io, Err(err))));
+        }
 
-        Box::new(fut)
+        if self.check_7bit && !io.has_capability("8BITMIME") {
+            exec_prechecked(self, io)
+        } else {
+            exec_data(self, io)
+        }
     }
 }
@@ -0,0 +1,260 @@
+//! Parameters of the DSN (Delivery Status Notification) extension (RFC 3461)
+//!
+//! DSN doesn't add any new commands, instead it adds parameters to the
+//! `MAIL` and `RCPT` commands. The functions in this module add them to
+//! the `Params` of the respective command.
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data_types::{EsmtpKeyword, EsmtpValue, SyntaxError};
+
+use super::Params;
+
+/// the ehlo keyword of the DSN extension
+pub const CAP_DSN: &str = "DSN";
+
+/// Specifies which part of the mail is returned in a failure DSN (`RET` on `MAIL`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Ret {
+    /// return the full message
+    Full,
+    /// return only the headers of the message
+    Hdrs,
+}
+
+impl Ret {
+    /// the value used for the `RET` parameter
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Ret::Full => "FULL",
+            Ret::Hdrs => "HDRS",
+        }
+    }
+}
+
+/// Specifies under which conditions a DSN is send  (`NOTIFY` on `RCPT`)
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Notify {
+    /// never send a DSN
+    Never,
+    /// send a DSN on any of the enabled events
+    ///
+    /// At last one of the events has to be enabled.
+    On {
+        success: bool,
+        failure: bool,
+        delay: bool,
+    },
+}
+
+impl Notify {
+    /// true if this is `Never` or at last one event is enabled
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Notify::Never => true,
+            Notify::On {
+                success,
+                failure,
+                delay,
+            } => success || failure || delay,
+        }
+    }
+
+    /// the value used for the `NOTIFY` parameter
+    pub fn to_param_value(&self) -> String {
+        match *self {
+            Notify::Never => "NEVER".to_owned(),
+            Notify::On {
+                success,
+                failure,
+                delay,
+            } => {
+                let mut parts = Vec::new();
+                if success {
+                    parts.push("SUCCESS");
+                }
+                if failure {
+                    parts.push("FAILURE");
+                }
+                if delay {
+                    parts.push("DELAY");
+                }
+                parts.join(",")
+            }
+        }
+    }
+}
+
+/// adds a `RET` parameter to the given (`MAIL`) parameters
+pub fn params_with_ret(mut p: Params, ret: Ret) -> Params {
+    p.insert(
+        EsmtpKeyword::from_unchecked("RET"),
+        Some(EsmtpValue::from_unchecked(ret.as_str())),
+    );
+    p
+}
+
+/// the maximal length of the (xtext encoded) `ENVID` parameter
+pub const MAX_ENVID_LEN: usize = 100;
+
+/// adds a `ENVID` parameter to the given (`MAIL`) parameters
+///
+/// The envelop id is xtext encoded, the encoded id should not be
+/// longer than `MAX_ENVID_LEN`.
+pub fn params_with_envid(mut p: Params, envid: &str) -> Params {
+    p.insert(
+        EsmtpKeyword::from_unchecked("ENVID"),
+        Some(EsmtpValue::from_unchecked(encode_xtext(envid))),
+    );
+    p
+}
+
+/// generates a new envelop id which is unique (with a very high probability)
+///
+/// The id is made from the current time, the process id and a process wide
+/// counter. It only contains characters which don't need to be xtext encoded.
+pub fn generate_envid() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|dur| dur.as_nanos())
+        .unwrap_or(0);
+    format!("{:x}.{:x}.{:x}", time, process::id(), count)
+}
+
+/// adds a `NOTIFY` parameter to the given (`RCPT`) parameters
+pub fn params_with_notify(mut p: Params, notify: Notify) -> Params {
+    p.insert(
+        EsmtpKeyword::from_unchecked("NOTIFY"),
+        Some(EsmtpValue::from_unchecked(notify.to_param_value())),
+    );
+    p
+}
+
+/// adds a `ORCPT` parameter for the given (original) mail address to the given (`RCPT`) parameters
+///
+/// The address is xtext encoded and send with the `rfc822` address type.
+pub fn params_with_orcpt(mut p: Params, original_recipient: &str) -> Params {
+    let value = format!("rfc822;{}", encode_xtext(original_recipient));
+    p.insert(
+        EsmtpKeyword::from_unchecked("ORCPT"),
+        Some(EsmtpValue::from_unchecked(value)),
+    );
+    p
+}
+
+/// encodes the input as xtext (RFC 3461 Section 4)
+///
+/// All bytes which are not printable us-ascii or are `'+'` or
+/// `'='` are encoded as `"+XX"` where `XX` is the uppercase hex
+/// representation of the byte.
+pub fn encode_xtext(inp: &str) -> String {
+    let mut out = String::with_capacity(inp.len());
+    for bch in inp.bytes() {
+        match bch {
+            b'+' | b'=' => out.push_str(&format!("+{:02X}", bch)),
+            33..=126 => out.push(bch as char),
+            _ => out.push_str(&format!("+{:02X}", bch)),
+        }
+    }
+    out
+}
+
+/// decodes a xtext encoded string (RFC 3461 Section 4)
+///
+/// Lowercase hex digits are accepted even through RFC 3461 only
+/// allows uppercase ones.
+pub fn decode_xtext(inp: &str) -> Result<String, SyntaxError> {
+    let mut out = Vec::with_capacity(inp.len());
+    let mut bytes = inp.bytes();
+    while let Some(bch) = bytes.next() {
+        match bch {
+            b'+' => {
+                let high = bytes.next().and_then(hex_value);
+                let low = bytes.next().and_then(hex_value);
+                match (high, low) {
+                    (Some(high), Some(low)) => out.push(high << 4 | low),
+                    _ => return Err(SyntaxError::XText),
+                }
+            }
+            b'=' => return Err(SyntaxError::XText),
+            33..=126 => out.push(bch),
+            _ => return Err(SyntaxError::XText),
+        }
+    }
+    String::from_utf8(out).map_err(|_| SyntaxError::XText)
+}
+
+fn hex_value(bch: u8) -> Option<u8> {
+    (bch as char).to_digit(16).map(|val| val as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn notify_values() {
+        assert_eq!(Notify::Never.to_param_value(), "NEVER");
+        let notify = Notify::On {
+            success: true,
+            failure: false,
+            delay: true,
+        };
+        assert_eq!(notify.to_param_value(), "SUCCESS,DELAY");
+    }
+
+    #[test]
+    fn notify_needs_some_event() {
+        let notify = Notify::On {
+            success: false,
+            failure: false,
+            delay: false,
+        };
+        assert!(!notify.is_valid());
+        assert!(Notify::Never.is_valid());
+    }
+
+    #[test]
+    fn xtext_encodes_space_and_plus() {
+        let encoded = encode_xtext("a b+c@test.test");
+        assert_eq!(encoded, "a+20b+2Bc@test.test");
+    }
+
+    #[test]
+    fn xtext_roundtrip() {
+        let addr = "weird =+ \"address\"@tü.test";
+        let encoded = encode_xtext(addr);
+        assert!(encoded.bytes().all(|bch| (33..=126).contains(&bch)));
+        assert_eq!(decode_xtext(&encoded).unwrap(), addr);
+    }
+
+    #[test]
+    fn xtext_rejects_malformed_input() {
+        assert_eq!(decode_xtext("a+2"), Err(SyntaxError::XText));
+        assert_eq!(decode_xtext("a+ZZ"), Err(SyntaxError::XText));
+        assert_eq!(decode_xtext("a=b"), Err(SyntaxError::XText));
+        assert_eq!(decode_xtext("a b"), Err(SyntaxError::XText));
+    }
+
+    #[test]
+    fn generated_envids_are_unique() {
+        let first = generate_envid();
+        let second = generate_envid();
+        assert_ne!(first, second);
+        assert_eq!(encode_xtext(&first), first);
+    }
+
+    #[test]
+    fn orcpt_param() {
+        let params = params_with_orcpt(Params::new(), "a b@test.test");
+        let value = params
+            .get(&EsmtpKeyword::from_unchecked("ORCPT"))
+            .unwrap()
+            .as_ref()
+            .unwrap();
+        assert_eq!(value, "rfc822;a+20b@test.test");
+    }
+}
@@ -1,11 +1,12 @@
-use std::collections::HashMap;
 use std::io as std_io;
 
 use bytes::BufMut;
 use futures::Future;
 
 use crate::error::MissingCapabilities;
-use crate::{ClientId, Cmd, Domain, EhloData, EhloParam, ExecFuture, Io, Response, SyntaxError};
+use crate::event::ConnectionEvent;
+use crate::io::SmtpResult;
+use crate::{ClientId, Cmd, Domain, EhloData, ExecFuture, Io, Response, SyntaxError};
 
 #[derive(Debug, Clone)]
 pub struct Ehlo {
@@ -20,6 +21,39 @@ impl Ehlo {
     pub fn identity(&self) -> &ClientId {
         &self.identity
     }
+
+    /// writes the command line to the output buffer without flushing it
+    pub(crate) fn write_line(&self, io: &mut Io) {
+        let str_me = match *self.identity() {
+            ClientId::Domain(ref domain) => domain.as_str(),
+            ClientId::AddressLiteral(ref addr_lit) => addr_lit.as_str(),
+        };
+
+        //7 == "EHLO ".len() + "\r\n".len()
+        let out = io.out_buffer(7 + str_me.len());
+        out.put("EHLO ");
+        out.put(str_me);
+        out.put("\r\n");
+    }
+
+    /// reads the response to `EHLO` and sets the ehlo data on success
+    pub(crate) fn read_response(
+        io: Io,
+    ) -> impl Future<Item = (Io, SmtpResult), Error = std_io::Error> + Send {
+        io.parse_response()
+            //TODO ctx_and_then
+            .and_then(|(mut io, result)| match result {
+                Err(response) => Ok((io, Err(response))),
+                Ok(response) => {
+                    let ehlo = parse_ehlo_response(&response)
+                        .map_err(|err| std_io::Error::new(std_io::ErrorKind::Other, err))?;
+
+                    io.set_ehlo_data(ehlo);
+                    io.emit_event(ConnectionEvent::EhloCompleted);
+                    Ok((io, Ok(response)))
+                }
+            })
+    }
 }
 
 impl From<ClientId> for Ehlo {
@@ -39,35 +73,13 @@ impl Cmd for Ehlo {
         Ok(())
     }
 
-    fn exec(self, mut io: Io) -> ExecFuture {
-        let str_me = match *self.identity() {
-            ClientId::Domain(ref domain) => domain.as_str(),
-            ClientId::AddressLiteral(ref addr_lit) => addr_lit.as_str(),
-        };
-
-        {
-            //7 == "EHLO ".len() + "\r\n".len()
-            let out = io.out_buffer(7 + str_me.len());
-            out.put("EHLO ");
-            out.put(str_me);
-            out.put("\r\n");
-        }
-
-        let fut = io
-            .flush()
-            .and_then(Io::parse_response)
-            //TODO ctx_and_then
-            .and_then(|(mut io, result)| match result {
-                Err(response) => Ok((io, Err(response))),
-                Ok(response) => {
-                    let ehlo = parse_ehlo_response(&response)
-                        .map_err(|err| std_io::Error::new(std_io::ErrorKind::Other, err))?;
-
-                    io.set_ehlo_data(ehlo);
-                    Ok((io, Ok(response)))
-                }
-            });
+    fn verb(&self) -> &'static str {
+        "EHLO"
+    }
 
+    fn exec(self, mut io: Io) -> ExecFuture {
+        self.write_line(&mut io);
+        let fut = io.flush().and_then(Ehlo::read_response);
         Box::new(fut)
     }
 }
@@ -77,19 +89,18 @@ fn parse_ehlo_response(response: &Response) -> Result<EhloData, SyntaxError> {
     let first = lines.first().expect("response with 0 lines should not");
     //UNWRAP_SAFE: Split has at last one entry
     let domain: Domain = first.split(' ').next().unwrap().parse()?;
-    let mut caps = HashMap::new();
-
-    for line in lines[1..].iter() {
-        let mut parts = line.split(' ');
-        //UNWRAP_SAFE: Split has at last one entry
-        let capability = parts.next().unwrap().parse()?;
-        let params = parts
-            .map(|part| part.parse())
-            .collect::<Result<Vec<EhloParam>, _>>()?;
-        caps.insert(capability, params);
-    }
 
-    Ok(EhloData::new(domain, caps))
+    // empty continuation lines (`"250-"`) are valid, but carry no capability
+    let capabilities = lines[1..]
+        .iter()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut parts = line.split(' ');
+            //UNWRAP_SAFE: Split has at last one entry
+            (parts.next().unwrap(), parts)
+        });
+
+    EhloData::parse_capabilities(domain, capabilities)
 }
 
 #[cfg(test)]
@@ -141,6 +152,24 @@ mod test {
             assert_eq!(ehlo_data.capability_map().len(), 2)
         }
 
+        #[test]
+        fn capability_params_are_merged_over_multiple_lines() {
+            let response = Response::new(
+                OK,
+                vec![
+                    "1aim.test says hy".to_owned(),
+                    "AUTH PLAIN LOGIN".to_owned(),
+                    "SMTPUTF8".to_owned(),
+                    "AUTH XOAUTH2 PLAIN".to_owned(),
+                ],
+            );
+            let ehlo_data = parse_ehlo_response(&response).unwrap();
+
+            let mechanisms = ehlo_data.auth_mechanisms();
+            assert_eq!(mechanisms, &["PLAIN", "LOGIN", "XOAUTH2"]);
+            assert!(ehlo_data.has_capability("SMTPUTF8"));
+        }
+
         #[test]
         fn capabilities_can_have_parameter() {
             let response = Response::new(
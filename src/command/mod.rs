@@ -11,8 +11,13 @@ pub use self::starttls::*;
 mod data;
 pub use self::data::*;
 
+mod bdat;
+pub use self::bdat::*;
+
 pub mod auth;
 
+pub mod dsn;
+
 mod reset;
 pub use self::reset::*;
 
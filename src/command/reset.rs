@@ -4,6 +4,7 @@ use futures::Future;
 
 use crate::common::EhloData;
 use crate::error::{LogicError, MissingCapabilities};
+use crate::io::{update_transaction_on_success, TransactionState};
 use crate::{Cmd, ExecFuture, Io};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -14,6 +15,10 @@ impl Cmd for Reset {
         Ok(())
     }
 
+    fn verb(&self) -> &'static str {
+        "RSET"
+    }
+
     fn exec(self, io: Io) -> ExecFuture {
         let fut = io
             .flush_line_from_parts(&["RSET"])
@@ -30,7 +35,8 @@ impl Cmd for Reset {
                     }
                 }
                 Err(logic_err) => Err(std_io::Error::new(std_io::ErrorKind::Other, logic_err)),
-            });
+            })
+            .map(update_transaction_on_success(TransactionState::reset));
 
         Box::new(fut)
     }
@@ -1,8 +1,16 @@
 use std::collections::HashMap;
 
+use bytes::{BufMut, BytesMut};
+
 use crate::common::EhloData;
-use crate::data_types::{EsmtpKeyword, EsmtpValue, ForwardPath, ReversePath};
-use crate::error::MissingCapabilities;
+use crate::data_types::{EsmtpKeyword, EsmtpValue, ForwardPath, ReversePath, SyntaxError};
+use crate::error::{AuthenticationRequired, LineBreakInCommand, LogicError, MissingCapabilities};
+use crate::event::ConnectionEvent;
+use crate::io::{
+    emit_on_success, update_transaction_on_success, SmtpResult, TransactionState, CR_LF,
+};
+use futures::{future, Future};
+
 use crate::{Cmd, ExecFuture, Io};
 
 /// Quit command, but as it makes the connection unusable we do
@@ -17,6 +25,10 @@ impl Cmd for Quit {
         Ok(())
     }
 
+    fn verb(&self) -> &'static str {
+        "QUIT"
+    }
+
     fn exec(self, io: Io) -> ExecFuture {
         io.exec_simple_cmd(&["QUIT"])
     }
@@ -30,6 +42,10 @@ impl Cmd for Noop {
         Ok(())
     }
 
+    fn verb(&self) -> &'static str {
+        "NOOP"
+    }
+
     fn exec(self, io: Io) -> ExecFuture {
         io.exec_simple_cmd(&["NOOP"])
     }
@@ -42,6 +58,14 @@ pub fn params_with_smtputf8(mut p: Params) -> Params {
     p
 }
 
+pub fn params_with_size(mut p: Params, size: usize) -> Params {
+    p.insert(
+        EsmtpKeyword::from_unchecked("SIZE"),
+        Some(EsmtpValue::from_unchecked(size.to_string())),
+    );
+    p
+}
+
 #[derive(Debug, Clone)]
 pub struct Mail {
     pub reverse_path: ReversePath,
@@ -55,6 +79,33 @@ impl Mail {
             params: Params::new(),
         }
     }
+
+    /// adds a parameter of a extension not modeled by this crate (e.g. `XFOO=bar`)
+    ///
+    /// Fails if `name` is not a valid esmtp-keyword or `value` is not a
+    /// valid esmtp-value.
+    pub fn extra_param(mut self, name: &str, value: Option<&str>) -> Result<Self, SyntaxError> {
+        self.params = params_with_extra(self.params, name, value)?;
+        Ok(self)
+    }
+}
+
+impl Mail {
+    /// writes the command line to the output buffer without flushing it
+    #[doc(hidden)]
+    pub fn write_line(&self, io: &mut Io) {
+        write_pathy_cmd(io, "MAIL FROM:", self.reverse_path.as_str(), &self.params)
+    }
+
+    /// turns a rejection because of missing authentication into a `AuthenticationRequired` error
+    pub(crate) fn map_result(result: SmtpResult) -> SmtpResult {
+        result.map_err(|err| match err {
+            LogicError::Code(response) if response.requires_authentication() => {
+                LogicError::Custom(Box::new(AuthenticationRequired::new(response)))
+            }
+            err => err,
+        })
+    }
 }
 
 impl Cmd for Mail {
@@ -62,8 +113,20 @@ impl Cmd for Mail {
         Ok(())
     }
 
-    fn exec(self, con: Io) -> ExecFuture {
-        handle_pathy_cmd(con, "MAIL FROM:", self.reverse_path.as_str(), &self.params)
+    fn verb(&self) -> &'static str {
+        "MAIL"
+    }
+
+    /// sends `MAIL`, failing with a `AuthenticationRequired` error if the server requires it
+    fn exec(self, mut io: Io) -> ExecFuture {
+        self.write_line(&mut io);
+        let fut = io
+            .flush()
+            .and_then(Io::parse_response)
+            .map(|(io, result)| (io, Mail::map_result(result)))
+            .map(update_transaction_on_success(TransactionState::start))
+            .map(emit_on_success(ConnectionEvent::TransactionStarted));
+        Box::new(fut)
     }
 }
 
@@ -82,6 +145,20 @@ impl Recipient {
             params: Params::new(),
         }
     }
+
+    /// adds a parameter of a extension not modeled by this crate, see `Mail::extra_param`
+    pub fn extra_param(mut self, name: &str, value: Option<&str>) -> Result<Self, SyntaxError> {
+        self.params = params_with_extra(self.params, name, value)?;
+        Ok(self)
+    }
+}
+
+impl Recipient {
+    /// writes the command line to the output buffer without flushing it
+    #[doc(hidden)]
+    pub fn write_line(&self, io: &mut Io) {
+        write_pathy_cmd(io, "RCPT TO:", self.forward_path.as_str(), &self.params)
+    }
 }
 
 impl Cmd for Recipient {
@@ -89,27 +166,82 @@ impl Cmd for Recipient {
         Ok(())
     }
 
-    fn exec(self, con: Io) -> ExecFuture {
-        handle_pathy_cmd(con, "RCPT TO:", self.forward_path.as_str(), &self.params)
+    fn verb(&self) -> &'static str {
+        "RCPT"
+    }
+
+    fn exec(self, mut io: Io) -> ExecFuture {
+        self.write_line(&mut io);
+        let fut = io
+            .flush()
+            .and_then(Io::parse_response)
+            .map(update_transaction_on_success(
+                TransactionState::add_recipient,
+            ));
+        Box::new(fut)
     }
 }
 
-fn handle_pathy_cmd(io: Io, cmd: &str, path: &str, params: &Params) -> ExecFuture {
-    //no additional heap alloc
-    if params.is_empty() {
-        io.exec_simple_cmd(&[cmd, "<", path, ">"])
-    } else {
-        let mut parts = vec![cmd, "<", path, ">"];
-        for (k, v) in params.iter() {
-            parts.push(" ");
-            parts.push(k.as_str());
-            if let Some(v) = v.as_ref() {
-                parts.push("=");
-                parts.push(v.as_str());
-            }
+fn params_with_extra(
+    mut p: Params,
+    name: &str,
+    value: Option<&str>,
+) -> Result<Params, SyntaxError> {
+    let name = EsmtpKeyword::new(name)?;
+    let value = value.map(EsmtpValue::new).transpose()?;
+    p.insert(name, value);
+    Ok(p)
+}
+
+fn write_pathy_cmd(io: &mut Io, cmd: &str, path: &str, params: &Params) {
+    //the line is directly written into the output buffer
+    let len = pathy_cmd_len(cmd, path, params);
+    put_pathy_cmd(io.out_buffer(len), cmd, path, params)
+}
+
+fn pathy_cmd_len(cmd: &str, path: &str, params: &Params) -> usize {
+    let params_len: usize = params
+        .iter()
+        .map(|(k, v)| 1 + k.as_str().len() + v.as_ref().map(|v| 1 + v.as_str().len()).unwrap_or(0))
+        .sum();
+    // "<" + ">" + "\r\n"
+    cmd.len() + path.len() + params_len + 4
+}
+
+/// the parameters modeled by this crate in the order they are written in
+const MODELED_PARAMS: &[&str] = &["SIZE", "SMTPUTF8", "RET", "ENVID", "NOTIFY", "ORCPT"];
+
+/// returns the parameters in the order they are written in
+///
+/// `Params` has no stable order, so the parameters modeled by this crate are
+/// written first (in the order of `MODELED_PARAMS`) followed by all other
+/// parameters (e.g. ones added with `extra_param`) ordered by their keyword.
+fn sorted_params(params: &Params) -> Vec<(&EsmtpKeyword, &Option<EsmtpValue>)> {
+    let rank = |keyword: &EsmtpKeyword| {
+        MODELED_PARAMS
+            .iter()
+            .position(|modeled| keyword.as_str().eq_ignore_ascii_case(modeled))
+            .unwrap_or(MODELED_PARAMS.len())
+    };
+    let mut sorted = params.iter().collect::<Vec<_>>();
+    sorted.sort_by_cached_key(|(k, _)| (rank(k), k.as_str().to_ascii_uppercase()));
+    sorted
+}
+
+fn put_pathy_cmd(out: &mut BytesMut, cmd: &str, path: &str, params: &Params) {
+    out.put(cmd);
+    out.put("<");
+    out.put(path);
+    out.put(">");
+    for (k, v) in sorted_params(params) {
+        out.put(" ");
+        out.put(k.as_str());
+        if let Some(v) = v.as_ref() {
+            out.put("=");
+            out.put(v.as_str());
         }
-        io.exec_simple_cmd(parts.as_slice())
     }
+    out.put(CR_LF);
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -122,6 +254,10 @@ impl Cmd for Verify {
         Ok(())
     }
 
+    fn verb(&self) -> &'static str {
+        "VRFY"
+    }
+
     fn exec(self, io: Io) -> ExecFuture {
         io.exec_simple_cmd(&["VRFY ", self.query.as_str()])
     }
@@ -137,6 +273,10 @@ impl Cmd for Help {
         Ok(())
     }
 
+    fn verb(&self) -> &'static str {
+        "HELP"
+    }
+
     fn exec(self, io: Io) -> ExecFuture {
         if let Some(topic) = self.topic.as_ref() {
             io.exec_simple_cmd(&["HELP ", topic.as_str()])
@@ -145,3 +285,104 @@ impl Cmd for Help {
         }
     }
 }
+
+/// sends the given line as is, e.g. for commands this crate doesn't provide
+///
+/// The line must not contain `"\r\n"` (it's added automatically) and the
+/// command must not change the connection state in ways the `Connection`
+/// doesn't know about (e.g. by starting tls). If the line contains a `'\r'`
+/// or `'\n'` it fails with a `LineBreakInCommand` error without sending
+/// anything.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Raw {
+    pub line: String,
+}
+
+impl Cmd for Raw {
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        Ok(())
+    }
+
+    fn verb(&self) -> &'static str {
+        "RAW"
+    }
+
+    fn exec(self, io: Io) -> ExecFuture {
+        if self.line.contains(['\r', '\n']) {
+            let err = LogicError::Custom(Box::new(LineBreakInCommand));
+            return Box::new(future::ok((io, Err(err))));
+        }
+        io.exec_simple_cmd(&[self.line.as_str()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn render(cmd: &str, path: &str, params: &Params) -> BytesMut {
+        let len = pathy_cmd_len(cmd, path, params);
+        let mut out = BytesMut::with_capacity(len);
+        put_pathy_cmd(&mut out, cmd, path, params);
+        assert_eq!(out.len(), len);
+        out
+    }
+
+    #[test]
+    fn pathy_cmd_without_params() {
+        let out = render("RCPT TO:", "b@test.test", &Params::new());
+        assert_eq!(&out[..], b"RCPT TO:<b@test.test>\r\n");
+    }
+
+    #[test]
+    fn pathy_cmd_with_param_with_value() {
+        let mut params = Params::new();
+        params.insert(
+            EsmtpKeyword::from_unchecked("SIZE"),
+            Some(EsmtpValue::from_unchecked("1024")),
+        );
+        let out = render("MAIL FROM:", "a@test.test", &params);
+        assert_eq!(&out[..], b"MAIL FROM:<a@test.test> SIZE=1024\r\n");
+    }
+
+    #[test]
+    fn pathy_cmd_with_param_without_value() {
+        let mut params = Params::new();
+        params.insert(EsmtpKeyword::from_unchecked("SMTPUTF8"), None);
+        let out = render("MAIL FROM:", "", &params);
+        assert_eq!(&out[..], b"MAIL FROM:<> SMTPUTF8\r\n");
+    }
+
+    #[test]
+    fn extra_params_are_written_after_the_modeled_ones() {
+        let reverse_path = ReversePath::from_unchecked("a@test.test");
+        let mail = Mail {
+            reverse_path,
+            params: params_with_smtputf8(Params::new()),
+        }
+        .extra_param("XFOO", Some("bar"))
+        .unwrap()
+        .extra_param("XBAR", None)
+        .unwrap();
+        let mail = Mail {
+            params: params_with_size(mail.params, 1024),
+            ..mail
+        };
+        let out = render("MAIL FROM:", mail.reverse_path.as_str(), &mail.params);
+        assert_eq!(
+            &out[..],
+            &b"MAIL FROM:<a@test.test> SIZE=1024 SMTPUTF8 XBAR XFOO=bar\r\n"[..]
+        );
+    }
+
+    #[test]
+    fn pathy_cmd_reuses_the_output_buffer() {
+        let mut out = render("MAIL FROM:", "a@test.test", &Params::new());
+        out.reserve(pathy_cmd_len("RCPT TO:", "b@test.test", &Params::new()));
+        put_pathy_cmd(&mut out, "RCPT TO:", "b@test.test", &Params::new());
+        assert_eq!(
+            &out[..],
+            &b"MAIL FROM:<a@test.test>\r\nRCPT TO:<b@test.test>\r\n"[..]
+        );
+    }
+}
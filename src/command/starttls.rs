@@ -2,11 +2,11 @@ use std::io as std_io;
 
 use futures::future::{self, Either, Future};
 
-use native_tls::TlsConnector as NativeTlsConnector;
 use tokio_tls::TlsConnector;
 
-use crate::error::MissingCapabilities;
-use crate::io::{Io, Socket};
+use crate::error::{ConnectError, MissingCapabilities};
+use crate::event::ConnectionEvent;
+use crate::io::{Io, Socket, TransactionState};
 use crate::response::{codes, Response};
 use crate::{
     map_tls_err, Capability, Cmd, DefaultTlsSetup, Domain, EhloData, EsmtpKeyword, ExecFuture,
@@ -61,7 +61,7 @@ fn connection_already_secure_error_future() -> ExecFuture {
     Box::new(fut)
 }
 
-const STARTTLS: &str = "STARTTLS";
+pub(crate) const STARTTLS: &str = "STARTTLS";
 
 impl<S> Cmd for StartTls<S>
 where
@@ -81,6 +81,10 @@ where
         })
     }
 
+    fn verb(&self) -> &'static str {
+        "STARTTLS"
+    }
+
     #[allow(clippy::redundant_closure_call)]
     fn exec(self, mut io: Io) -> ExecFuture {
         let StartTls {
@@ -106,6 +110,7 @@ where
         };
 
         if was_mock {
+            io.emit_event(ConnectionEvent::TlsUpgraded);
             let fut = future::ok((io, Ok(tls_done_result())));
             return Box::new(fut);
         }
@@ -118,24 +123,30 @@ where
                 Ok(_) => {
                     let connector = alttry!(
                         {
-                            let contor = setup_tls.setup(NativeTlsConnector::builder())?;
+                            let contor = setup_tls.into_connector()?;
                             Ok(TlsConnector::from(contor))
                         } =>
                         |err| Either::A(future::err(map_tls_err(err)))
                     );
 
-                    let (socket, _buffer, _ehlo_data) = io.split();
-                    let stream = match socket {
-                        Socket::Insecure(stream) => stream,
-                        _ => unreachable!(),
-                    };
-
-                    let fut = connector
-                        .connect(sni_domain.as_str(), stream)
-                        .map_err(map_tls_err)
-                        .map(move |stream| {
-                            let socket = Socket::Secure(stream);
-                            let io = Io::from(socket);
+                    let fut = io
+                        .upgrade_socket(move |socket| {
+                            let stream = match socket {
+                                Socket::Insecure(stream) => stream,
+                                _ => unreachable!(),
+                            };
+                            connector
+                                .connect(sni_domain.as_str(), stream)
+                                .map_err(ConnectError::wrap_tls)
+                                .map(Socket::Secure)
+                        })
+                        .map(|mut io| {
+                            // STARTTLS resets the smtp state, so anything received before
+                            // the upgrade, the ehlo data and the transaction are discarded
+                            io.in_buffer().clear();
+                            io.clear_ehlo_data();
+                            io.update_transaction_state(TransactionState::reset);
+                            io.emit_event(ConnectionEvent::TlsUpgraded);
                             (io, Ok(tls_done_result()))
                         });
 
@@ -2,12 +2,13 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::io as std_io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
 
 use hostname::get_hostname;
-use native_tls::{self, TlsConnector as NativeTlsConnector, TlsConnectorBuilder};
+use native_tls::{self, Protocol, TlsConnector as NativeTlsConnector, TlsConnectorBuilder};
 
 use crate::ascii::IgnoreAsciiCaseStr;
-use crate::data_types::{AddressLiteral, Capability, Domain, EhloParam};
+use crate::data_types::{AddressLiteral, Capability, Domain, EhloParam, SyntaxError};
 
 /// Represents the identity of an client
 ///
@@ -22,7 +23,7 @@ use crate::data_types::{AddressLiteral, Capability, Domain, EhloParam};
 ///
 /// MX: Mail Exchanger
 ///
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ClientId {
     /// a registered domain
     Domain(Domain),
@@ -128,10 +129,34 @@ impl From<Domain> for TlsConfig {
     }
 }
 
+impl TlsConfig<SecureTlsSetup> {
+    /// creates a config for the given server domain using `SecureTlsSetup`
+    ///
+    /// This is the recommended setup if there are no special requirements
+    /// (like client certificates).
+    pub fn secure(domain: Domain) -> Self {
+        TlsConfig {
+            domain,
+            setup: SecureTlsSetup,
+        }
+    }
+}
+
 /// Trait used when setting up tls to modify the setup process
 pub trait SetupTls: Debug + Send + 'static {
     /// Accepts a connection builder and returns a connector if possible
     fn setup(self, builder: TlsConnectorBuilder) -> Result<NativeTlsConnector, native_tls::Error>;
+
+    /// Returns the connector to use, by default calling `setup` with a new builder
+    ///
+    /// Implementations which don't need a builder (e.g. `ReuseTlsConnector`)
+    /// can overwrite this to avoid creating one.
+    fn into_connector(self) -> Result<NativeTlsConnector, native_tls::Error>
+    where
+        Self: Sized,
+    {
+        self.setup(NativeTlsConnector::builder())
+    }
 }
 
 /// The default tls setup, which just calls `builder.build()`
@@ -144,6 +169,74 @@ impl SetupTls for DefaultTlsSetup {
     }
 }
 
+/// A tls setup enforcing safe settings, independent of the defaults of `native_tls`
+///
+/// The systems root certificates are used, at last TLS 1.2 is required and
+/// both the certificate and the hostname are verified.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecureTlsSetup;
+
+impl SetupTls for SecureTlsSetup {
+    fn setup(
+        self,
+        mut builder: TlsConnectorBuilder,
+    ) -> Result<NativeTlsConnector, native_tls::Error> {
+        builder
+            .min_protocol_version(Some(Protocol::Tlsv12))
+            .disable_built_in_roots(false)
+            .danger_accept_invalid_certs(false)
+            .danger_accept_invalid_hostnames(false)
+            .use_sni(true)
+            .build()
+    }
+}
+
+/// A tls setup reusing a already build connector
+///
+/// Building a connector (e.g. loading the root certificates) can be
+/// expensive, so if many connections are opened it's better to build
+/// it once and share it between all of them:
+///
+/// ```
+/// use native_tls::TlsConnector;
+/// use new_tokio_smtp::{ReuseTlsConnector, TlsConfig};
+///
+/// let setup = ReuseTlsConnector::new(TlsConnector::new().unwrap());
+/// let config_a = TlsConfig { domain: "smtp1.example.com".parse().unwrap(), setup: setup.clone() };
+/// let config_b = TlsConfig { domain: "smtp2.example.com".parse().unwrap(), setup };
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReuseTlsConnector(Arc<NativeTlsConnector>);
+
+impl ReuseTlsConnector {
+    /// wraps the connector so that it can be shared between tls configs
+    pub fn new(connector: NativeTlsConnector) -> Self {
+        ReuseTlsConnector(Arc::new(connector))
+    }
+
+    /// returns the shared connector
+    pub fn connector(&self) -> &Arc<NativeTlsConnector> {
+        &self.0
+    }
+}
+
+impl From<Arc<NativeTlsConnector>> for ReuseTlsConnector {
+    fn from(connector: Arc<NativeTlsConnector>) -> Self {
+        ReuseTlsConnector(connector)
+    }
+}
+
+impl SetupTls for ReuseTlsConnector {
+    /// ignores the builder and returns (a clone of) the shared connector
+    fn setup(self, _builder: TlsConnectorBuilder) -> Result<NativeTlsConnector, native_tls::Error> {
+        self.into_connector()
+    }
+
+    fn into_connector(self) -> Result<NativeTlsConnector, native_tls::Error> {
+        Ok((*self.0).clone())
+    }
+}
+
 impl<F: 'static> SetupTls for F
 where
     F: Send + Debug + FnOnce(TlsConnectorBuilder) -> Result<NativeTlsConnector, native_tls::Error>,
@@ -168,6 +261,22 @@ pub(crate) fn map_tls_err(err: native_tls::Error) -> std_io::Error {
     std_io::Error::new(std_io::ErrorKind::Other, err)
 }
 
+/// The ehlo keywords of the extensions this crate knows about
+///
+/// I.e. extensions which are used by some of the provided commands or
+/// helpers, e.g. `SMTPUTF8` or `DSN`.
+pub const KNOWN_CAPABILITIES: &[&str] = &[
+    "8BITMIME",
+    "AUTH",
+    "CHUNKING",
+    "DSN",
+    "ENHANCEDSTATUSCODES",
+    "PIPELINING",
+    "SIZE",
+    "SMTPUTF8",
+    "STARTTLS",
+];
+
 /// A type representing the ehlo response of the last ehlo call
 ///
 /// This is mainly used to check if a certain capability/command
@@ -185,6 +294,58 @@ impl EhloData {
         EhloData { domain, data }
     }
 
+    /// create a new Ehlo data from a list of capabilities (ehlo-keywords) and their parameters
+    ///
+    /// This is meant for creating ehlo data without a server, e.g. in tests
+    /// or stubs. Keywords and parameters are validated like when parsing a
+    /// ehlo response, a capability listed multiple times has the parameters
+    /// of all entries.
+    ///
+    /// ```
+    /// use new_tokio_smtp::{Domain, EhloData};
+    ///
+    /// let domain = Domain::from_unchecked("they.test");
+    /// let ehlo = EhloData::from_capabilities(domain, &[("STARTTLS", &[]), ("SIZE", &["1024"])])
+    ///     .unwrap();
+    /// assert!(ehlo.has_capability("starttls"));
+    /// ```
+    pub fn from_capabilities(
+        domain: Domain,
+        capabilities: &[(&str, &[&str])],
+    ) -> Result<Self, SyntaxError> {
+        let capabilities = capabilities
+            .iter()
+            .map(|(capability, params)| (*capability, params.iter().cloned()));
+        EhloData::parse_capabilities(domain, capabilities)
+    }
+
+    /// parses and merges capabilities and their parameters, see `from_capabilities`
+    pub(crate) fn parse_capabilities<'a, I, P>(
+        domain: Domain,
+        capabilities: I,
+    ) -> Result<Self, SyntaxError>
+    where
+        I: IntoIterator<Item = (&'a str, P)>,
+        P: IntoIterator<Item = &'a str>,
+    {
+        let mut data = HashMap::new();
+        for (capability, params) in capabilities {
+            let capability: Capability = capability.parse()?;
+            let params = params
+                .into_iter()
+                .map(|param| param.parse())
+                .collect::<Result<Vec<EhloParam>, _>>()?;
+            // some servers split a capability over multiple lines (e.g. `AUTH`)
+            let known_params: &mut Vec<EhloParam> = data.entry(capability).or_default();
+            for param in params {
+                if !known_params.contains(&param) {
+                    known_params.push(param);
+                }
+            }
+        }
+        Ok(EhloData::new(domain, data))
+    }
+
     /// check if a ehlo contained a specific capability e.g. `SMTPUTF8`
     pub fn has_capability<A>(&self, cap: A) -> bool
     where
@@ -204,6 +365,50 @@ impl EhloData {
             .map(|vec| &**vec)
     }
 
+    /// returns the mechanisms advertised with `AUTH` (empty if `AUTH` isn't supported)
+    ///
+    /// If the server (unusually) advertised `AUTH` on multiple lines the
+    /// mechanisms from all lines are combined.
+    pub fn auth_mechanisms(&self) -> &[EhloParam] {
+        self.get_capability_params("AUTH").unwrap_or(&[])
+    }
+
+    /// returns the maximal message size declared with `SIZE` (RFC 1870)
+    ///
+    /// - `None` if the server didn't advertise `SIZE` at all, i.e. no limit
+    ///   is declared (but there still might be one)
+    /// - `Some(SizeLimit::Unlimited)` for `SIZE 0` or `SIZE` without (or
+    ///   with a malformed) value, i.e. the server has no fixed limit
+    /// - `Some(SizeLimit::Limit(n))` for `SIZE n`
+    pub fn size_limit(&self) -> Option<SizeLimit> {
+        let params = self.get_capability_params("SIZE")?;
+        let limit = params
+            .first()
+            .and_then(|param| param.as_str().parse::<usize>().ok())
+            .filter(|&limit| limit > 0)
+            .map(SizeLimit::Limit)
+            .unwrap_or(SizeLimit::Unlimited);
+        Some(limit)
+    }
+
+    /// returns all capabilities which are not in `KNOWN_CAPABILITIES`
+    ///
+    /// This is meant for diagnostics, e.g. to find vendor specific
+    /// extensions (like `XFORWARD`). The capabilities are sorted by name.
+    pub fn unknown_capabilities(&self) -> Vec<&Capability> {
+        let mut unknown = self
+            .data
+            .keys()
+            .filter(|cap| {
+                !KNOWN_CAPABILITIES
+                    .iter()
+                    .any(|known| cap.as_str().eq_ignore_ascii_case(known))
+            })
+            .collect::<Vec<_>>();
+        unknown.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        unknown
+    }
+
     /// return a reference to the inner hash map
     pub fn capability_map(&self) -> &HashMap<Capability, Vec<EhloParam>> {
         &self.data
@@ -215,6 +420,25 @@ impl EhloData {
     }
 }
 
+/// The maximal message size declared by the server, see `EhloData::size_limit`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SizeLimit {
+    /// the server has no fixed limit (`SIZE 0` or `SIZE`)
+    Unlimited,
+    /// messages can be at most this many bytes large (`SIZE n`)
+    Limit(usize),
+}
+
+impl SizeLimit {
+    /// true if a message of given size is within the limit
+    pub fn allows(self, size: usize) -> bool {
+        match self {
+            SizeLimit::Unlimited => true,
+            SizeLimit::Limit(limit) => size <= limit,
+        }
+    }
+}
+
 impl From<(Domain, HashMap<Capability, Vec<EhloParam>>)> for EhloData {
     fn from((domain, map): (Domain, HashMap<Capability, Vec<EhloParam>>)) -> Self {
         EhloData::new(domain, map)
@@ -227,3 +451,88 @@ impl Into<(Domain, HashMap<Capability, Vec<EhloParam>>)> for EhloData {
         (domain, data)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn known_capabilities_contain_all_capabilities_used_by_the_crate() {
+        use crate::command::{auth::CAP_AUTH, dsn::CAP_DSN, CAP_CHUNKING, STARTTLS};
+        use crate::pipelining::CAP_PIPELINING;
+
+        let used = [
+            "8BITMIME",
+            CAP_AUTH,
+            CAP_CHUNKING,
+            CAP_DSN,
+            CAP_PIPELINING,
+            "SIZE",
+            "SMTPUTF8",
+            STARTTLS,
+        ];
+        for cap in used.iter() {
+            assert!(KNOWN_CAPABILITIES.contains(cap), "missing {}", cap);
+        }
+    }
+
+    #[test]
+    fn size_limit_distinguishes_absent_unlimited_and_limit() {
+        let size_limit = |caps: &[(&str, &[&str])]| {
+            let domain = Domain::from_unchecked("they.test");
+            EhloData::from_capabilities(domain, caps)
+                .unwrap()
+                .size_limit()
+        };
+        assert_eq!(size_limit(&[]), None);
+        assert_eq!(size_limit(&[("SIZE", &["0"])]), Some(SizeLimit::Unlimited));
+        assert_eq!(size_limit(&[("SIZE", &[])]), Some(SizeLimit::Unlimited));
+        assert_eq!(
+            size_limit(&[("SIZE", &["1024"])]),
+            Some(SizeLimit::Limit(1024))
+        );
+        assert!(SizeLimit::Limit(1024).allows(1024));
+        assert!(!SizeLimit::Limit(1024).allows(1025));
+    }
+
+    #[test]
+    fn from_capabilities_builds_the_capability_map() {
+        let domain = Domain::from_unchecked("they.test");
+        let ehlo =
+            EhloData::from_capabilities(domain, &[("SIZE", &["1024"]), ("STARTTLS", &[])]).unwrap();
+        assert_eq!(ehlo.domain(), "they.test");
+        assert!(ehlo.has_capability("STARTTLS"));
+        assert!(ehlo.has_capability("size"));
+        assert!(!ehlo.has_capability("SMTPUTF8"));
+        assert_eq!(ehlo.size_limit(), Some(SizeLimit::Limit(1024)));
+        assert_eq!(ehlo.capability_map().len(), 2);
+    }
+
+    #[test]
+    fn from_capabilities_validates_keywords_and_params() {
+        let domain = || Domain::from_unchecked("they.test");
+        assert!(EhloData::from_capabilities(domain(), &[("NOT VALID", &[])]).is_err());
+        assert!(EhloData::from_capabilities(domain(), &[("SIZE", &["1 2"])]).is_err());
+    }
+
+    #[test]
+    fn reuse_tls_connector_shares_one_connector() {
+        let setup = ReuseTlsConnector::new(NativeTlsConnector::new().unwrap());
+        let configs = vec![
+            TlsConfig {
+                domain: Domain::from_unchecked("a.test"),
+                setup: setup.clone(),
+            },
+            TlsConfig {
+                domain: Domain::from_unchecked("b.test"),
+                setup: setup.clone(),
+            },
+        ];
+        assert_eq!(Arc::strong_count(setup.connector()), 3);
+
+        for config in configs {
+            config.setup.into_connector().unwrap();
+        }
+        assert_eq!(Arc::strong_count(setup.connector()), 1);
+    }
+}
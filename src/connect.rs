@@ -1,15 +1,22 @@
 use std::fmt::Debug;
 use std::io as std_io;
 use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::future::{self, Either, Future};
+use futures::future::{self, Either, Future, Loop};
+use tokio::timer::Timeout;
 
 use crate::common::{ClientId, DefaultTlsSetup, SetupTls, TlsConfig};
 use crate::connection::{Cmd, Connection};
 use crate::data_types::Domain;
-use crate::error::{ConnectingFailed, LogicError};
+use crate::error::{
+    AffinityMismatch, AllCandidatesFailed, ConnectError, ConnectingFailed, LogicError,
+};
+use crate::event::{ConnectionEvent, EventSubscriber};
 use crate::future_ext::ResultWithContextExt;
-use crate::io::{Io, SmtpResult};
+use crate::io::{emit_on_success, BufferConfig, Io, Keepalive, SmtpResult};
+use crate::response::Response;
 //NOTE: out-of-order (potential circular) dep, but ok in this case
 use crate::command::Noop;
 
@@ -40,6 +47,18 @@ impl Connection {
     pub fn connect<S, A>(
         config: ConnectionConfig<A, S>,
     ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send
+    where
+        S: SetupTls,
+        A: Cmd + Send,
+    {
+        Connection::connect_with_options(config, SetupOptions::default())
+    }
+
+    /// like `connect` but uses the given `SetupOptions` (e.g. a greeting timeout)
+    pub fn connect_with_options<S, A>(
+        config: ConnectionConfig<A, S>,
+        opts: SetupOptions,
+    ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send
     where
         S: SetupTls,
         A: Cmd + Send,
@@ -53,13 +72,15 @@ impl Connection {
 
         #[allow(deprecated)]
         let con_fut = match security {
-            Security::None => Either::B(Either::A(Connection::_connect_insecure(&addr, client_id))),
+            Security::None => Either::B(Either::A(Connection::_connect_insecure(
+                &addr, client_id, opts,
+            ))),
             Security::DirectTls(tls_config) => Either::B(Either::B(
-                Connection::_connect_direct_tls(&addr, client_id, tls_config),
+                Connection::_connect_direct_tls(&addr, client_id, tls_config, opts),
+            )),
+            Security::StartTls(tls_config) => Either::A(Connection::_connect_starttls(
+                &addr, client_id, tls_config, opts,
             )),
-            Security::StartTls(tls_config) => {
-                Either::A(Connection::_connect_starttls(&addr, client_id, tls_config))
-            }
         };
 
         con_fut.and_then(|con| {
@@ -68,46 +89,206 @@ impl Connection {
         })
     }
 
+    /// like `connect` but also returns the greeting (banner) of the server
+    ///
+    /// This works for all kinds of `Security`, with `STARTTLS` it's the
+    /// greeting received before upgrading the connection.
+    pub fn connect_with_greeting<S, A>(
+        config: ConnectionConfig<A, S>,
+        opts: SetupOptions,
+    ) -> impl Future<Item = (Connection, Response), Error = ConnectingFailed> + Send
+    where
+        S: SetupTls,
+        A: Cmd + Send,
+    {
+        Connection::connect_with_options(config, opts).map(|con| {
+            //UNWRAP_SAFE: connecting only succeeds after receiving the greeting
+            let greeting = con.greeting().cloned().unwrap();
+            (con, greeting)
+        })
+    }
+
+    /// like `connect` but fails if the server isn't the one identified by `identity`
+    ///
+    /// This is meant for reconnecting to the same backend, see `ServerIdentity`.
+    /// The identity is checked after the connection was fully set up, i.e.
+    /// after authenticating.
+    pub fn connect_with_affinity<S, A>(
+        config: ConnectionConfig<A, S>,
+        opts: SetupOptions,
+        identity: ServerIdentity,
+    ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send
+    where
+        S: SetupTls,
+        A: Cmd + Send,
+    {
+        Connection::connect_with_options(config, opts).and_then(move |con| identity.verify(con))
+    }
+
+    /// like `connect` but tries each of the `candidates` (instead of `config.addr`) in order
+    ///
+    /// The candidates are tried one after another (not raced), if connecting,
+    /// the greeting, EHLO or authentication fails the next one is tried. The
+    /// first fully set up connection is returned. If all candidates fail
+    /// the error lists the failure of each of them.
+    ///
+    /// This is the normal next-hop behavior of a MTA, e.g. with the addresses
+    /// of the mail exchangers of a domain (see `mx::mail_exchangers`) in the
+    /// order of their preference.
+    pub fn connect_to_first<S, A>(
+        config: ConnectionConfig<A, S>,
+        opts: SetupOptions,
+        candidates: Vec<SocketAddr>,
+    ) -> impl Future<Item = Connection, Error = AllCandidatesFailed> + Send
+    where
+        S: SetupTls + Clone,
+        A: Cmd + Send + Clone,
+    {
+        let mut candidates = candidates;
+        // so that `pop` returns them in order
+        candidates.reverse();
+        future::loop_fn(
+            (config, opts, candidates, Vec::new()),
+            |(config, opts, mut candidates, mut failures)| {
+                let addr = match candidates.pop() {
+                    Some(addr) => addr,
+                    None => return Either::A(future::err(AllCandidatesFailed::new(failures))),
+                };
+                let mut candidate_config = config.clone();
+                candidate_config.addr = addr;
+                let fut = Connection::connect_with_options(candidate_config, opts.clone());
+                let fut = fut.then(move |res| match res {
+                    Ok(con) => Ok(Loop::Break(con)),
+                    Err(err) => {
+                        failures.push((addr, err));
+                        Ok(Loop::Continue((config, opts, candidates, failures)))
+                    }
+                });
+                Either::B(fut)
+            },
+        )
+    }
+
     #[doc(hidden)]
     pub fn _connect_insecure_no_ehlo(
         addr: &SocketAddr,
+        greeting_timeout: Option<Duration>,
     ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send {
         Io::connect_insecure(addr)
-            .and_then(Io::parse_response)
-            .then(|res| {
-                let res = res.map(|(io, res)| (Connection::from(io), res));
-                cmd_future2connecting_future(res, ConnectingFailed::Setup)
-            })
+            .map_err(ConnectError::wrap_tcp)
+            .map_err(ConnectingFailed::Io)
+            .and_then(move |io| Connection::_setup_no_ehlo(io, greeting_timeout))
     }
 
     #[doc(hidden)]
     pub fn _connect_direct_tls_no_ehlo<S>(
         addr: &SocketAddr,
         config: TlsConfig<S>,
+        greeting_timeout: Option<Duration>,
     ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send
     where
         S: SetupTls,
     {
         Io::connect_secure(addr, config)
-            .and_then(Io::parse_response)
+            .map_err(ConnectingFailed::Io)
+            .and_then(move |io| Connection::_setup_no_ehlo(io, greeting_timeout))
+    }
+
+    /// reads the greeting from a freshly opened connection
+    ///
+    /// The greeting is read completely, i.e. including all continuation
+    /// lines, before the future resolves. So no command can be written
+    /// before the server finished greeting us.
+    ///
+    /// If a `greeting_timeout` is given and the greeting wasn't received
+    /// in time it fails with an `io::Error` of kind `TimedOut` (with a
+    /// `ConnectError::Timeout` as inner error). (Using a
+    /// timeout requires the future to be run on a tokio runtime.)
+    #[doc(hidden)]
+    pub fn _setup_no_ehlo(
+        io: Io,
+        greeting_timeout: Option<Duration>,
+    ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send {
+        read_greeting(io, greeting_timeout).then(|res| {
+            let res = res.map(|(io, res)| (Connection::from(io), res));
+            cmd_future2connecting_future(res, ConnectingFailed::Setup)
+        })
+    }
+
+    /// reads the greeting from a freshly opened connection and then sends EHLO
+    ///
+    /// If `opts.ehlo_before_greeting` is set EHLO is written before the greeting
+    /// is read, the future still only resolves after both responses where read.
+    /// If the server accepted the connection but rejected the early EHLO (e.g.
+    /// with `503` or `554`) EHLO is send again, this time after the greeting.
+    #[doc(hidden)]
+    pub fn _setup(
+        io: Io,
+        clid: ClientId,
+        opts: SetupOptions,
+    ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send {
+        //Note: this has a circular dependency between Connection <-> cmd Ehlo which
+        // could be resolved using a ext. trait, but it's more ergonomic this way
+        use crate::command::Ehlo;
+
+        let SetupOptions {
+            greeting_timeout,
+            ehlo_before_greeting,
+            event_subscriber,
+            // already applied by `_connect_*`
+            keepalive: _,
+            buffer_config,
+        } = opts;
+
+        let mut io = io;
+        io.set_buffer_config(buffer_config);
+        if let Some(subscriber) = event_subscriber {
+            io.set_event_subscriber(subscriber);
+        }
+
+        if !ehlo_before_greeting {
+            let fut = Connection::_setup_no_ehlo(io, greeting_timeout).and_then(|con| {
+                con.send(Ehlo::from(clid))
+                    .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup))
+            });
+            return Either::A(fut);
+        }
+
+        Ehlo::from(clid.clone()).write_line(&mut io);
+        let fut = io
+            .flush()
+            .and_then(move |io| read_greeting(io, greeting_timeout))
+            .ctx_and_then(|io, _greeting| {
+                Ehlo::read_response(io).and_then(move |(io, result)| match result {
+                    // fall back to sending EHLO after the greeting
+                    Err(LogicError::Code(_)) => {
+                        let fut = Connection::from(io)
+                            .send(Ehlo::from(clid))
+                            .map(|(con, result)| (Io::from(con), result));
+                        Either::A(fut)
+                    }
+                    result => Either::B(future::ok((io, result))),
+                })
+            })
             .then(|res| {
                 let res = res.map(|(io, res)| (Connection::from(io), res));
                 cmd_future2connecting_future(res, ConnectingFailed::Setup)
-            })
+            });
+        Either::B(fut)
     }
 
     #[doc(hidden)]
     pub fn _connect_insecure(
         addr: &SocketAddr,
         clid: ClientId,
+        opts: SetupOptions,
     ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send {
-        //Note: this has a circular dependency between Connection <-> cmd Ehlo which
-        // could be resolved using a ext. trait, but it's more ergonomic this way
-        use crate::command::Ehlo;
-        Connection::_connect_insecure_no_ehlo(addr).and_then(|con| {
-            con.send(Ehlo::from(clid))
-                .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup))
-        })
+        let keepalive = opts.keepalive;
+        Io::connect_insecure(addr)
+            .map_err(ConnectError::wrap_tcp)
+            .and_then(move |io| io.socket().set_keepalive(keepalive).map(|()| io))
+            .map_err(ConnectingFailed::Io)
+            .and_then(move |io| Connection::_setup(io, clid, opts))
     }
 
     #[doc(hidden)]
@@ -115,17 +296,16 @@ impl Connection {
         addr: &SocketAddr,
         clid: ClientId,
         config: TlsConfig<S>,
+        opts: SetupOptions,
     ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send
     where
         S: SetupTls,
     {
-        //Note: this has a circular dependency between Connection <-> cmd Ehlo which
-        // could be resolved using a ext. trait, but it's more ergonomic this way
-        use crate::command::Ehlo;
-        Connection::_connect_direct_tls_no_ehlo(addr, config).and_then(|con| {
-            con.send(Ehlo::from(clid))
-                .then(|res| cmd_future2connecting_future(res, ConnectingFailed::Setup))
-        })
+        let keepalive = opts.keepalive;
+        Io::connect_secure(addr, config)
+            .and_then(move |io| io.socket().set_keepalive(keepalive).map(|()| io))
+            .map_err(ConnectingFailed::Io)
+            .and_then(move |io| Connection::_setup(io, clid, opts))
     }
 
     #[doc(hidden)]
@@ -133,6 +313,7 @@ impl Connection {
         addr: &SocketAddr,
         clid: ClientId,
         config: TlsConfig<S>,
+        opts: SetupOptions,
     ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send
     where
         S: SetupTls,
@@ -142,7 +323,7 @@ impl Connection {
         use crate::command::{Ehlo, StartTls};
         let TlsConfig { domain, setup } = config;
 
-        Connection::_connect_insecure(&addr, clid.clone())
+        Connection::_connect_insecure(&addr, clid.clone(), opts)
             .and_then(|con| {
                 con.send(StartTls {
                     setup_tls: setup,
@@ -155,6 +336,100 @@ impl Connection {
     }
 }
 
+/// reads the greeting, failing with `ErrorKind::TimedOut` if `greeting_timeout` elapses
+///
+/// Emits the `Connected` and (on success) `GreetingReceived` events and
+/// stores the greeting in the `Io` (see `Io::greeting`).
+fn read_greeting(
+    io: Io,
+    greeting_timeout: Option<Duration>,
+) -> impl Future<Item = (Io, SmtpResult), Error = std_io::Error> + Send {
+    io.emit_event(ConnectionEvent::Connected);
+    let parsing = io
+        .parse_response()
+        .map(emit_on_success(ConnectionEvent::GreetingReceived))
+        .map(|(mut io, result)| {
+            if let Ok(greeting) = result.as_ref() {
+                io.set_greeting(Some(greeting.clone()));
+            }
+            (io, result)
+        });
+    if let Some(timeout) = greeting_timeout {
+        let fut = Timeout::new(parsing, timeout).map_err(|err| {
+            if err.is_timer() {
+                let msg = "timer for the greeting timeout failed";
+                std_io::Error::new(std_io::ErrorKind::TimedOut, msg)
+            } else {
+                err.into_inner()
+                    .unwrap_or_else(ConnectError::new_timeout_error)
+            }
+        });
+        Either::A(fut)
+    } else {
+        Either::B(parsing)
+    }
+}
+
+/// Options for setting up a connection beyond what's in `ConnectionConfig`.
+///
+/// They are passed to `Connection::connect_with_options` (and the other
+/// `connect_*` functions taking them) separately from the `ConnectionConfig`,
+/// so that crating a `ConnectionConfig` by hand keeps working when new
+/// options are added. The `ConnectionBuilder` has setters for all of them.
+///
+/// When crating it by hand use `..Default::default()` for the options you
+/// don't set, as further options might be added in the future.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use new_tokio_smtp::SetupOptions;
+///
+/// let opts = SetupOptions {
+///     greeting_timeout: Some(Duration::from_secs(60)),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SetupOptions {
+    /// how long to wait for the servers greeting (`None` means no timeout)
+    ///
+    /// Some servers delay the greeting (e.g. for reverse DNS lookups or
+    /// greylisting) so this should be more generous than a timeout used for
+    /// commands. Using a timeout requires running the connect future on a
+    /// tokio runtime.
+    pub greeting_timeout: Option<Duration>,
+    /// send EHLO directly after connecting, without waiting for the greeting
+    ///
+    /// **This is not standard conform**, it saves a round trip but should
+    /// only be used with servers known to handle it (e.g. ones you control).
+    /// The greeting and the EHLO response are still both read and checked.
+    ///
+    /// If the server rejects the early EHLO (e.g. with `503 bad sequence`)
+    /// but did send a positive greeting, EHLO is send again after the greeting,
+    /// so a misjudged server costs one round trip but doesn't fail the setup.
+    pub ehlo_before_greeting: bool,
+    /// subscriber notified about lifecycle events of the connection
+    ///
+    /// It's set before the greeting is read, so it also receives the
+    /// `Connected`, `GreetingReceived` and the first `EhloCompleted` event.
+    pub event_subscriber: Option<Arc<dyn EventSubscriber>>,
+    /// enables TCP keepalive (`SO_KEEPALIVE`) with the given idle time and probe interval
+    ///
+    /// This keeps stateful firewalls/NATs from silently dropping long lived
+    /// (e.g. pooled) idle connections. `None` (the default) leaves the
+    /// system default, which normally means keepalive is disabled.
+    pub keepalive: Option<Keepalive>,
+    /// initial capacity and growth of the buffer responses are read into
+    ///
+    /// The default starts with an empty buffer which grows in small steps,
+    /// which is fine for the usual short responses. If a server is known to
+    /// send large multi-line responses (e.g. a long EHLO response) a larger
+    /// buffer saves some reads (and re-allocations).
+    pub buffer_config: BufferConfig,
+}
+
 /// configure what kind of security is used
 #[derive(Debug, Clone, PartialEq)]
 pub enum Security<S>
@@ -218,6 +493,134 @@ where
     pub client_id: ClientId,
 }
 
+impl<A, S> ConnectionConfig<A, S>
+where
+    S: SetupTls,
+    A: Cmd,
+{
+    /// returns a key identifying equivalent connections (e.g. for pooling them)
+    ///
+    /// The key is based on the address, the kind of security and the domain
+    /// used for TLS and the client id. The auth command and the `SetupTls`
+    /// instance can not be compared and are _not_ part of the key, if
+    /// different credentials are used `ConnectionKey::with_auth_identity`
+    /// has to be used to tell them apart.
+    pub fn connection_key(&self) -> ConnectionKey {
+        #[allow(deprecated)]
+        let (security, tls_domain) = match self.security {
+            Security::None => (SecurityKind::None, None),
+            Security::DirectTls(ref config) => {
+                (SecurityKind::DirectTls, Some(config.domain.clone()))
+            }
+            Security::StartTls(ref config) => (SecurityKind::StartTls, Some(config.domain.clone())),
+        };
+        ConnectionKey {
+            addr: self.addr,
+            security,
+            tls_domain,
+            client_id: self.client_id.clone(),
+            auth_identity: None,
+        }
+    }
+}
+
+/// A key identifying equivalent connections, see `ConnectionConfig::connection_key`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionKey {
+    addr: SocketAddr,
+    security: SecurityKind,
+    tls_domain: Option<Domain>,
+    client_id: ClientId,
+    auth_identity: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SecurityKind {
+    None,
+    DirectTls,
+    StartTls,
+}
+
+impl ConnectionKey {
+    /// adds the identity used for authentication (e.g. the username) to the key
+    ///
+    /// **Never pass in secrets** (like passwords or tokens), the key is not
+    /// meant to keep them safe (e.g. it implements `Debug`).
+    pub fn with_auth_identity<I>(mut self, identity: I) -> Self
+    where
+        I: Into<String>,
+    {
+        self.auth_identity = Some(identity.into());
+        self
+    }
+
+    /// the address connected to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+/// The identity of a server, used to keep the affinity to it across reconnects
+///
+/// The identity is the domain the server announced in it's EHLO response.
+/// A pool which reconnects using the same `ConnectionConfig` (and with it
+/// the same resolved address, see `ConnectionKey::addr`) can use it to
+/// detect that a reconnect ended up at a different backend, e.g. because
+/// of a misrouted connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ServerIdentity {
+    domain: Domain,
+}
+
+impl ServerIdentity {
+    pub fn new(domain: Domain) -> Self {
+        ServerIdentity { domain }
+    }
+
+    /// the identity of the server `con` is connected to, `None` if no EHLO was send
+    pub fn of(con: &Connection) -> Option<Self> {
+        con.ehlo_data()
+            .map(|ehlo| ServerIdentity::new(ehlo.domain().clone()))
+    }
+
+    /// the EHLO domain of the server
+    pub fn domain(&self) -> &Domain {
+        &self.domain
+    }
+
+    /// checks that `con` is connected to the server with this identity
+    ///
+    /// Domains are compared case-insensitive.
+    pub fn check(&self, con: &Connection) -> Result<(), AffinityMismatch> {
+        let found = con.ehlo_data().map(|ehlo| ehlo.domain());
+        let matches = found
+            .map(|found| found.as_str().eq_ignore_ascii_case(self.domain.as_str()))
+            .unwrap_or(false);
+        if matches {
+            Ok(())
+        } else {
+            Err(AffinityMismatch::new(self.domain.clone(), found.cloned()))
+        }
+    }
+
+    /// like `check` but quits the connection if it doesn't match
+    ///
+    /// The mismatch is returned as `ConnectingFailed::Setup` with a
+    /// `LogicError::Custom` wrapping the `AffinityMismatch`.
+    pub fn verify(
+        &self,
+        con: Connection,
+    ) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send {
+        match self.check(&con) {
+            Ok(()) => Either::A(future::ok(con)),
+            Err(err) => {
+                let err = ConnectingFailed::Setup(LogicError::Custom(Box::new(err)));
+                Either::B(con.quit().then(|_| Err(err)))
+            }
+        }
+    }
+}
+
 impl<A> ConnectionConfig<A, DefaultTlsSetup>
 where
     A: Cmd,
@@ -238,6 +641,7 @@ impl ConnectionConfig<Noop, DefaultTlsSetup> {
             client_id: None,
             port: DEFAULT_SMTP_MSA_PORT,
             auth_cmd: Noop,
+            options: SetupOptions::default(),
         }
     }
 
@@ -273,6 +677,7 @@ where
     client_id: Option<ClientId>,
     port: u16,
     auth_cmd: A,
+    options: SetupOptions,
 }
 
 impl<A> LocalNonSecureBuilder<A>
@@ -291,28 +696,70 @@ where
         self
     }
 
+    /// sets the timeout for receiving the servers greeting (default: no timeout)
+    pub fn greeting_timeout(mut self, timeout: Duration) -> Self {
+        self.options.greeting_timeout = Some(timeout);
+        self
+    }
+
+    /// sends EHLO without waiting for the greeting (default: false)
+    ///
+    /// **This is not standard conform**, see `SetupOptions::ehlo_before_greeting`.
+    pub fn send_ehlo_before_greeting(mut self) -> Self {
+        self.options.ehlo_before_greeting = true;
+        self
+    }
+
+    /// sets the subscriber for lifecycle events of the connection (default: none)
+    pub fn event_subscriber(mut self, subscriber: Arc<dyn EventSubscriber>) -> Self {
+        self.options.event_subscriber = Some(subscriber);
+        self
+    }
+
+    /// enables TCP keepalive with the given idle time or `Keepalive` settings (default: not enabled)
+    pub fn keepalive(mut self, keepalive: impl Into<Keepalive>) -> Self {
+        self.options.keepalive = Some(keepalive.into());
+        self
+    }
+
+    /// sets the capacity and growth of the response buffer (default: `BufferConfig::default()`)
+    pub fn buffer_config(mut self, config: BufferConfig) -> Self {
+        self.options.buffer_config = config;
+        self
+    }
+
     /// sets the auth command to use (default no authentication)
     pub fn auth<NA>(self, auth_cmd: NA) -> LocalNonSecureBuilder<NA>
     where
         NA: Cmd,
     {
         let LocalNonSecureBuilder {
-            client_id, port, ..
+            client_id,
+            port,
+            options,
+            ..
         } = self;
 
         LocalNonSecureBuilder {
             client_id,
             port,
             auth_cmd,
+            options,
         }
     }
 
     // builds the connection config
     pub fn build(self) -> ConnectionConfig<A, DefaultTlsSetup> {
+        self.build_with_options().0
+    }
+
+    /// builds the connection config and returns it with the set `SetupOptions`
+    pub fn build_with_options(self) -> (ConnectionConfig<A, DefaultTlsSetup>, SetupOptions) {
         let LocalNonSecureBuilder {
             client_id,
             port,
             auth_cmd,
+            options,
         } = self;
 
         let client_id = client_id.unwrap_or_else(ClientId::hostname);
@@ -322,17 +769,19 @@ where
         #[allow(deprecated)]
         let security = Security::None;
 
-        ConnectionConfig {
+        let config = ConnectionConfig {
             addr,
             client_id,
             auth_cmd,
             security,
-        }
+        };
+        (config, options)
     }
 
-    /// Calls `Connection::connect(self.build())`.
+    /// Connects using the built config and the set `SetupOptions`.
     pub fn connect(self) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send {
-        Connection::connect(self.build())
+        let (config, options) = self.build_with_options();
+        Connection::connect_with_options(config, options)
     }
 }
 
@@ -349,6 +798,7 @@ where
     setup_tls: S,
     use_security: UseSecurity,
     auth_cmd: A,
+    options: SetupOptions,
 }
 
 impl ConnectionBuilder<Noop, DefaultTlsSetup> {
@@ -395,6 +845,7 @@ impl ConnectionBuilder<Noop, DefaultTlsSetup> {
             client_id: None,
             setup_tls: DefaultTlsSetup,
             auth_cmd: Noop,
+            options: SetupOptions::default(),
         }
     }
 }
@@ -422,6 +873,7 @@ where
             use_security,
             client_id,
             auth_cmd,
+            options,
             ..
         } = self;
 
@@ -432,6 +884,7 @@ where
             client_id,
             setup_tls: setup,
             auth_cmd,
+            options,
         }
     }
 
@@ -471,6 +924,7 @@ where
             use_security,
             client_id,
             setup_tls,
+            options,
             ..
         } = self;
 
@@ -481,6 +935,7 @@ where
             client_id,
             setup_tls,
             auth_cmd,
+            options,
         }
     }
 
@@ -492,6 +947,47 @@ where
         self
     }
 
+    /// Sets the timeout for receiving the servers greeting.
+    ///
+    /// (The default is to not use a timeout)
+    pub fn greeting_timeout(mut self, timeout: Duration) -> Self {
+        self.options.greeting_timeout = Some(timeout);
+        self
+    }
+
+    /// Sends EHLO without waiting for the greeting.
+    ///
+    /// **This is not standard conform**, see `SetupOptions::ehlo_before_greeting`.
+    pub fn send_ehlo_before_greeting(mut self) -> Self {
+        self.options.ehlo_before_greeting = true;
+        self
+    }
+
+    /// Sets the subscriber for lifecycle events of the connection.
+    ///
+    /// (The default is to not have any subscriber)
+    pub fn event_subscriber(mut self, subscriber: Arc<dyn EventSubscriber>) -> Self {
+        self.options.event_subscriber = Some(subscriber);
+        self
+    }
+
+    /// Enables TCP keepalive with the given idle time or `Keepalive` settings,
+    /// see `SetupOptions::keepalive`.
+    ///
+    /// (The default is to not enable keepalive)
+    pub fn keepalive(mut self, keepalive: impl Into<Keepalive>) -> Self {
+        self.options.keepalive = Some(keepalive.into());
+        self
+    }
+
+    /// Sets the capacity and growth of the response buffer, see `SetupOptions::buffer_config`.
+    ///
+    /// (The default is `BufferConfig::default()`)
+    pub fn buffer_config(mut self, config: BufferConfig) -> Self {
+        self.options.buffer_config = config;
+        self
+    }
+
     /// Creates a new connection config.
     ///
     /// If not specified differently, then
@@ -501,7 +997,24 @@ where
     /// - `StartTls` is used as security method
     /// - `DefaultTlsSetup` is used for setting up tls (i.e. no special options are set)
     ///
+    /// The `SetupOptions` (e.g. the greeting timeout) are not part of the
+    /// `ConnectionConfig`, use `build_with_options` or `connect` if you set any.
     pub fn build(self) -> ConnectionConfig<A, S> {
+        self.build_with_options().0
+    }
+
+    /// Creates a new connection config and returns it with the set `SetupOptions`.
+    ///
+    /// If not specified differently, then
+    ///
+    /// - no timeout is used for receiving the greeting
+    /// - EHLO is only send after receiving the greeting
+    /// - no event subscriber is set
+    /// - TCP keepalive is not enabled
+    /// - the default `BufferConfig` is used
+    ///
+    /// (For the defaults of the `ConnectionConfig` see `build`.)
+    pub fn build_with_options(self) -> (ConnectionConfig<A, S>, SetupOptions) {
         let ConnectionBuilder {
             addr,
             domain,
@@ -509,6 +1022,7 @@ where
             client_id,
             setup_tls: setup,
             auth_cmd,
+            options,
         } = self;
 
         let tls_config = TlsConfig { domain, setup };
@@ -519,17 +1033,19 @@ where
 
         let client_id = client_id.unwrap_or_else(ClientId::hostname);
 
-        ConnectionConfig {
+        let config = ConnectionConfig {
             addr,
             security,
             auth_cmd,
             client_id,
-        }
+        };
+        (config, options)
     }
 
-    /// Calls `Connection::connect(self.build())`.
+    /// Connects using the built config and the set `SetupOptions`.
     pub fn connect(self) -> impl Future<Item = Connection, Error = ConnectingFailed> + Send {
-        Connection::connect(self.build())
+        let (config, options) = self.build_with_options();
+        Connection::connect_with_options(config, options)
     }
 }
 
@@ -558,6 +1074,23 @@ mod testd {
     //this domain has to exist
     const EXAMPLE_DOMAIN: &str = "1aim.com";
 
+    #[test]
+    fn connection_key_depends_on_port() {
+        let domain = Domain::new_unchecked("smtp1.example.com".to_owned());
+        let addr = SocketAddr::from(([127, 0, 0, 1], 587));
+        let config = |addr| {
+            ConnectionBuilder::new_with_addr(addr, domain.clone())
+                .client_id(ClientId::localhost())
+                .build()
+        };
+
+        let key = config(addr).connection_key();
+        assert_eq!(key, config(addr).connection_key());
+        let other_addr = SocketAddr::from(([127, 0, 0, 1], 465));
+        assert_ne!(key, config(other_addr).connection_key());
+        assert_ne!(key, key.clone().with_auth_identity("user"));
+    }
+
     #[test]
     fn builder_uses_right_defaults() {
         //
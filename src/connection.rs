@@ -1,11 +1,17 @@
 use std::io as std_io;
+use std::sync::Arc;
 
 use futures::future::{self, Either, Future};
+use futures::{try_ready, Async, Poll};
+use tokio::clock;
 use tokio::io::{shutdown, Shutdown};
 
 use crate::common::EhloData;
-use crate::error::{LogicError, MissingCapabilities};
-use crate::io::{Io, SmtpResult, Socket};
+use crate::data_types::EhloParam;
+use crate::error::{LogicError, MissingCapabilities, UnexpectedResponse};
+use crate::event::{CommandTiming, ConnectionEvent, EventSubscriber};
+use crate::io::{ByteLogger, Io, IoParts, SmtpResult, Socket, TransactionState};
+use crate::response::{ExpectedCode, Response};
 
 /// future returned by `Cmd::exec`
 pub type ExecFuture =
@@ -101,6 +107,10 @@ impl Connection {
     /// can be used to chain `send` calls in a way that the next call is only
     /// run if there was no error at all (neither connection nor logic error).
     ///
+    /// If the command is not available (see `Cmd::check_cmd_availability`)
+    /// nothing is send and `LogicError::MissingCapabilities` is returned, or
+    /// `LogicError::EhloRequired` if no EHLO was performed on the connection.
+    ///
     /// # Connection Failure
     ///
     /// If the connection fails (e.g. the internet connection is interrupted)
@@ -111,15 +121,135 @@ impl Connection {
         cmd: C,
     ) -> impl Future<Item = (Connection, SmtpResult), Error = std_io::Error> {
         if let Err(err) = cmd.check_cmd_availability(self.io.ehlo_data()) {
-            Either::B(future::ok((
-                self,
-                Err(LogicError::MissingCapabilities(err)),
-            )))
+            let err = if self.io.ehlo_data().is_none() {
+                LogicError::EhloRequired(err)
+            } else {
+                LogicError::MissingCapabilities(err)
+            };
+            Either::B(future::ok((self, Err(err))))
+        } else {
+            let mut io: Io = self.into();
+            if cmd.is_sensitive() {
+                io.set_redact_output(Some(cmd.verb()));
+            }
+            let verb = cmd.verb();
+            let started = if io.records_timing() {
+                io.clear_timing_marks();
+                Some(clock::now())
+            } else {
+                None
+            };
+            Either::A(cmd.exec(io).map(move |(mut io, smtp_res)| {
+                io.set_redact_output(None);
+                if let Some(started) = started {
+                    let timing = CommandTiming {
+                        verb,
+                        think_time: io.think_time(),
+                        total: clock::now() - started,
+                    };
+                    io.set_last_command_timing(timing);
+                    io.emit_event(ConnectionEvent::CommandTimed(timing));
+                }
+                (Connection::from(io), smtp_res)
+            }))
+        }
+    }
+
+    /// sends the line as raw command and checks the response code (see `command::Raw`)
+    ///
+    /// Returns the response if its code matches the expected one (even if it's
+    /// an error code), else wise a `LogicError::Custom` containing a
+    /// `UnexpectedResponse` error is returned.
+    pub fn exec_expect<L>(
+        self,
+        line: L,
+        expected: ExpectedCode,
+    ) -> impl Future<Item = (Connection, SmtpResult), Error = std_io::Error>
+    where
+        L: Into<String>,
+    {
+        use crate::command::Raw;
+
+        let line = line.into();
+        self.send(Raw { line }).map(move |(con, result)| {
+            let response = match result {
+                Ok(response)
+                | Err(LogicError::Code(response))
+                | Err(LogicError::UnexpectedCode(response)) => response,
+                Err(err) => return (con, Err(err)),
+            };
+            if expected.matches(response.code()) {
+                (con, Ok(response))
+            } else {
+                let err = UnexpectedResponse::new(expected, response);
+                (con, Err(LogicError::Custom(Box::new(err))))
+            }
+        })
+    }
+
+    /// sets a `ByteLogger` which is called with all data send and received
+    ///
+    /// The payload of sensitive commands is redacted (see `Cmd::is_sensitive`).
+    pub fn set_byte_logger(&mut self, logger: Box<dyn ByteLogger>) {
+        self.io.set_byte_logger(logger)
+    }
+
+    /// sets a `EventSubscriber` which is notified about lifecycle events
+    ///
+    /// To also receive the events emitted while connecting use
+    /// `ConnectionConfig::event_subscriber` instead.
+    pub fn set_event_subscriber(&mut self, subscriber: Arc<dyn EventSubscriber>) {
+        self.io.set_event_subscriber(subscriber)
+    }
+
+    /// polls for a response the server send while the connection is idle
+    ///
+    /// A `Ready(Some(_))` (normally a `421`) or `Ready(None)` (socket closed)
+    /// means the server is shutting down the connection, so it should be discarded.
+    /// In that case a `ConnectionEvent::Closed` event is emitted.
+    /// See `Io::poll_unsolicited` for details.
+    pub fn poll_unsolicited(&mut self) -> Poll<Option<Response>, std_io::Error> {
+        let ready = try_ready!(self.io.poll_unsolicited());
+        let reason = self.close_reason(ready.clone());
+        self.io.emit_event(ConnectionEvent::Closed(Some(reason)));
+        Ok(Async::Ready(ready))
+    }
+
+    /// the number of syntax error responses received in a row, see `Io::consecutive_errors`
+    pub fn consecutive_errors(&self) -> usize {
+        self.io.consecutive_errors()
+    }
+
+    /// classifies why the server closed (or is closing) the connection
+    ///
+    /// `response` is the last response received before the connection was
+    /// closed, e.g. a `421` returned by `poll_unsolicited` or as response to
+    /// a command, `None` if the socket was closed without a response.
+    ///
+    /// Servers like postfix close the connection if a client caused too many
+    /// errors (`smtpd_hard_error_limit`), this is detected and reported as
+    /// `CloseReason::TooManyErrors`, which shouldn't be retried by reconnecting.
+    pub fn close_reason(&self, response: Option<Response>) -> CloseReason {
+        let consecutive_errors = self.consecutive_errors();
+        let mentions_errors = response
+            .as_ref()
+            .map(|response| {
+                response
+                    .msg()
+                    .iter()
+                    .any(|line| line.to_ascii_lowercase().contains("too many errors"))
+            })
+            .unwrap_or(false);
+
+        if mentions_errors || consecutive_errors >= HARD_ERROR_THRESHOLD {
+            CloseReason::TooManyErrors {
+                consecutive_errors,
+                response,
+            }
+        } else if let Some(response) = response {
+            CloseReason::ServiceUnavailable(response)
         } else {
-            Either::A(
-                cmd.exec(self.into())
-                    .map(|(io, smtp_res)| (Connection::from(io), smtp_res)),
-            )
+            CloseReason::Closed
         }
     }
 
@@ -143,6 +273,63 @@ impl Connection {
         self.io.ehlo_data()
     }
 
+    /// limits the total number of bytes written through this connection
+    ///
+    /// See `Io::set_write_quota`.
+    pub fn set_write_quota(&mut self, quota: Option<usize>) {
+        self.io.set_write_quota(quota);
+    }
+
+    /// enables tracking the state of the current mail transaction
+    ///
+    /// See `Io::enable_transaction_tracking`.
+    pub fn enable_transaction_tracking(&mut self) {
+        self.io.enable_transaction_tracking();
+    }
+
+    /// returns the state of the current mail transaction, `None` if it's not tracked
+    ///
+    /// See `Io::transaction_state`.
+    pub fn transaction_state(&self) -> Option<TransactionState> {
+        self.io.transaction_state()
+    }
+
+    /// enables/disables recording the timing of commands send with `send`
+    ///
+    /// See `Io::set_record_timing`.
+    pub fn set_record_timing(&mut self, enable: bool) {
+        self.io.set_record_timing(enable);
+    }
+
+    /// returns the timing of the last command, if timing is recorded
+    ///
+    /// See `Io::last_command_timing`.
+    pub fn last_command_timing(&self) -> Option<CommandTiming> {
+        self.io.last_command_timing()
+    }
+
+    /// returns the greeting of the server, if it was received through this connection
+    ///
+    /// See `Io::greeting`.
+    pub fn greeting(&self) -> Option<&Response> {
+        self.io.greeting()
+    }
+
+    /// returns the `AUTH` mechanisms of the last ehlo call (empty if there are none)
+    ///
+    /// The mechanisms are parsed once when the EHLO response is received and
+    /// are replaced with the ehlo data on the next EHLO (e.g. after `STARTTLS`).
+    ///
+    /// This returns `&[EhloParam]` and not `&[&str]`: a cached `Vec<&str>` would
+    /// borrow from the ehlo data stored in the same connection, which can't be
+    /// expressed without unsafe code. `EhloParam` can be compared with `&str`
+    /// and `EhloParam::as_str` gives access to the mechanism name.
+    pub fn auth_mechanisms(&self) -> &[EhloParam] {
+        self.ehlo_data()
+            .map(EhloData::auth_mechanisms)
+            .unwrap_or(&[])
+    }
+
     /// converts the `Connection` into an `Io` instance
     ///
     /// This is only need when implementing custom `Cmd`'s
@@ -151,9 +338,18 @@ impl Connection {
         io
     }
 
+    /// converts the `Connection` into the parts of it's `Io` instance
+    ///
+    /// Unlike `into_inner().split()` this also includes info about the tls
+    /// session (if the connection is secure), e.g. to log it on teardown.
+    pub fn into_parts(self) -> IoParts {
+        self.into_inner().into_parts()
+    }
+
     /// shutdown the connection _without_ sending quit
     pub fn shutdown(self) -> Shutdown<Socket> {
         let io = self.into_inner();
+        io.emit_event(ConnectionEvent::Closed(None));
         let (socket, _, _) = io.split();
         shutdown(socket)
     }
@@ -170,6 +366,46 @@ impl Connection {
 
         self.send(Quit).and_then(|(con, _res)| con.shutdown())
     }
+
+    /// sends quit to the server and shuts down the socket without waiting for the response
+    ///
+    /// This is a faster but less graceful alternative to `quit`, the `221`
+    /// response (or any other data the server sends) is never read.
+    pub fn quit_fast(self) -> impl Future<Item = Socket, Error = std_io::Error> {
+        let mut io = self.into_inner();
+        io.write_line_from_parts(&["QUIT"]);
+        io.flush().and_then(|io| Connection::from(io).shutdown())
+    }
+}
+
+/// number of syntax errors in a row (see `Io::consecutive_errors`) after which closing the connection is seen as `CloseReason::TooManyErrors`
+pub const HARD_ERROR_THRESHOLD: usize = 3;
+
+/// The reason why a server closed the connection, see `Connection::close_reason`
+#[derive(Debug, Clone)]
+pub enum CloseReason {
+    /// the server closed the connection with a response (normally `421`)
+    ///
+    /// E.g. because it shuts down or the connection was idle for too long,
+    /// reconnecting (later) is fine.
+    ServiceUnavailable(Response),
+    /// the server gave up on the client after too many errors
+    ///
+    /// This is terminal, reconnecting and sending the same commands will
+    /// most likely just fail again.
+    TooManyErrors {
+        consecutive_errors: usize,
+        response: Option<Response>,
+    },
+    /// the connection was closed without any response
+    Closed,
+}
+
+impl CloseReason {
+    /// true if the connection shouldn't just be reestablished to retry
+    pub fn is_terminal(&self) -> bool {
+        matches!(*self, CloseReason::TooManyErrors { .. })
+    }
 }
 
 /// create a new `Connection` from a `Io` instance
@@ -210,6 +446,25 @@ pub trait Cmd: Send + 'static {
     /// for a given connection
     fn check_cmd_availability(&self, caps: Option<&EhloData>) -> Result<(), MissingCapabilities>;
 
+    /// The smtp verb of this command, e.g. `"EHLO"` or `"MAIL"`
+    ///
+    /// This is meant for logging and metrics, so it should be
+    /// a fixed uppercase string and not the rendered command line.
+    /// Defaults to `"UNKNOWN"` for commands which don't provide it.
+    fn verb(&self) -> &'static str {
+        "UNKNOWN"
+    }
+
+    /// True if the payload of this command should not be logged
+    ///
+    /// If true the `ByteLogger` (if any) is only told the verb and
+    /// how many bytes where written, the response is logged as usual.
+    /// This should be true for commands containing credentials or
+    /// mail bodies.
+    fn is_sensitive(&self) -> bool {
+        false
+    }
+
     /// Executes this command on the given connection
     ///
     /// This method should not be called directly, instead it
@@ -256,6 +511,20 @@ pub trait TypeErasableCmd {
     #[doc(hidden)]
     fn _check_cmd_availability(&self, caps: Option<&EhloData>) -> Result<(), MissingCapabilities>;
 
+    /// # Panics
+    ///
+    /// may panic if called after `_only_once_exec` was
+    /// called
+    #[doc(hidden)]
+    fn _verb(&self) -> &'static str;
+
+    /// # Panics
+    ///
+    /// may panic if called after `_only_once_exec` was
+    /// called
+    #[doc(hidden)]
+    fn _is_sensitive(&self) -> bool;
+
     /// # Panics
     ///
     /// may panic if called more then once
@@ -277,6 +546,18 @@ where
         me.check_cmd_availability(caps)
     }
 
+    fn _verb(&self) -> &'static str {
+        let me = self.as_ref().expect("_verb called after _only_once_exec");
+        me.verb()
+    }
+
+    fn _is_sensitive(&self) -> bool {
+        let me = self
+            .as_ref()
+            .expect("_is_sensitive called after _only_once_exec");
+        me.is_sensitive()
+    }
+
     fn _only_once_exec(&mut self, io: Io) -> ExecFuture {
         let me = self.take().expect("_only_once_exec called a second time");
         me.exec(io)
@@ -288,6 +569,14 @@ impl Cmd for BoxedCmd {
         self._check_cmd_availability(caps)
     }
 
+    fn verb(&self) -> &'static str {
+        self._verb()
+    }
+
+    fn is_sensitive(&self) -> bool {
+        self._is_sensitive()
+    }
+
     fn exec(mut self, io: Io) -> ExecFuture {
         self._only_once_exec(io)
     }
@@ -179,6 +179,65 @@ impl ReversePath {
     }
 }
 
+impl ForwardPath {
+    /// creates a source routed forward path (e.g. `"@relay1,@relay2:user@final"`)
+    ///
+    /// Source routes are deprecated (RFC 5321 Appendix C) and should only be
+    /// used when talking to systems which require them. Each relay is validated
+    /// to be a `Domain`. If no relays are given this is the same as
+    /// `ForwardPath::from_unchecked(mailbox)`.
+    ///
+    /// ```
+    /// use new_tokio_smtp::ForwardPath;
+    ///
+    /// let fpath = ForwardPath::with_source_route(&["relay1.example.com"], "user@example.com").unwrap();
+    /// assert_eq!(fpath.as_str(), "@relay1.example.com:user@example.com");
+    /// ```
+    pub fn with_source_route<I>(relays: I, mailbox: &str) -> Result<Self, SyntaxError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let mut route = Vec::new();
+        for relay in relays {
+            let domain: Domain = relay.as_ref().parse()?;
+            route.push(format!("@{}", domain.as_str()));
+        }
+        if route.is_empty() {
+            Ok(ForwardPath(mailbox.to_owned()))
+        } else {
+            Ok(ForwardPath(format!("{}:{}", route.join(","), mailbox)))
+        }
+    }
+
+    /// returns the relay domains of the source route (empty if there is none)
+    pub fn source_route(&self) -> Vec<&str> {
+        self.split_source_route()
+            .0
+            .map(|route| {
+                route
+                    .split(',')
+                    .map(|relay| relay.trim_start_matches('@'))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// returns the mailbox, i.e. the path without the source route
+    pub fn mailbox(&self) -> &str {
+        self.split_source_route().1
+    }
+
+    fn split_source_route(&self) -> (Option<&str>, &str) {
+        if self.0.starts_with('@') {
+            if let Some(idx) = self.0.find(':') {
+                return (Some(&self.0[..idx]), &self.0[idx + 1..]);
+            }
+        }
+        (None, &self.0)
+    }
+}
+
 impl FromStr for EhloParam {
     type Err = SyntaxError;
 
@@ -308,6 +367,7 @@ pub enum SyntaxError {
     AddressLiteral,
     EsmtpValue,
     EsmtpKeyword,
+    XText,
 }
 
 impl Display for SyntaxError {
@@ -322,6 +382,7 @@ impl Display for SyntaxError {
                 EsmtpKeyword => "syntax error parsing esmtp-keyword from str",
                 EsmtpValue => "syntax error parsing esmtp-value from str",
                 AddressLiteral => "syntax error parsing address-literal from str",
+                XText => "syntax error parsing xtext from str",
             }
         )
     }
@@ -495,6 +556,37 @@ mod test {
         }
     }
 
+    mod ForwardPath {
+        use super::super::{ForwardPath, SyntaxError};
+
+        #[test]
+        fn source_route() {
+            let fpath =
+                ForwardPath::with_source_route(&["Relay1.test", "relay2.test"], "user@final.test")
+                    .unwrap();
+            assert_eq!(fpath, "@relay1.test,@relay2.test:user@final.test");
+            assert_eq!(fpath.source_route(), vec!["relay1.test", "relay2.test"]);
+            assert_eq!(fpath.mailbox(), "user@final.test");
+        }
+
+        #[test]
+        fn no_source_route_by_default() {
+            let fpath = ForwardPath::from_unchecked("user@final.test");
+            assert!(fpath.source_route().is_empty());
+            assert_eq!(fpath.mailbox(), "user@final.test");
+
+            let empty: &[&str] = &[];
+            let fpath = ForwardPath::with_source_route(empty, "user@final.test").unwrap();
+            assert_eq!(fpath, "user@final.test");
+        }
+
+        #[test]
+        fn source_route_relays_are_validated() {
+            let res = ForwardPath::with_source_route(&["relay1.test", "bad relay"], "u@final.test");
+            assert_eq!(res, Err(SyntaxError::Domain));
+        }
+    }
+
     mod Domain {
         use super::super::Domain;
 
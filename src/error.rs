@@ -1,9 +1,10 @@
 //! error module
-use crate::data_types::{Capability, EsmtpKeyword};
-use crate::response::Response;
+use crate::data_types::{Capability, Domain, EsmtpKeyword};
+use crate::response::{ExpectedCode, Response};
 use std::error::Error;
 use std::fmt::{self, Debug, Display};
 use std::io as std_io;
+use std::net::SocketAddr;
 
 #[derive(Debug)]
 pub enum GeneralError {
@@ -88,6 +89,139 @@ impl Display for ConnectingFailed {
     }
 }
 
+impl ConnectingFailed {
+    /// the `ConnectError` if connecting failed while opening the (TCP/TLS) connection
+    ///
+    /// See `ConnectError::from_io_error`.
+    pub fn connect_error(&self) -> Option<&ConnectError> {
+        match *self {
+            ConnectingFailed::Io(ref err) => ConnectError::from_io_error(err),
+            _ => None,
+        }
+    }
+}
+
+/// error representing which step of opening a connection failed
+///
+/// The connect functions (e.g. `Io::connect_secure`, `Connection::connect`)
+/// return this as inner error of the `io::Error` they fail with, use
+/// `ConnectError::from_io_error` (or `ConnectingFailed::connect_error`) to
+/// get it. The kind of the `io::Error` is that of the cause for `Tcp`,
+/// `TimedOut` for `Timeout` and `Other` for the tls errors.
+#[derive(Debug)]
+pub enum ConnectError {
+    /// the TCP connection could not be established (e.g. it was refused)
+    Tcp(std_io::Error),
+    /// the tls handshake failed (e.g. because the certificate of the server was not valid)
+    ///
+    /// See `ConnectError::is_probably_cert_validation_error`.
+    TlsHandshake(native_tls::Error),
+    /// the server didn't send a greeting in time (see `greeting_timeout`)
+    Timeout,
+}
+
+impl ConnectError {
+    pub(crate) fn wrap_tcp(cause: std_io::Error) -> std_io::Error {
+        let kind = cause.kind();
+        std_io::Error::new(kind, ConnectError::Tcp(cause))
+    }
+
+    pub(crate) fn wrap_tls(cause: native_tls::Error) -> std_io::Error {
+        std_io::Error::new(std_io::ErrorKind::Other, ConnectError::TlsHandshake(cause))
+    }
+
+    pub(crate) fn new_timeout_error() -> std_io::Error {
+        std_io::Error::new(std_io::ErrorKind::TimedOut, ConnectError::Timeout)
+    }
+
+    /// returns the `ConnectError` if it's the inner error of `err`
+    pub fn from_io_error(err: &std_io::Error) -> Option<&ConnectError> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<ConnectError>())
+    }
+
+    /// true if this is a `Tcp` or `Timeout` error, which often are worth a retry
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            ConnectError::Tcp(_) | ConnectError::Timeout => true,
+            ConnectError::TlsHandshake(_) => false,
+        }
+    }
+
+    /// true if this is a `TlsHandshake` error which looks like the certificate was not valid
+    ///
+    /// This is a best-effort guess for diagnostics (e.g. log messages) only.
+    /// The tls layer doesn't classify its errors, so this checks if the error
+    /// message mentions a certificate, which depends on the platform (and tls
+    /// backend). Don't base retry or security decisions on it.
+    pub fn is_probably_cert_validation_error(&self) -> bool {
+        match *self {
+            ConnectError::TlsHandshake(ref err) => {
+                err.to_string().to_lowercase().contains("certificate")
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Error for ConnectError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            ConnectError::Tcp(ref err) => Some(err),
+            ConnectError::TlsHandshake(ref err) => Some(err),
+            ConnectError::Timeout => None,
+        }
+    }
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ConnectError::Tcp(ref err) => write!(fter, "TCP connect failed: {}", err),
+            ConnectError::TlsHandshake(ref err) => write!(fter, "tls handshake failed: {}", err),
+            ConnectError::Timeout => write!(fter, "timed out waiting for greeting"),
+        }
+    }
+}
+
+/// error returned by `Connection::connect_to_first` if no candidate could be connected to
+#[derive(Debug)]
+pub struct AllCandidatesFailed {
+    failures: Vec<(SocketAddr, ConnectingFailed)>,
+}
+
+impl AllCandidatesFailed {
+    pub(crate) fn new(failures: Vec<(SocketAddr, ConnectingFailed)>) -> Self {
+        AllCandidatesFailed { failures }
+    }
+
+    /// the address and failure of each candidate, in the order they were tried
+    ///
+    /// This is empty if there where no candidates.
+    pub fn failures(&self) -> &[(SocketAddr, ConnectingFailed)] {
+        &self.failures
+    }
+
+    pub fn into_failures(self) -> Vec<(SocketAddr, ConnectingFailed)> {
+        self.failures
+    }
+}
+
+impl Error for AllCandidatesFailed {}
+
+impl Display for AllCandidatesFailed {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.failures.is_empty() {
+            return write!(fter, "connecting failed: no candidates given");
+        }
+        write!(fter, "connecting to all candidates failed")?;
+        for (addr, err) in self.failures.iter() {
+            write!(fter, "; {}: {}", addr, err)?;
+        }
+        Ok(())
+    }
+}
+
 pub fn check_response(response: Response) -> Result<Response, LogicError> {
     if response.is_erroneous() {
         Err(LogicError::Code(response))
@@ -123,6 +257,96 @@ pub enum LogicError {
 
     /// command can not be used, as the server does not promotes the necessary capabilities
     MissingCapabilities(MissingCapabilities),
+
+    /// command needs capabilities, but no EHLO was performed yet
+    ///
+    /// So it's unknown if the server supports them, the contained error
+    /// lists the capabilities the command needs. Returned by `Connection::send`
+    /// and `pipeline` instead of `MissingCapabilities` if there is no `EhloData`.
+    ///
+    /// (Adding this variant is a breaking change for code exhaustively
+    /// matching on `LogicError`.)
+    EhloRequired(MissingCapabilities),
+}
+
+impl LogicError {
+    /// maps the error to a `sysexits.h` style exit code (see `exit_codes`)
+    ///
+    /// Error responses are mapped based on their code and enhanced status
+    /// code (if any):
+    ///
+    /// - transient failures (`4xx`) map to `EX_TEMPFAIL`
+    /// - unknown/invalid mailboxes (`X.1.1`, `X.1.3`, `X.1.6`, `551`, `553`) map to `EX_NOUSER`
+    /// - a bad destination system (`X.1.2`) maps to `EX_NOHOST`
+    /// - security or policy reasons (`X.7.X`) map to `EX_NOPERM`
+    /// - content problems and too large mails (`X.3.4`, `X.6.X`, `552`) map to `EX_DATAERR`
+    /// - protocol errors (`X.5.X`, `500`-`504`) map to `EX_PROTOCOL`
+    /// - any other permanent failure maps to `EX_UNAVAILABLE`
+    ///
+    /// Unexpected (non error) responses map to `EX_PROTOCOL`, missing
+    /// capabilities to `EX_UNAVAILABLE`, a `MessageTooLarge` error to
+    /// `EX_DATAERR`, a `AuthenticationRequired` error to `EX_NOPERM` and any
+    /// other error to `EX_SOFTWARE`.
+    pub fn exit_code(&self) -> i32 {
+        use self::exit_codes::*;
+        use self::LogicError::*;
+        match *self {
+            Code(ref response) => exit_code_for_response(response),
+            UnexpectedCode(_) => EX_PROTOCOL,
+            MissingCapabilities(_) => EX_UNAVAILABLE,
+            EhloRequired(_) => EX_SOFTWARE,
+            Custom(ref err) if err.is::<MessageTooLarge>() => EX_DATAERR,
+            Custom(ref err) if err.is::<AuthenticationRequired>() => EX_NOPERM,
+            Custom(_) => EX_SOFTWARE,
+        }
+    }
+}
+
+fn exit_code_for_response(response: &Response) -> i32 {
+    use self::exit_codes::*;
+    let code = response.code();
+    if code.is_transient_failure() {
+        return EX_TEMPFAIL;
+    }
+    if !code.is_permanent_failure() {
+        return EX_PROTOCOL;
+    }
+    if let Some(status) = response.enhanced_status_code() {
+        return match (status.subject, status.detail) {
+            (1, 1) | (1, 3) | (1, 6) => EX_NOUSER,
+            (1, 2) => EX_NOHOST,
+            (7, _) => EX_NOPERM,
+            (3, 4) | (6, _) => EX_DATAERR,
+            (5, _) => EX_PROTOCOL,
+            _ => EX_UNAVAILABLE,
+        };
+    }
+    match code.as_u16() {
+        551 | 553 => EX_NOUSER,
+        552 => EX_DATAERR,
+        500..=504 => EX_PROTOCOL,
+        _ => EX_UNAVAILABLE,
+    }
+}
+
+/// exit codes as defined by `sysexits.h`, see `LogicError::exit_code`
+pub mod exit_codes {
+    /// the input data was incorrect in some way
+    pub const EX_DATAERR: i32 = 65;
+    /// the user specified did not exist
+    pub const EX_NOUSER: i32 = 67;
+    /// the host specified did not exist
+    pub const EX_NOHOST: i32 = 68;
+    /// a service is unavailable
+    pub const EX_UNAVAILABLE: i32 = 69;
+    /// an internal software error has been detected
+    pub const EX_SOFTWARE: i32 = 70;
+    /// temporary failure, indicating something that is not really an error
+    pub const EX_TEMPFAIL: i32 = 75;
+    /// the remote system returned something that was "not possible" during a protocol exchange
+    pub const EX_PROTOCOL: i32 = 76;
+    /// insufficient permission to perform the operation
+    pub const EX_NOPERM: i32 = 77;
 }
 
 impl From<MissingCapabilities> for LogicError {
@@ -151,6 +375,7 @@ impl Display for LogicError {
                 "server responded with unexpected non-error response code"
             ),
             MissingCapabilities(ref err) => write!(fter, "{}", err),
+            EhloRequired(ref err) => write!(fter, "EHLO required before using command: {}", err),
             Custom(ref boxed) => write!(fter, "{}", boxed),
         }
     }
@@ -219,3 +444,497 @@ impl Display for MissingCapabilities {
         Ok(())
     }
 }
+
+/// error representing that the tls session failed after it was established
+///
+/// E.g. because the server refused a renegotiation or send a invalid tls
+/// record. Read/write errors of a secure `Socket` which come from the tls
+/// layer are returned as an `io::Error` with this error as inner error,
+/// use `TlsSessionFailed::from_io_error` to check for it. The kind of the
+/// `io::Error` is the one of the tls layer (i.e. `ErrorKind::Other`).
+#[derive(Debug)]
+pub struct TlsSessionFailed {
+    cause: std_io::Error,
+}
+
+impl TlsSessionFailed {
+    pub(crate) fn wrap(cause: std_io::Error) -> std_io::Error {
+        std_io::Error::new(cause.kind(), TlsSessionFailed { cause })
+    }
+
+    /// returns the `TlsSessionFailed` error if it's the inner error of `err`
+    pub fn from_io_error(err: &std_io::Error) -> Option<&TlsSessionFailed> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<TlsSessionFailed>())
+    }
+
+    /// the error returned by the tls layer
+    pub fn tls_error(&self) -> &std_io::Error {
+        &self.cause
+    }
+}
+
+impl Error for TlsSessionFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl Display for TlsSessionFailed {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fter, "tls session failed: {}", self.cause)
+    }
+}
+
+/// error representing that the response code was not the expected one
+///
+/// Returned (as `LogicError::Custom`) by `Connection::exec_expect`.
+#[derive(Debug)]
+pub struct UnexpectedResponse {
+    expected: ExpectedCode,
+    response: Response,
+}
+
+impl UnexpectedResponse {
+    pub fn new(expected: ExpectedCode, response: Response) -> Self {
+        UnexpectedResponse { expected, response }
+    }
+
+    /// the code which was expected
+    pub fn expected(&self) -> ExpectedCode {
+        self.expected
+    }
+
+    /// the response actually received
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+
+    pub fn into_response(self) -> Response {
+        self.response
+    }
+}
+
+impl Error for UnexpectedResponse {}
+
+impl Display for UnexpectedResponse {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fter,
+            "expected response code {} but got {} {:?}",
+            self.expected,
+            self.response.code(),
+            self.response.msg().join("\n")
+        )
+    }
+}
+
+/// error returned if a mail is larger than the limit the server declared with `SIZE`
+///
+/// Returned (as `LogicError::Custom`) by `send_mail` and `SendOperation::exec`
+/// before sending anything if `EhloData::size_limit` is a `SizeLimit::Limit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    size: usize,
+    limit: usize,
+}
+
+impl MessageTooLarge {
+    pub fn new(size: usize, limit: usize) -> Self {
+        MessageTooLarge { size, limit }
+    }
+
+    /// the size of the mail (or the size hint given for it)
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// the limit declared by the server
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+impl Error for MessageTooLarge {}
+
+impl Display for MessageTooLarge {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fter,
+            "mail of {} bytes exceeds the servers size limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+/// error representing that the server requires authentication before the command
+///
+/// Returned (as `LogicError::Custom`) by the `MAIL` command (pipelined or
+/// not) instead of a `LogicError::Code` if the response indicates it (see
+/// `Response::requires_authentication`). This happens with servers which
+/// don't advertise `AUTH` before a `STARTTLS` or demand authentication only
+/// for some senders. The caller can authenticate and retry the transaction.
+#[derive(Debug, Clone)]
+pub struct AuthenticationRequired {
+    response: Response,
+}
+
+impl AuthenticationRequired {
+    pub fn new(response: Response) -> Self {
+        AuthenticationRequired { response }
+    }
+
+    /// the response of the server
+    pub fn response(&self) -> &Response {
+        &self.response
+    }
+}
+
+impl Error for AuthenticationRequired {}
+
+impl Display for AuthenticationRequired {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fter,
+            "server requires authentication: {} {}",
+            self.response.code().as_u16(),
+            self.response.msg().join(" ")
+        )
+    }
+}
+
+/// error returned if `DATA` is send while no `RCPT` was accepted
+///
+/// Returned (as `LogicError::Custom`) by the `DATA` command before writing
+/// anything if transaction-state tracking is enabled (see
+/// `Io::enable_transaction_tracking`), as the server would reject it anyway.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoAcceptedRecipients;
+
+impl Error for NoAcceptedRecipients {}
+
+impl Display for NoAcceptedRecipients {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fter, "DATA requires at last one accepted recipient")
+    }
+}
+
+/// error returned if the line of a `Raw` command contains a `'\r'` or `'\n'`
+///
+/// Returned (as `LogicError::Custom`) by the `Raw` command (and so by
+/// `Connection::exec_expect`) before writing anything, as a line break
+/// would allow the line to smuggle in additional commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineBreakInCommand;
+
+impl Error for LineBreakInCommand {}
+
+impl Display for LineBreakInCommand {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fter, "command line must not contain '\\r' or '\\n'")
+    }
+}
+
+/// Error returned by `ServerIdentity::check` if a connection is to a different server
+///
+/// E.g. a reconnect was routed to a different backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AffinityMismatch {
+    expected: Domain,
+    found: Option<Domain>,
+}
+
+impl AffinityMismatch {
+    pub fn new(expected: Domain, found: Option<Domain>) -> Self {
+        AffinityMismatch { expected, found }
+    }
+
+    /// the EHLO domain of the server the connection should be to
+    pub fn expected(&self) -> &Domain {
+        &self.expected
+    }
+
+    /// the EHLO domain of the server connected to, `None` if no EHLO was send
+    pub fn found(&self) -> Option<&Domain> {
+        self.found.as_ref()
+    }
+}
+
+impl Error for AffinityMismatch {}
+
+impl Display for AffinityMismatch {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.found.as_ref() {
+            Some(found) => write!(
+                fter,
+                "expected to connect to {} but server identified as {}",
+                self.expected.as_str(),
+                found.as_str()
+            ),
+            None => write!(
+                fter,
+                "expected to connect to {} but server did not identify itself",
+                self.expected.as_str()
+            ),
+        }
+    }
+}
+
+/// Error returned by `mx::mail_exchangers`
+#[derive(Debug)]
+pub enum MxLookupFailed {
+    /// looking up the `MX` records failed
+    Lookup(std_io::Error),
+    /// the domain has a null MX (RFC 7505), i.e. it does not accept mail
+    DoesNotAcceptMail(Domain),
+}
+
+impl Error for MxLookupFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            MxLookupFailed::Lookup(ref err) => Some(err),
+            MxLookupFailed::DoesNotAcceptMail(_) => None,
+        }
+    }
+}
+
+impl Display for MxLookupFailed {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            MxLookupFailed::Lookup(ref err) => write!(fter, "MX lookup failed: {}", err),
+            MxLookupFailed::DoesNotAcceptMail(ref domain) => {
+                write!(fter, "domain {} does not accept mail", domain.as_str())
+            }
+        }
+    }
+}
+
+/// error representing that the write quota of a connection was exceeded
+///
+/// Returned as inner error of an `io::Error` of kind `Other` (use
+/// `WriteQuotaExceeded::from_io_error` to get it), see `Io::set_write_quota`.
+/// As the `io::Error` fails the command the connection is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteQuotaExceeded {
+    quota: usize,
+    bytes_written: usize,
+}
+
+impl WriteQuotaExceeded {
+    pub(crate) fn new_io_error(quota: usize, bytes_written: usize) -> std_io::Error {
+        let err = WriteQuotaExceeded {
+            quota,
+            bytes_written,
+        };
+        std_io::Error::new(std_io::ErrorKind::Other, err)
+    }
+
+    /// returns the `WriteQuotaExceeded` error if it's the (transitive) inner error of `err`
+    ///
+    /// This also looks into a `BodyUploadFailed` error, i.e. works if the
+    /// quota was exceeded while writing a mail body.
+    pub fn from_io_error(err: &std_io::Error) -> Option<&WriteQuotaExceeded> {
+        let inner = err.get_ref()?;
+        if let Some(upload_failed) = inner.downcast_ref::<BodyUploadFailed>() {
+            return WriteQuotaExceeded::from_io_error(upload_failed.io_error());
+        }
+        inner.downcast_ref::<WriteQuotaExceeded>()
+    }
+
+    /// the quota of the connection
+    pub fn quota(&self) -> usize {
+        self.quota
+    }
+
+    /// the number of bytes written to the connection before the quota was exceeded
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+}
+
+impl Error for WriteQuotaExceeded {}
+
+impl Display for WriteQuotaExceeded {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fter,
+            "write quota of {} bytes exceeded ({} bytes already written)",
+            self.quota, self.bytes_written
+        )
+    }
+}
+
+/// error representing that a mail body isn't 7-bit clean
+///
+/// See `Data::check_7bit_clean`. If found before `DATA` was send it's
+/// returned as `LogicError::Custom`. Else wise it's returned as inner error
+/// of an `io::Error` of kind `InvalidData` (use `Not7BitClean::from_io_error`
+/// to get it), the chunk containing the offending byte is not written, but
+/// as `DATA` was already send the connection is closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Not7BitClean {
+    offset: usize,
+    byte: u8,
+}
+
+impl Not7BitClean {
+    pub(crate) fn new(offset: usize, byte: u8) -> Self {
+        Not7BitClean { offset, byte }
+    }
+
+    pub(crate) fn new_io_error(offset: usize, byte: u8) -> std_io::Error {
+        std_io::Error::new(
+            std_io::ErrorKind::InvalidData,
+            Not7BitClean::new(offset, byte),
+        )
+    }
+
+    /// returns the `Not7BitClean` error if it's the (transitive) inner error of `err`
+    ///
+    /// Like `WriteQuotaExceeded::from_io_error` this also looks into a
+    /// `BodyUploadFailed` error.
+    pub fn from_io_error(err: &std_io::Error) -> Option<&Not7BitClean> {
+        let inner = err.get_ref()?;
+        if let Some(upload_failed) = inner.downcast_ref::<BodyUploadFailed>() {
+            return Not7BitClean::from_io_error(upload_failed.io_error());
+        }
+        inner.downcast_ref::<Not7BitClean>()
+    }
+
+    /// the offset of the first offending byte in the body
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// the offending byte (a `NUL` or a byte > 127)
+    pub fn byte(&self) -> u8 {
+        self.byte
+    }
+}
+
+impl Error for Not7BitClean {}
+
+impl Display for Not7BitClean {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fter,
+            "body is not 7-bit clean, found byte 0x{:02x} at offset {}",
+            self.byte, self.offset
+        )
+    }
+}
+
+/// error representing that writing the mail body failed
+///
+/// Errors writing the body are returned as an `io::Error` with this error as
+/// inner error (use `BodyUploadFailed::from_io_error` to get it), the kind
+/// of the `io::Error` is the same as the one of the cause.
+#[derive(Debug)]
+pub struct BodyUploadFailed {
+    bytes_sent: usize,
+    cause: std_io::Error,
+}
+
+/// the reason why a body upload was interrupted
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UploadInterruption {
+    /// the server closed the connection (e.g. FIN), writing was no longer possible
+    Closed,
+    /// the connection was reset or aborted
+    Reset,
+    /// any other I/O-Error
+    Other,
+}
+
+impl BodyUploadFailed {
+    pub(crate) fn wrap(cause: std_io::Error, bytes_sent: usize) -> std_io::Error {
+        let kind = cause.kind();
+        std_io::Error::new(kind, BodyUploadFailed { bytes_sent, cause })
+    }
+
+    /// returns the `BodyUploadFailed` error if it's the inner error of `err`
+    pub fn from_io_error(err: &std_io::Error) -> Option<&BodyUploadFailed> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<BodyUploadFailed>())
+    }
+
+    /// number of body bytes known to be written to the socket before the failure
+    ///
+    /// This doesn't include the dots added by dot-stashing. Through written to
+    /// the socket the bytes might not have reached the server.
+    pub fn bytes_sent(&self) -> usize {
+        self.bytes_sent
+    }
+
+    /// classifies the cause of the failure
+    pub fn interruption(&self) -> UploadInterruption {
+        use std::io::ErrorKind::*;
+        match self.cause.kind() {
+            WriteZero | BrokenPipe | UnexpectedEof => UploadInterruption::Closed,
+            ConnectionReset | ConnectionAborted => UploadInterruption::Reset,
+            _ => UploadInterruption::Other,
+        }
+    }
+
+    /// the error which caused the upload to fail
+    pub fn io_error(&self) -> &std_io::Error {
+        &self.cause
+    }
+}
+
+impl Error for BodyUploadFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+impl Display for BodyUploadFailed {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fter,
+            "writing the body failed after {} bytes: {}",
+            self.bytes_sent, self.cause
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::exit_codes::*;
+    use super::*;
+    use crate::response::parser::parse_code;
+
+    fn code_error(code: [u8; 3], msg: &str) -> LogicError {
+        let code = parse_code(code[0], code[1], code[2]).unwrap();
+        LogicError::Code(Response::new(code, vec![msg.to_owned()]))
+    }
+
+    #[test]
+    fn transient_failures_are_tempfail() {
+        let err = code_error(*b"450", "4.2.1 mailbox busy, try again");
+        assert_eq!(err.exit_code(), EX_TEMPFAIL);
+    }
+
+    #[test]
+    fn unknown_users_are_nouser() {
+        let err = code_error(*b"550", "5.1.1 no such user");
+        assert_eq!(err.exit_code(), EX_NOUSER);
+        let err = code_error(*b"553", "mailbox name not allowed");
+        assert_eq!(err.exit_code(), EX_NOUSER);
+    }
+
+    #[test]
+    fn other_permanent_failures() {
+        assert_eq!(code_error(*b"550", "5.7.1 rejected").exit_code(), EX_NOPERM);
+        assert_eq!(code_error(*b"554", "no").exit_code(), EX_UNAVAILABLE);
+        assert_eq!(code_error(*b"500", "what?").exit_code(), EX_PROTOCOL);
+    }
+
+    #[test]
+    fn not_7bit_clean_is_found_in_body_upload_failed() {
+        let err = BodyUploadFailed::wrap(Not7BitClean::new_io_error(3, 0x80), 3);
+        let clean_err = Not7BitClean::from_io_error(&err).expect("7-bit error not found");
+        assert_eq!(clean_err.offset(), 3);
+        assert_eq!(clean_err.byte(), 0x80);
+    }
+}
@@ -0,0 +1,61 @@
+//! Provides lifecycle events of a `Connection` for observability
+//!
+//! Unlike the `ByteLogger` (which sees every byte) an `EventSubscriber` is
+//! only told about the important steps in the life of a connection, e.g.
+//! to drive metrics or dashboards.
+use std::fmt::Debug;
+use std::time::Duration;
+
+use crate::connection::CloseReason;
+
+/// A lifecycle event of a `Connection`
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    /// the connection was opened (emitted right before the greeting is read)
+    Connected,
+    /// the server greeted us with a positive response
+    GreetingReceived,
+    /// a `EHLO` command succeeded, i.e. new ehlo data is available
+    EhloCompleted,
+    /// the connection was upgraded to TLS using `STARTTLS`
+    TlsUpgraded,
+    /// a `AUTH` command succeeded
+    Authenticated,
+    /// a `MAIL` command succeeded, i.e. a mail transaction started
+    TransactionStarted,
+    /// the mail data (`DATA` or the last `BDAT` chunk) was accepted
+    TransactionCompleted,
+    /// a command send with `Connection::send` finished (only if timing is recorded)
+    ///
+    /// See `Io::set_record_timing`.
+    CommandTimed(CommandTiming),
+    /// the connection was closed
+    ///
+    /// `None` if the client closed it (e.g. using `Connection::quit`),
+    /// else wise the reason why the server closed it (as noticed through
+    /// `Connection::poll_unsolicited`).
+    Closed(Option<CloseReason>),
+}
+
+/// Timing of a single command, see `Io::set_record_timing`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandTiming {
+    /// the verb of the command (see `Cmd::verb`)
+    pub verb: &'static str,
+    /// the time from the end of the last write to the first byte of the response (server think time)
+    ///
+    /// For commands writing multiple times (e.g. `DATA`) this is measured for
+    /// the last write, it's `None` if nothing was read after it.
+    pub think_time: Option<Duration>,
+    /// the total duration of the command
+    pub total: Duration,
+}
+
+/// Subscriber notified about `ConnectionEvent`s
+///
+/// It's shared using an `Arc`, so the same subscriber can be used for
+/// multiple connections (and connections created from a cloned config).
+pub trait EventSubscriber: Debug + Send + Sync {
+    /// called for each event in the order they happen
+    fn on_event(&self, event: &ConnectionEvent);
+}
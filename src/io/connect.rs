@@ -2,20 +2,26 @@ use std::io as std_io;
 use std::net::SocketAddr;
 
 use futures::future::{self, Either, Future, Map};
-use native_tls::TlsConnector as NativeTlsConnector;
 use tokio::net::tcp::{ConnectFuture, TcpStream};
 use tokio_tls::TlsConnector;
 
 use super::Io;
 use crate::common::{map_tls_err, SetupTls, TlsConfig};
+use crate::error::ConnectError;
 
 impl Io {
     /// create a new Tcp only connection to the given address
+    ///
+    /// Unlike the other connect functions errors do not have a `ConnectError`
+    /// as inner error, `Connection::connect` adds it.
     pub fn connect_insecure(addr: &SocketAddr) -> Map<ConnectFuture, fn(TcpStream) -> Io> {
         TcpStream::connect(addr).map(Io::from as fn(TcpStream) -> Io)
     }
 
     /// create a new Tcp-Tls connection to the given address using the given tls config
+    ///
+    /// Errors of the TCP connect or the tls handshake have a `ConnectError` as
+    /// inner error. (Failing to create the tls connector from the config does not.)
     #[allow(clippy::redundant_closure_call)]
     pub fn connect_secure<S>(
         addr: &SocketAddr,
@@ -27,17 +33,18 @@ impl Io {
         let TlsConfig { domain, setup } = config;
         let connector = alttry!(
             {
-                let contor = setup.setup(NativeTlsConnector::builder())?;
+                let contor = setup.into_connector()?;
                 Ok(TlsConnector::from(contor))
             } =>
             |err| Either::B(future::err(map_tls_err(err)))
         );
 
         let fut = TcpStream::connect(&addr)
+            .map_err(ConnectError::wrap_tcp)
             .and_then(move |stream| {
                 connector
                     .connect(domain.as_str(), stream)
-                    .map_err(map_tls_err)
+                    .map_err(ConnectError::wrap_tls)
             })
             .map(Io::from);
 
@@ -5,6 +5,7 @@ use futures::stream::Stream;
 use futures::{Async, Future, Poll, try_ready};
 
 use super::{Io, OUTPUT_BUFFER_INC_SIZE};
+use crate::error::BodyUploadFailed;
 
 impl Io {
     /// write all data from source to the output socket using dot-stashing
@@ -13,6 +14,9 @@ impl Io {
     /// implementation makes sure not to add a additional "\r\n" to the end
     /// of the file if it isn't needed.
     ///
+    /// If writing to the socket fails the error has a `BodyUploadFailed` as
+    /// inner error (see `BodyUploadFailed::from_io_error`).
+    ///
     pub fn write_dot_stashed<S>(self, source: S) -> DotStashedWrite<S>
     where
         S: Stream<Error = std_io::Error>,
@@ -20,8 +24,30 @@ impl Io {
     {
         DotStashedWrite::new(self, source)
     }
+
+    /// like `write_dot_stashed` but calls `progress` each time a chunk was written
+    ///
+    /// `progress` is called with the number of bytes of `source` written so far
+    /// (excluding the added dots and end of mail sequence) and `total` (if known).
+    pub fn write_dot_stashed_with_progress<S>(
+        self,
+        source: S,
+        total: Option<usize>,
+        progress: ProgressFn,
+    ) -> DotStashedWrite<S>
+    where
+        S: Stream<Error = std_io::Error>,
+        S::Item: Buf,
+    {
+        let mut write = DotStashedWrite::new(self, source);
+        write.progress = Some((progress, total));
+        write
+    }
 }
 
+/// Callback receiving the number of bytes written so far and the total number of bytes (if known)
+pub type ProgressFn = Box<dyn FnMut(usize, Option<usize>) + Send>;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 enum CrLf {
     None,
@@ -39,6 +65,12 @@ where
     stash_state: CrLf,
     /// end of mail sequence i.e. "\r\n.\r\n"
     write_eom_seq: bool,
+    progress: Option<(ProgressFn, Option<usize>)>,
+    /// bytes taken from source so far
+    written: usize,
+    /// bytes taken from source which are known to be written to the socket
+    flushed: usize,
+    report_progress: bool,
 }
 
 impl<S> DotStashedWrite<S>
@@ -52,6 +84,10 @@ where
             io: Some(io),
             stash_state: CrLf::None,
             write_eom_seq: false,
+            progress: None,
+            written: 0,
+            flushed: 0,
+            report_progress: false,
         }
     }
 
@@ -78,6 +114,8 @@ where
 
     fn write_dot_stashed_output(&mut self, unstashed: S::Item) {
         let mut state = self.stash_state;
+        self.written += unstashed.remaining();
+        self.report_progress = true;
         {
             let raw_len = unstashed.remaining();
             let out = self.io_mut().out_buffer(raw_len);
@@ -125,7 +163,19 @@ where
             //TODO this can be improved to not flush each slice before dot-stashing the next slice
             // e.g. while buffer has space write dot stashed bytes from self.pending into
             // out buffer while poll_flush is NotReady
-            try_ready!(self.io_mut().poll_flush());
+            let flushed = self.flushed;
+            try_ready!(self
+                .io_mut()
+                .poll_flush()
+                .map_err(|err| BodyUploadFailed::wrap(err, flushed)));
+            self.flushed = self.written;
+
+            if self.report_progress {
+                self.report_progress = false;
+                if let Some((progress, total)) = self.progress.as_mut() {
+                    progress(self.written, *total);
+                }
+            }
 
             if self.write_eom_seq {
                 return Ok(Async::Ready(self.io.take().expect("poll after completion")));
@@ -4,6 +4,7 @@ use futures::{Async, Future, Poll, try_ready};
 use tokio::io::AsyncWrite;
 
 use super::Io;
+use crate::error::WriteQuotaExceeded;
 
 impl Io {
     /// return a futures resolving back to this instance once all output data is flushed
@@ -21,20 +22,36 @@ impl Io {
     ///
     /// This first poll the writing of data from output to socket until
     /// output is empty, then it will start polling flush on the socket.
+    ///
+    /// Fails with a `WriteQuotaExceeded` error (wrapped in an `io::Error`)
+    /// if the output would exceed the write quota (see `Io::set_write_quota`).
     pub fn poll_flush(&mut self) -> Poll<(), std_io::Error> {
-        let output = &mut self.buffer.output;
-        let socket = &mut self.socket;
-        while !output.is_empty() {
-            let n = try_ready!(socket.poll_write(output));
+        if let Some(quota) = self.write_quota {
+            if self.bytes_written + self.buffer.output.len() > quota {
+                return Err(WriteQuotaExceeded::new_io_error(quota, self.bytes_written));
+            }
+        }
+
+        while !self.buffer.output.is_empty() {
+            let n = try_ready!(self.socket.poll_write(&self.buffer.output));
 
-            // as long as output is not empty a it should never write 0 bytes
-            assert!(n > 0);
+            // as long as output is not empty writing 0 bytes means the connection was closed
+            if n == 0 {
+                return Err(std_io::ErrorKind::WriteZero.into());
+            }
+            self.bytes_written += n;
 
-            // remove the bytes written from the buffer
-            output.advance(n);
+            if self.byte_logger.is_some() {
+                let written = self.buffer.output.split_to(n);
+                self.log_output(&written);
+            } else {
+                // remove the bytes written from the buffer
+                self.buffer.output.advance(n);
+            }
         }
 
-        try_ready!(socket.poll_flush());
+        try_ready!(self.socket.poll_flush());
+        self.mark_flushed();
 
         Ok(Async::Ready(()))
     }
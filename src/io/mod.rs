@@ -1,14 +1,21 @@
 //! This modules contains all the `Io` type related parts (for implementing `Cmd`)
 //!
+use std::fmt::Debug;
+use std::io as std_io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use bytes::buf::BufMut;
 use bytes::BytesMut;
 use futures::Future;
+use tokio::clock;
 use tokio::net::TcpStream;
 use tokio_tls::TlsStream;
 
 use super::ExecFuture;
 use crate::common::EhloData;
 use crate::error::LogicError;
+use crate::event::{CommandTiming, ConnectionEvent, EventSubscriber};
 use crate::response::Response;
 
 mod socket;
@@ -42,6 +49,87 @@ pub struct Io {
     socket: Socket,
     buffer: Buffers,
     ehlo_data: Option<EhloData>,
+    byte_logger: Option<Box<dyn ByteLogger>>,
+    /// the verb of the currently running sensitive command (if any)
+    redact_output: Option<&'static str>,
+    /// number of error responses received in a row (excluding `421`)
+    consecutive_errors: usize,
+    event_subscriber: Option<Arc<dyn EventSubscriber>>,
+    buffer_config: BufferConfig,
+    /// the greeting read when the connection was set up
+    greeting: Option<Response>,
+    write_quota: Option<usize>,
+    bytes_written: usize,
+    /// `None` if transaction-state tracking is disabled
+    transaction: Option<TransactionState>,
+    record_timing: bool,
+    /// when the last flush completed (only if timing is recorded)
+    flushed_at: Option<Instant>,
+    /// when the first byte after the last flush was read (only if timing is recorded)
+    first_byte_at: Option<Instant>,
+    last_command_timing: Option<CommandTiming>,
+}
+
+/// The state of the current mail transaction as seen by the client
+///
+/// Only kept if enabled with `Io::enable_transaction_tracking`. It's updated
+/// by the build-in `MAIL`, `RCPT`, `RSET`, `DATA` and `BDAT` commands (incl.
+/// pipelined ones), commands send through `Io::exec_simple_cmd` are not seen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TransactionState {
+    /// true if a `MAIL` command was accepted and the transaction didn't end yet
+    pub started: bool,
+    /// the number of `RCPT` commands accepted in the current transaction
+    pub accepted_recipients: usize,
+}
+
+/// Configures the initial capacity and growth of the input buffer
+///
+/// The input buffer is reused for all responses of a connection, it's only
+/// grown (by `input_increment` bytes at a time) if a response doesn't fit
+/// into the space left over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// the capacity reserved for the input buffer before the first read
+    pub input_capacity: usize,
+    /// by how much the input buffer is grown if it's full (at last 1)
+    pub input_increment: usize,
+}
+
+impl Default for BufferConfig {
+    /// starts with an empty input buffer which grows in steps of 256 bytes
+    fn default() -> Self {
+        BufferConfig {
+            input_capacity: 0,
+            input_increment: INPUT_BUFFER_INC_SIZE,
+        }
+    }
+}
+
+/// The parts of an `Io` instance, returned by `Io::into_parts`
+#[derive(Debug)]
+pub struct IoParts {
+    pub socket: Socket,
+    pub buffers: Buffers,
+    pub ehlo_data: Option<EhloData>,
+    /// info about the negotiated tls session, `None` for plain text connections
+    pub tls_info: Option<TlsInfo>,
+}
+
+/// Hook to observe all bytes written to and read from the socket
+///
+/// The output of sensitive commands (see `Cmd::is_sensitive`) is not
+/// passed to `log_output`, instead `log_redacted_output` is called
+/// with the verb of the command and the number of bytes written.
+pub trait ByteLogger: Debug + Send {
+    /// called with the bytes written to the socket
+    fn log_output(&mut self, data: &[u8]);
+
+    /// called with the bytes read from the socket
+    fn log_input(&mut self, data: &[u8]);
+
+    /// called instead of `log_output` when writing the output of a sensitive command
+    fn log_redacted_output(&mut self, _verb: &'static str, _len: usize) {}
 }
 
 impl Io {
@@ -51,12 +139,25 @@ impl Io {
        \\---------------------------------------------------------------//
     */
 
+    /// split this instance into it's parts, including info about the tls session
+    pub fn into_parts(self) -> IoParts {
+        let tls_info = self.socket.tls_info();
+        let (socket, buffers, ehlo_data) = self.split();
+        IoParts {
+            socket,
+            buffers,
+            ehlo_data,
+            tls_info,
+        }
+    }
+
     /// split this instance into it's parts
     pub fn split(self) -> (Socket, Buffers, Option<EhloData>) {
         let Io {
             socket,
             buffer,
             ehlo_data,
+            ..
         } = self;
         (socket, buffer, ehlo_data)
     }
@@ -89,6 +190,57 @@ impl Io {
         self.socket.is_secure()
     }
 
+    /// replaces the socket with the one created by `upgrade`, keeping all other state
+    ///
+    /// This is meant for upgrading the socket (e.g. with `STARTTLS`), state
+    /// the upgrade invalidates has to be reset by the caller.
+    pub(crate) fn upgrade_socket<F, FUT>(
+        self,
+        upgrade: F,
+    ) -> impl Future<Item = Io, Error = std_io::Error> + Send
+    where
+        F: FnOnce(Socket) -> FUT,
+        FUT: Future<Item = Socket, Error = std_io::Error> + Send,
+    {
+        let Io {
+            socket,
+            buffer,
+            ehlo_data,
+            byte_logger,
+            redact_output,
+            consecutive_errors,
+            event_subscriber,
+            buffer_config,
+            greeting,
+            write_quota,
+            bytes_written,
+            transaction,
+            record_timing,
+            flushed_at,
+            first_byte_at,
+            last_command_timing,
+        } = self;
+
+        upgrade(socket).map(move |socket| Io {
+            socket,
+            buffer,
+            ehlo_data,
+            byte_logger,
+            redact_output,
+            consecutive_errors,
+            event_subscriber,
+            buffer_config,
+            greeting,
+            write_quota,
+            bytes_written,
+            transaction,
+            record_timing,
+            flushed_at,
+            first_byte_at,
+            last_command_timing,
+        })
+    }
+
     /// returns a `&mut` to a (the) output buffer having at last `need_rem` bytes free capacity
     pub fn out_buffer(&mut self, need_rem: usize) -> &mut BytesMut {
         let buf = &mut self.buffer.output;
@@ -111,6 +263,212 @@ impl Io {
         self.ehlo_data = Some(data);
     }
 
+    pub(crate) fn clear_ehlo_data(&mut self) {
+        self.ehlo_data = None;
+    }
+
+    /// sets the `ByteLogger` used to observe the data send/received
+    pub fn set_byte_logger(&mut self, logger: Box<dyn ByteLogger>) {
+        self.byte_logger = Some(logger);
+    }
+
+    /// removes the `ByteLogger` (if any) and returns it
+    pub fn take_byte_logger(&mut self) -> Option<Box<dyn ByteLogger>> {
+        self.byte_logger.take()
+    }
+
+    /// sets the `EventSubscriber` notified about lifecycle events
+    pub fn set_event_subscriber(&mut self, subscriber: Arc<dyn EventSubscriber>) {
+        self.event_subscriber = Some(subscriber);
+    }
+
+    /// removes the `EventSubscriber` (if any) and returns it
+    pub fn take_event_subscriber(&mut self) -> Option<Arc<dyn EventSubscriber>> {
+        self.event_subscriber.take()
+    }
+
+    /// passes the event to the `EventSubscriber` (if any)
+    ///
+    /// The build-in commands emit their events themselves, this is
+    /// only needed for custom `Cmd` implementations.
+    pub fn emit_event(&self, event: ConnectionEvent) {
+        if let Some(subscriber) = self.event_subscriber.as_ref() {
+            subscriber.on_event(&event);
+        }
+    }
+
+    /// the greeting of the server, if it was read through this instance
+    ///
+    /// It's set when setting up a connection (e.g. `Connection::connect`)
+    /// and kept when upgrading the connection with `STARTTLS`.
+    pub fn greeting(&self) -> Option<&Response> {
+        self.greeting.as_ref()
+    }
+
+    pub(crate) fn set_greeting(&mut self, greeting: Option<Response>) {
+        self.greeting = greeting;
+    }
+
+    /// limits the total number of bytes written to the socket (default: no limit)
+    ///
+    /// All output is counted, including mail bodies. Flushing output which
+    /// would exceed the quota fails with an `io::Error` wrapping a
+    /// `WriteQuotaExceeded` error without writing any of it, which fails the
+    /// running command and with it closes the connection.
+    pub fn set_write_quota(&mut self, quota: Option<usize>) {
+        self.write_quota = quota;
+    }
+
+    /// the write quota, see `set_write_quota`
+    pub fn write_quota(&self) -> Option<usize> {
+        self.write_quota
+    }
+
+    /// the total number of bytes written to the socket
+    pub fn bytes_written(&self) -> usize {
+        self.bytes_written
+    }
+
+    /// enables tracking the `TransactionState` (default: disabled)
+    ///
+    /// With it enabled `DATA` fails client-side with a `NoAcceptedRecipients`
+    /// error if no `RCPT` was accepted. Enabling it resets the state, so it
+    /// should be done outside of a mail transaction.
+    pub fn enable_transaction_tracking(&mut self) {
+        self.transaction = Some(TransactionState::default());
+    }
+
+    /// the `TransactionState`, `None` if tracking is disabled
+    pub fn transaction_state(&self) -> Option<TransactionState> {
+        self.transaction
+    }
+
+    /// updates the `TransactionState` using `update`, does nothing if tracking is disabled
+    pub(crate) fn update_transaction_state<F>(&mut self, update: F)
+    where
+        F: FnOnce(&mut TransactionState),
+    {
+        if let Some(state) = self.transaction.as_mut() {
+            update(state);
+        }
+    }
+
+    /// enables/disables recording the timing of commands (default: disabled)
+    ///
+    /// With it enabled `Connection::send` measures the think time of the
+    /// server and the total duration of each command, which are then
+    /// available through `last_command_timing` and emitted as
+    /// `ConnectionEvent::CommandTimed`.
+    pub fn set_record_timing(&mut self, enable: bool) {
+        self.record_timing = enable;
+        if !enable {
+            self.flushed_at = None;
+            self.first_byte_at = None;
+        }
+    }
+
+    /// true if the timing of commands is recorded, see `set_record_timing`
+    pub fn records_timing(&self) -> bool {
+        self.record_timing
+    }
+
+    /// the timing of the last command send with `Connection::send` (if recorded)
+    pub fn last_command_timing(&self) -> Option<CommandTiming> {
+        self.last_command_timing
+    }
+
+    pub(crate) fn set_last_command_timing(&mut self, timing: CommandTiming) {
+        self.last_command_timing = Some(timing);
+    }
+
+    pub(crate) fn mark_flushed(&mut self) {
+        if self.record_timing {
+            self.flushed_at = Some(clock::now());
+            self.first_byte_at = None;
+        }
+    }
+
+    pub(crate) fn mark_read(&mut self) {
+        if self.record_timing && self.flushed_at.is_some() && self.first_byte_at.is_none() {
+            self.first_byte_at = Some(clock::now());
+        }
+    }
+
+    /// the time from the last flush to the first byte read after it (if both happened)
+    pub(crate) fn think_time(&self) -> Option<Duration> {
+        match (self.flushed_at, self.first_byte_at) {
+            (Some(flushed), Some(first_byte)) => Some(first_byte.duration_since(flushed)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn clear_timing_marks(&mut self) {
+        self.flushed_at = None;
+        self.first_byte_at = None;
+    }
+
+    /// the `BufferConfig` used for the input buffer
+    pub fn buffer_config(&self) -> BufferConfig {
+        self.buffer_config
+    }
+
+    /// sets the `BufferConfig` and reserves the configured input capacity
+    ///
+    /// A `input_increment` of 0 is treated as 1.
+    pub fn set_buffer_config(&mut self, config: BufferConfig) {
+        let input = &mut self.buffer.input;
+        if input.capacity() < config.input_capacity {
+            let additional = config.input_capacity - input.len();
+            input.reserve(additional);
+        }
+        self.buffer_config = BufferConfig {
+            input_increment: config.input_increment.max(1),
+            ..config
+        };
+    }
+
+    /// makes the `ByteLogger` redact all output until it's set back to `None`
+    ///
+    /// `Connection::send` sets this to the commands verb for all sensitive commands.
+    pub fn set_redact_output(&mut self, verb: Option<&'static str>) {
+        self.redact_output = verb;
+    }
+
+    /// the number of syntax error responses (`x0z`, e.g. `500` to `504`) received in a row
+    ///
+    /// Only syntax errors indicate a broken session, other errors like a `550`
+    /// rejecting a single recipient or a `421` (service shutting down) are not
+    /// counted. Any non-error response resets the count to 0.
+    pub fn consecutive_errors(&self) -> usize {
+        self.consecutive_errors
+    }
+
+    pub(crate) fn track_response(&mut self, response: &Response) {
+        let code = response.code();
+        if !code.is_erroneous() {
+            self.consecutive_errors = 0;
+        } else if code.as_byte_string()[1] == b'0' {
+            // RFC 5321 4.2.1: a 0 as second digit marks a syntax error
+            self.consecutive_errors += 1;
+        }
+    }
+
+    pub(crate) fn log_output(&mut self, data: &[u8]) {
+        if let Some(logger) = self.byte_logger.as_mut() {
+            if let Some(verb) = self.redact_output {
+                logger.log_redacted_output(verb, data.len());
+            } else {
+                logger.log_output(data);
+            }
+        }
+    }
+
+    pub(crate) fn log_input(&mut self, data: &[u8]) {
+        if let Some(logger) = self.byte_logger.as_mut() {
+            logger.log_input(data);
+        }
+    }
+
     /// checks if a specific `EsmtpKeyword` had been in the last
     /// Ehlo response
     pub fn has_capability<C>(&self, cap: C) -> bool
@@ -132,43 +490,91 @@ impl Io {
     }
 }
 
+/// returns a function (for `Future::map`) which emits `event` if the result is `Ok`
+pub(crate) fn emit_on_success(
+    event: ConnectionEvent,
+) -> impl FnOnce((Io, SmtpResult)) -> (Io, SmtpResult) {
+    move |(io, result)| {
+        if result.is_ok() {
+            io.emit_event(event);
+        }
+        (io, result)
+    }
+}
+
+/// returns a function (for `Future::map`) which applies `update` to the `TransactionState` if the result is `Ok`
+pub(crate) fn update_transaction_on_success<F>(
+    update: F,
+) -> impl FnOnce((Io, SmtpResult)) -> (Io, SmtpResult)
+where
+    F: FnOnce(&mut TransactionState),
+{
+    move |(mut io, result)| {
+        if result.is_ok() {
+            io.update_transaction_state(update);
+        }
+        (io, result)
+    }
+}
+
+impl TransactionState {
+    /// the state after a accepted `MAIL` command
+    pub(crate) fn start(&mut self) {
+        *self = TransactionState {
+            started: true,
+            accepted_recipients: 0,
+        };
+    }
+
+    /// counts a accepted `RCPT` command
+    pub(crate) fn add_recipient(&mut self) {
+        self.accepted_recipients += 1;
+    }
+
+    /// the state after the transaction ended (e.g. through `RSET` or `DATA`)
+    pub(crate) fn reset(&mut self) {
+        *self = TransactionState::default();
+    }
+}
+
 impl From<(Socket, Buffers, Option<EhloData>)> for Io {
     fn from((socket, buffer, ehlo_data): (Socket, Buffers, Option<EhloData>)) -> Self {
         Io {
             socket,
             buffer,
             ehlo_data,
+            byte_logger: None,
+            redact_output: None,
+            consecutive_errors: 0,
+            event_subscriber: None,
+            buffer_config: BufferConfig::default(),
+            greeting: None,
+            write_quota: None,
+            bytes_written: 0,
+            transaction: None,
+            record_timing: false,
+            flushed_at: None,
+            first_byte_at: None,
+            last_command_timing: None,
         }
     }
 }
 
 impl From<(Socket, Buffers, EhloData)> for Io {
     fn from((socket, buffer, ehlo_data): (Socket, Buffers, EhloData)) -> Self {
-        Io {
-            socket,
-            buffer,
-            ehlo_data: Some(ehlo_data),
-        }
+        Io::from((socket, buffer, Some(ehlo_data)))
     }
 }
 
 impl From<(Socket, Buffers)> for Io {
     fn from((socket, buffer): (Socket, Buffers)) -> Self {
-        Io {
-            socket,
-            buffer,
-            ehlo_data: None,
-        }
+        Io::from((socket, buffer, None))
     }
 }
 
 impl From<Socket> for Io {
     fn from(socket: Socket) -> Self {
-        Io {
-            socket,
-            buffer: Buffers::new(),
-            ehlo_data: None,
-        }
+        Io::from((socket, Buffers::new(), None))
     }
 }
 
@@ -6,9 +6,9 @@ use futures::{Async, Future, Poll};
 use tokio::io::AsyncRead;
 
 use crate::error::check_response;
-use crate::response::parser;
+use crate::response::{parser, Response};
 
-use super::{Io, SmtpResult, INPUT_BUFFER_INC_SIZE};
+use super::{Io, SmtpResult};
 
 impl Io {
     /// parse a "normal" smtp response
@@ -25,26 +25,77 @@ impl Io {
 
     /// read data from the socket to buffer.input until it would block or the socket closed
     ///
-    /// The input buffer is increased in increments of 256 bytes (`INPUT_BUFFER_INC_SIZE`)
+    /// The input buffer is increased in increments of `BufferConfig::input_increment`
+    /// bytes (256 by default) if it is full.
     pub fn read_from_socket(&mut self) -> Result<ReadState, std_io::Error> {
-        let input = &mut self.buffer.input;
-        let socket = &mut self.socket;
-
         //TODO limit the buffer size (configurable) to limit smtp response line size
         loop {
+            let input = &mut self.buffer.input;
             if input.remaining_mut() == 0 {
-                input.reserve(INPUT_BUFFER_INC_SIZE);
+                input.reserve(self.buffer_config.input_increment);
             }
 
-            match socket.read_buf(input) {
+            let start = input.len();
+            match self.socket.read_buf(input) {
                 Ok(Async::NotReady) => return Ok(ReadState::NotReady),
                 Ok(Async::Ready(0)) => return Ok(ReadState::SocketClosed),
                 Ok(Async::Ready(_)) => (),
                 Err(err) => return Err(err),
             }
+            self.mark_read();
+
+            if self.byte_logger.is_some() {
+                let read = self.buffer.input[start..].to_vec();
+                self.log_input(&read);
+            }
+        }
+    }
+
+    /// polls for a response the server send without a command being send
+    ///
+    /// E.g. servers send a `421` before closing a connection which was idle for too
+    /// long. This reads from the socket without blocking and returns:
+    ///
+    /// - `Ready(Some(response))` if a complete response was received
+    /// - `Ready(None)` if the socket was closed
+    /// - `NotReady` if nothing (or just a part of a response) was received
+    ///
+    /// Like any `poll` method this has to be called in the context of a task.
+    pub fn poll_unsolicited(&mut self) -> Poll<Option<Response>, std_io::Error> {
+        let state = self.read_from_socket()?;
+
+        if has_complete_response(&self.buffer.input) {
+            let mut lines = Vec::new();
+            loop {
+                let line = self
+                    .try_pop_line(parser::parse_line)
+                    .map_err(|err| std_io::Error::new(std_io::ErrorKind::InvalidData, err))?
+                    .expect("[BUG] checked that a complete response is buffered");
+                let last = line.last_line;
+                lines.push(line);
+                if last {
+                    break;
+                }
+            }
+            let response = parser::response_from_parsed_lines(lines)
+                .map_err(|err| std_io::Error::new(std_io::ErrorKind::InvalidData, err))?;
+            self.track_response(&response);
+            return Ok(Async::Ready(Some(response)));
+        }
+
+        match state {
+            ReadState::NotReady => Ok(Async::NotReady),
+            ReadState::SocketClosed => Ok(Async::Ready(None)),
         }
     }
 
+    /// pops the next line (terminated by `"\r\n"`) from the input buffer and parses it
+    ///
+    /// Lines are strictly split at CRLF, the line passed to `parse_line_fn`
+    /// can contain anything else (including a bare CR or LF). So text looking
+    /// like a response line (e.g. `"250-"`) in the middle of a line does not
+    /// affect where the line ends or whether it's the last line.
+    ///
     /// # Implementation Limitations
     ///
     /// Be aware that try_read_line does only work on continuous buffers.
@@ -71,6 +122,18 @@ impl Io {
     }
 }
 
+/// true if the input contains a last response line (or a line failing to parse)
+fn has_complete_response(input: &[u8]) -> bool {
+    let mut rest = input;
+    while let Some(eol) = rest.windows(2).position(|pair| pair == b"\r\n") {
+        match parser::parse_line(&rest[..eol]) {
+            Ok(line) if !line.last_line => rest = &rest[eol + 2..],
+            _ => return true,
+        }
+    }
+    false
+}
+
 /// Used to hint if a socket was closed
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum ReadState {
@@ -131,7 +194,8 @@ impl Parsing {
                 let lines = mem::replace(&mut self.lines, Vec::new());
                 let response = parser::response_from_parsed_lines(lines.into_iter())?;
 
-                let io = self.inner.take().expect("[BUG] poll after completion");
+                let mut io = self.inner.take().expect("[BUG] poll after completion");
+                io.track_response(&response);
                 //FIXME[buf_management]: maybe normalize output bufer to have at most cap of 1024
                 return Ok(Some((io, check_response(response))));
             } else {
@@ -139,6 +203,11 @@ impl Parsing {
             }
         }
     }
+
+    fn poll_read_result(&mut self) -> Result<Option<(Io, SmtpResult)>, std_io::Error> {
+        self.read_result()
+            .map_err(|err| std_io::Error::new(std_io::ErrorKind::InvalidData, err))
+    }
 }
 
 impl Future for Parsing {
@@ -146,14 +215,17 @@ impl Future for Parsing {
     type Error = std_io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        //0. with pipelining the response might already be in the input buffer
+        if let Some(result) = self.poll_read_result()? {
+            return Ok(Async::Ready(result));
+        }
+
         //1. parse more data
         let state = self.io_mut().read_from_socket()?;
 
         //2. see if we have a full response now
-        match self.read_result() {
-            Ok(Some(result)) => return Ok(Async::Ready(result)),
-            Ok(None) => (),
-            Err(err) => return Err(std_io::Error::new(std_io::ErrorKind::InvalidData, err)),
+        if let Some(result) = self.poll_read_result()? {
+            return Ok(Async::Ready(result));
         }
 
         //3. if not see if the socked was closed
@@ -1,12 +1,90 @@
 use std::fmt::Debug;
 use std::io as std_io;
+use std::time::Duration;
 
 use bytes::buf::{Buf, BufMut};
 use futures::Poll;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio_tls::TlsStream;
 
+use crate::error::TlsSessionFailed;
+
+/// TCP keepalive settings
+///
+/// `idle` is the time a connection has to be idle before the first
+/// probe is send, `interval` the time between probes which weren't
+/// answered. If `interval` is `None` the system default is used.
+///
+/// Setting the interval is only supported on some platforms (e.g. Linux,
+/// BSDs, macOS and Windows), on others it's ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub idle: Duration,
+    pub interval: Option<Duration>,
+}
+
+impl Keepalive {
+    /// keepalive with given idle time and the system default interval
+    pub fn new(idle: Duration) -> Self {
+        Keepalive {
+            idle,
+            interval: None,
+        }
+    }
+
+    /// sets the interval between probes
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = Some(interval);
+        self
+    }
+
+    fn to_tcp_keepalive(self) -> TcpKeepalive {
+        let params = TcpKeepalive::new().with_time(self.idle);
+        match self.interval {
+            Some(interval) => with_interval(params, interval),
+            None => params,
+        }
+    }
+}
+
+impl From<Duration> for Keepalive {
+    fn from(idle: Duration) -> Self {
+        Keepalive::new(idle)
+    }
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_vendor = "apple",
+    windows,
+))]
+fn with_interval(params: TcpKeepalive, interval: Duration) -> TcpKeepalive {
+    params.with_interval(interval)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_vendor = "apple",
+    windows,
+)))]
+fn with_interval(params: TcpKeepalive, _interval: Duration) -> TcpKeepalive {
+    params
+}
+
 /// Abstraction over Tcp, TcpTls (and Mock)
 ///
 /// Allows treating both `TcpStream` and
@@ -36,6 +114,61 @@ impl Socket {
             Socket::Mock(ref mock) => mock.is_secure(),
         }
     }
+
+    /// returns the underlying `TcpStream` (`None` for a mock socket)
+    pub fn tcp_stream(&self) -> Option<&TcpStream> {
+        match *self {
+            Socket::Secure(ref stream) => Some(stream.get_ref().get_ref()),
+            Socket::Insecure(ref stream) => Some(stream),
+            #[cfg(feature = "mock-support")]
+            Socket::Mock(_) => None,
+        }
+    }
+
+    /// returns info about the negotiated tls session (`None` if not secure)
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match *self {
+            Socket::Secure(ref stream) => Some(TlsInfo::from_stream(stream)),
+            Socket::Insecure(_) => None,
+            #[cfg(feature = "mock-support")]
+            Socket::Mock(ref mock) => mock.tls_info(),
+        }
+    }
+
+    /// enables TCP keepalive (`SO_KEEPALIVE`) with the given settings
+    ///
+    /// If `keepalive` is `None` nothing is changed. For mock sockets
+    /// this does nothing.
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) -> Result<(), std_io::Error> {
+        match (keepalive, self.tcp_stream()) {
+            (Some(keepalive), Some(stream)) => {
+                SockRef::from(stream).set_tcp_keepalive(&keepalive.to_tcp_keepalive())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// maps errors of the tls layer to `TlsSessionFailed`
+    ///
+    /// tokio-tls/native-tls return errors from the underlying tcp stream
+    /// unchanged, the errors of the tls layer are returned with
+    /// `ErrorKind::Other` and the error of the tls implementation as inner
+    /// error. Errors without a inner error (e.g. os errors) are never
+    /// from the tls layer.
+    fn map_tls_session_err<T>(&self, res: Result<T, std_io::Error>) -> Result<T, std_io::Error> {
+        match res {
+            Err(err) => {
+                let from_tls_layer =
+                    err.kind() == std_io::ErrorKind::Other && err.get_ref().is_some();
+                if self.is_secure() && from_tls_layer {
+                    Err(TlsSessionFailed::wrap(err))
+                } else {
+                    Err(err)
+                }
+            }
+            ok => ok,
+        }
+    }
 }
 
 macro_rules! socket_mux {
@@ -51,23 +184,26 @@ macro_rules! socket_mux {
 
 impl std_io::Read for Socket {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, std_io::Error> {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             socket.read(buf)
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 }
 
 impl std_io::Write for Socket {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std_io::Error> {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             socket.write(buf)
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 
     fn flush(&mut self) -> Result<(), std_io::Error> {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             socket.flush()
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 }
 
@@ -84,9 +220,10 @@ impl AsyncRead for Socket {
 
     #[inline]
     fn poll_read(&mut self, buf: &mut [u8]) -> Poll<usize, std_io::Error> {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             socket.poll_read(buf)
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 
     #[inline]
@@ -94,23 +231,26 @@ impl AsyncRead for Socket {
     where
         Self: Sized,
     {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             socket.read_buf(buf)
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 }
 
 impl AsyncWrite for Socket {
     fn poll_write(&mut self, buf: &[u8]) -> Poll<usize, std_io::Error> {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             AsyncWrite::poll_write(socket, buf)
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 
     fn poll_flush(&mut self) -> Poll<(), std_io::Error> {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             AsyncWrite::poll_flush(socket)
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 
     fn shutdown(&mut self) -> Poll<(), std_io::Error> {
@@ -123,9 +263,10 @@ impl AsyncWrite for Socket {
     where
         Self: Sized,
     {
-        socket_mux! {self, |socket| {
+        let res = socket_mux! {self, |socket| {
             AsyncWrite::write_buf(socket, buf)
-        }}
+        }};
+        self.map_tls_session_err(res)
     }
 }
 
@@ -135,4 +276,51 @@ pub trait MockStream: Debug + AsyncRead + AsyncWrite + 'static {
         false
     }
     fn set_is_secure(&mut self, secure: bool);
+
+    /// the (stubbed) info about the tls session, see `Socket::tls_info`
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+}
+
+/// Information about the negotiated tls session, see `Socket::tls_info`
+///
+/// `native-tls` does not expose the protocol version or cipher suite, so
+/// only the data it does provide is included.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    peer_certificate: Option<Vec<u8>>,
+    tls_server_end_point: Option<Vec<u8>>,
+}
+
+impl TlsInfo {
+    /// create a new instance from the DER encoded peer certificate and the channel binding data
+    pub fn new(peer_certificate: Option<Vec<u8>>, tls_server_end_point: Option<Vec<u8>>) -> Self {
+        TlsInfo {
+            peer_certificate,
+            tls_server_end_point,
+        }
+    }
+
+    fn from_stream(stream: &TlsStream<TcpStream>) -> Self {
+        let stream = stream.get_ref();
+        // this is meant for logging, so if any info isn't available it's just missing
+        let peer_certificate = stream
+            .peer_certificate()
+            .ok()
+            .and_then(|cert| cert)
+            .and_then(|cert| cert.to_der().ok());
+        let tls_server_end_point = stream.tls_server_end_point().ok().and_then(|data| data);
+        TlsInfo::new(peer_certificate, tls_server_end_point)
+    }
+
+    /// the DER encoded leaf certificate of the server (if available)
+    pub fn peer_certificate(&self) -> Option<&[u8]> {
+        self.peer_certificate.as_deref()
+    }
+
+    /// the tls-server-end-point channel binding data (RFC 5929) if available
+    pub fn tls_server_end_point(&self) -> Option<&[u8]> {
+        self.tls_server_end_point.as_deref()
+    }
 }
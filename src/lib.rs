@@ -40,9 +40,13 @@ pub mod command;
 mod connect;
 mod connection;
 pub mod error;
+pub mod event;
 pub mod io;
+pub mod limit;
 #[cfg(feature = "mock-impl")]
 pub mod mock;
+pub mod mx;
+pub mod pipelining;
 pub mod response;
 #[cfg(feature = "send-mail")]
 pub mod send_mail;
@@ -0,0 +1,380 @@
+//! Provides a limit for the number of concurrent connections per destination
+//!
+//! Some destinations (e.g. large mail providers) only accept a limited number
+//! of concurrent connections from the same sender. A `DestinationLimiter`
+//! hands out a `DestinationPermit` for each connection, if the limit for a
+//! destination is reached further requests for a permit wait (in the order
+//! they were made) until a permit for that destination is released.
+//!
+//! `DestinationLimiter::connect` and `DestinationLimiter::connect_with_greeting`
+//! limit by the address connected to. With `Connection::connect_to_first`
+//! the address is only known once connected, so limit by e.g. the domain
+//! of the recipients instead and keep the permit with the connection:
+//!
+//! ```no_run
+//! use futures::Future;
+//! use new_tokio_smtp::limit::DestinationLimiter;
+//! use new_tokio_smtp::{Connection, ConnectionConfig, Domain, SetupOptions};
+//! # use std::net::SocketAddr;
+//! # fn mail_exchangers() -> Vec<SocketAddr> { vec![] }
+//!
+//! let limiter = DestinationLimiter::<Domain>::new(4);
+//! let config = ConnectionConfig::builder_local_unencrypted().build();
+//! let candidates = mail_exchangers();
+//! let fut = limiter
+//!     .acquire(Domain::from_unchecked("example.com"))
+//!     .map_err(|never| match never {})
+//!     .and_then(move |permit| {
+//!         Connection::connect_to_first(config, SetupOptions::default(), candidates)
+//!             .map(move |con| (con, permit))
+//!     });
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::sync::oneshot;
+use futures::{Async, Future, Poll};
+
+use crate::common::SetupTls;
+use crate::connect::{ConnectionConfig, SetupOptions};
+use crate::connection::{Cmd, Connection};
+use crate::error::ConnectingFailed;
+use crate::response::Response;
+
+/// Limits the number of concurrent connections per destination
+///
+/// The destination is by default the address connected to, but can be
+/// anything else, e.g. the domain of the recipients.
+///
+/// The limiter can be cloned, all clones share the same limits and permits.
+#[derive(Debug)]
+pub struct DestinationLimiter<D = SocketAddr>
+where
+    D: Hash + Eq + Clone,
+{
+    inner: Arc<Inner<D>>,
+}
+
+impl<D> Clone for DestinationLimiter<D>
+where
+    D: Hash + Eq + Clone,
+{
+    fn clone(&self) -> Self {
+        DestinationLimiter {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner<D>
+where
+    D: Hash + Eq + Clone,
+{
+    max_per_destination: usize,
+    slots: Mutex<HashMap<D, Slot<D>>>,
+}
+
+#[derive(Debug)]
+struct Slot<D>
+where
+    D: Hash + Eq + Clone,
+{
+    active: usize,
+    waiting: VecDeque<oneshot::Sender<DestinationPermit<D>>>,
+}
+
+impl<D> DestinationLimiter<D>
+where
+    D: Hash + Eq + Clone,
+{
+    /// creates a new limiter allowing `max_per_destination` concurrent permits per destination
+    ///
+    /// # Panics
+    ///
+    /// if `max_per_destination` is 0
+    pub fn new(max_per_destination: usize) -> Self {
+        assert!(
+            max_per_destination > 0,
+            "the limit of a DestinationLimiter has to be at least 1"
+        );
+        DestinationLimiter {
+            inner: Arc::new(Inner {
+                max_per_destination,
+                slots: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// the maximal number of concurrent permits per destination
+    pub fn max_per_destination(&self) -> usize {
+        self.inner.max_per_destination
+    }
+
+    /// requests a permit for given destination
+    ///
+    /// The returned future resolves once the permit is granted, which
+    /// is immediately if the limit for the destination is not yet reached.
+    /// Dropping the future before it resolved gives up the request.
+    pub fn acquire(&self, destination: D) -> Acquire<D> {
+        let mut slots = self.inner.lock_slots();
+        let slot = slots.entry(destination.clone()).or_insert_with(|| Slot {
+            active: 0,
+            waiting: VecDeque::new(),
+        });
+
+        let state = if slot.active < self.inner.max_per_destination {
+            slot.active += 1;
+            AcquireState::Ready(Some(DestinationPermit {
+                limiter: self.inner.clone(),
+                destination: Some(destination),
+            }))
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            slot.waiting.push_back(sender);
+            AcquireState::Waiting(receiver)
+        };
+
+        Acquire {
+            _limiter: self.inner.clone(),
+            state,
+        }
+    }
+
+    /// the number of currently granted permits for the destination
+    pub fn active(&self, destination: &D) -> usize {
+        self.inner
+            .lock_slots()
+            .get(destination)
+            .map(|slot| slot.active)
+            .unwrap_or(0)
+    }
+
+    /// the number of requests waiting for a permit for the destination
+    pub fn waiting(&self, destination: &D) -> usize {
+        self.inner
+            .lock_slots()
+            .get(destination)
+            .map(|slot| {
+                slot.waiting
+                    .iter()
+                    .filter(|sender| !sender.is_canceled())
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl DestinationLimiter<SocketAddr> {
+    /// like `Connection::connect_with_options` but only connects once a permit for `config.addr` is granted
+    ///
+    /// The permit is returned together with the connection, it should be
+    /// kept as long as the connection is used.
+    pub fn connect<S, A>(
+        &self,
+        config: ConnectionConfig<A, S>,
+        opts: SetupOptions,
+    ) -> impl Future<Item = (Connection, DestinationPermit), Error = ConnectingFailed> + Send
+    where
+        S: SetupTls,
+        A: Cmd + Send,
+    {
+        self.acquire(config.addr)
+            .map_err(|never| match never {})
+            .and_then(move |permit| {
+                Connection::connect_with_options(config, opts).map(move |con| (con, permit))
+            })
+    }
+
+    /// like `Connection::connect_with_greeting` but waits for a permit for `config.addr` first
+    ///
+    /// See `DestinationLimiter::connect`.
+    pub fn connect_with_greeting<S, A>(
+        &self,
+        config: ConnectionConfig<A, S>,
+        opts: SetupOptions,
+    ) -> impl Future<Item = (Connection, Response, DestinationPermit), Error = ConnectingFailed> + Send
+    where
+        S: SetupTls,
+        A: Cmd + Send,
+    {
+        self.acquire(config.addr)
+            .map_err(|never| match never {})
+            .and_then(move |permit| {
+                Connection::connect_with_greeting(config, opts)
+                    .map(move |(con, greeting)| (con, greeting, permit))
+            })
+    }
+}
+
+impl<D> Inner<D>
+where
+    D: Hash + Eq + Clone,
+{
+    fn lock_slots(&self) -> ::std::sync::MutexGuard<'_, HashMap<D, Slot<D>>> {
+        // the lock is never held while calling code which could panic
+        self.slots
+            .lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    fn release(self: &Arc<Self>, destination: D) {
+        let mut slots = self.lock_slots();
+        let remove = if let Some(slot) = slots.get_mut(&destination) {
+            // hand the permit over to the first still waiting request
+            while let Some(sender) = slot.waiting.pop_front() {
+                let permit = DestinationPermit {
+                    limiter: self.clone(),
+                    destination: Some(destination.clone()),
+                };
+                match sender.send(permit) {
+                    Ok(()) => return,
+                    Err(mut permit) => {
+                        // the request was given up, so this permit was never granted
+                        permit.destination = None;
+                    }
+                }
+            }
+            slot.active -= 1;
+            slot.active == 0
+        } else {
+            false
+        };
+        if remove {
+            slots.remove(&destination);
+        }
+    }
+}
+
+/// Future returned by `DestinationLimiter::acquire`
+#[derive(Debug)]
+pub struct Acquire<D = SocketAddr>
+where
+    D: Hash + Eq + Clone,
+{
+    // keeps the senders of waiting requests alive
+    _limiter: Arc<Inner<D>>,
+    state: AcquireState<D>,
+}
+
+#[derive(Debug)]
+enum AcquireState<D>
+where
+    D: Hash + Eq + Clone,
+{
+    Ready(Option<DestinationPermit<D>>),
+    Waiting(oneshot::Receiver<DestinationPermit<D>>),
+}
+
+impl<D> Future for Acquire<D>
+where
+    D: Hash + Eq + Clone,
+{
+    type Item = DestinationPermit<D>;
+    type Error = Infallible;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.state {
+            AcquireState::Ready(ref mut permit) => {
+                let permit = permit.take().expect("poll after future resolved");
+                Ok(Async::Ready(permit))
+            }
+            AcquireState::Waiting(ref mut receiver) => match receiver.poll() {
+                Ok(ready) => Ok(ready),
+                // senders are only dropped after sending or with the limiter
+                Err(_canceled) => unreachable!("limiter dropped while waiting for a permit"),
+            },
+        }
+    }
+}
+
+/// A granted permit of a `DestinationLimiter`, it's released when dropped
+#[derive(Debug)]
+pub struct DestinationPermit<D = SocketAddr>
+where
+    D: Hash + Eq + Clone,
+{
+    limiter: Arc<Inner<D>>,
+    /// `None` for permits which were never granted
+    destination: Option<D>,
+}
+
+impl<D> DestinationPermit<D>
+where
+    D: Hash + Eq + Clone,
+{
+    /// the destination this permit is for
+    pub fn destination(&self) -> &D {
+        self.destination
+            .as_ref()
+            .expect("only not granted permits have no destination")
+    }
+}
+
+impl<D> Drop for DestinationPermit<D>
+where
+    D: Hash + Eq + Clone,
+{
+    fn drop(&mut self) {
+        if let Some(destination) = self.destination.take() {
+            self.limiter.release(destination);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::future;
+
+    fn poll_now<F: Future>(fut: &mut F) -> Poll<F::Item, F::Error> {
+        future::lazy(|| Ok::<_, ()>(fut.poll())).wait().unwrap()
+    }
+
+    #[test]
+    fn permits_go_to_waiting_requests_in_order() {
+        let limiter = DestinationLimiter::new(1);
+        let first = limiter.acquire("a").wait().unwrap();
+        let mut second = limiter.acquire("a");
+        let mut third = limiter.acquire("a");
+        assert!(poll_now(&mut second).unwrap().is_not_ready());
+        assert_eq!(limiter.waiting(&"a"), 2);
+
+        drop(first);
+        let second = match poll_now(&mut second).unwrap() {
+            Async::Ready(permit) => permit,
+            Async::NotReady => panic!("permit was not handed over"),
+        };
+        assert!(poll_now(&mut third).unwrap().is_not_ready());
+        assert_eq!(limiter.active(&"a"), 1);
+        drop(second);
+        drop(third.wait().unwrap());
+        assert_eq!(limiter.active(&"a"), 0);
+    }
+
+    #[test]
+    fn given_up_requests_do_not_keep_a_permit() {
+        let limiter = DestinationLimiter::new(1);
+        let first = limiter.acquire("a").wait().unwrap();
+        let second = limiter.acquire("a");
+        drop(second);
+        assert_eq!(limiter.waiting(&"a"), 0);
+        drop(first);
+        assert_eq!(limiter.active(&"a"), 0);
+        let _third = limiter.acquire("a").wait().unwrap();
+        assert_eq!(limiter.active(&"a"), 1);
+    }
+
+    #[test]
+    fn destinations_are_limited_independently() {
+        let limiter = DestinationLimiter::new(1);
+        let _a = limiter.acquire("a").wait().unwrap();
+        let _b = limiter.acquire("b").wait().unwrap();
+        assert_eq!(limiter.active(&"a"), 1);
+        assert_eq!(limiter.active(&"b"), 1);
+    }
+}
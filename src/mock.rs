@@ -2,8 +2,9 @@
 use std::cmp::min;
 use std::io::{self as std_io, Read, Write};
 use std::mem;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rand::{random, thread_rng, Rng};
 
@@ -11,9 +12,11 @@ use bytes::{BufMut, BytesMut};
 use futures::sync::mpsc;
 use futures::task::{self, Task};
 use futures::{future, Async, Future, Poll, Stream, try_ready};
+use tokio::clock;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_timer::clock::{Clock, DefaultGuard, Now};
 
-use crate::io::MockStream;
+use crate::io::{MockStream, TlsInfo};
 
 /// Represents if the action is taken by `Client` or `Server`
 #[derive(Debug)]
@@ -144,12 +147,210 @@ impl State {
     }
 }
 
+/// Fluent builder for the conversation of a `MockSocket`
+///
+/// # Example
+///
+/// ```
+/// use futures::Future;
+/// use new_tokio_smtp::mock::MockConversation;
+/// use new_tokio_smtp::{command, ClientId, Connection, Io};
+///
+/// let socket = MockConversation::new()
+///     .client_sends("EHLO me.test")
+///     .server_multiline(&["250-they.test", "250 SMTPUTF8"])
+///     .client_sends("QUIT")
+///     .server_says("221 Bye")
+///     .expect_shutdown()
+///     .build_socket();
+///
+/// let client_id = ClientId::Domain("me.test".parse().unwrap());
+/// let (con, result) = Connection::from(Io::from(socket))
+///     .send(command::Ehlo::from(client_id))
+///     .wait()
+///     .unwrap();
+/// assert!(result.is_ok());
+/// assert!(con.has_capability("SMTPUTF8"));
+/// con.quit().wait().unwrap();
+/// ```
+#[derive(Debug, Default)]
+pub struct MockConversation {
+    actions: Vec<(Actor, ActionData)>,
+    check_shutdown: bool,
+}
+
+impl MockConversation {
+    /// creates an empty conversation (which doesn't expect a shutdown)
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// the server sends the given line (without trailing `"\r\n"`)
+    pub fn server_says(self, line: &'static str) -> Self {
+        self.server_multiline(&[line])
+    }
+
+    /// the server sends all given lines at once (without trailing `"\r\n"`)
+    pub fn server_multiline(mut self, lines: &[&'static str]) -> Self {
+        self.actions
+            .push((Actor::Server, ActionData::Lines(lines.to_vec())));
+        self
+    }
+
+    /// the server sends the given bytes as they are
+    pub fn server_sends_blob(mut self, blob: impl Into<Vec<u8>>) -> Self {
+        self.actions
+            .push((Actor::Server, ActionData::Blob(blob.into())));
+        self
+    }
+
+    /// the client is expected to send the given line (without trailing `"\r\n"`)
+    pub fn client_sends(mut self, line: &'static str) -> Self {
+        self.actions
+            .push((Actor::Client, ActionData::Lines(vec![line])));
+        self
+    }
+
+    /// the client is expected to send exactly the given bytes
+    pub fn client_sends_blob(mut self, blob: impl Into<Vec<u8>>) -> Self {
+        self.actions
+            .push((Actor::Client, ActionData::Blob(blob.into())));
+        self
+    }
+
+    /// makes the socket panic on drop if it wasn't shutdown
+    ///
+    /// Only affects `build_socket`.
+    pub fn expect_shutdown(mut self) -> Self {
+        self.check_shutdown = true;
+        self
+    }
+
+    /// returns the conversation, e.g. for `MockSocket::new`
+    pub fn build(self) -> Vec<(Actor, ActionData)> {
+        self.actions
+    }
+
+    /// creates a `MockSocket` going through this conversation
+    pub fn build_socket(self) -> MockSocket {
+        MockSocket::new_with_params(self.actions, self.check_shutdown)
+    }
+}
+
+impl From<MockConversation> for Vec<(Actor, ActionData)> {
+    fn from(conversation: MockConversation) -> Self {
+        conversation.build()
+    }
+}
+
+/// A manually advanced clock for `tokio::clock::now`, e.g. for `MockSocket::with_mock_clock`
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use new_tokio_smtp::mock::MockClock;
+///
+/// let clock = MockClock::new();
+/// let _guard = clock.set_default();
+/// let start = tokio::clock::now();
+/// clock.advance(Duration::from_secs(3));
+/// assert_eq!(tokio::clock::now() - start, Duration::from_secs(3));
+/// ```
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    /// creates a new clock starting at the current time
+    pub fn new() -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// moves the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// moves the clock forward to `instant`, if it's in the future
+    pub fn advance_to(&self, instant: Instant) {
+        let mut now = self.now.lock().unwrap();
+        if *now < instant {
+            *now = instant;
+        }
+    }
+
+    /// returns a tokio `Clock` using this clock, e.g. for a runtime `Builder`
+    pub fn to_clock(&self) -> Clock {
+        Clock::new_with_now(self.clone())
+    }
+
+    /// uses this clock for `tokio::clock::now` on the current thread until the guard is dropped
+    pub fn set_default(&self) -> DefaultGuard {
+        tokio_timer::clock::set_default(&self.to_clock())
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Now for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
 #[derive(Debug)]
 pub struct MockSocket {
     conversation: Vec<(Actor, ActionData)>,
     fake_secure: bool,
+    tls_info: Option<TlsInfo>,
     state: State,
     check_shutdown: bool,
+    sent: Option<SentRecorder>,
+    response_delay: Option<Duration>,
+    /// the current `Server` action can't be read before this
+    delayed_until: Option<Instant>,
+    mock_clock: Option<MockClock>,
+}
+
+/// Handle to the data the client wrote to a `MockSocket`, see `MockSocket::record_sent`
+#[derive(Debug, Clone, Default)]
+pub struct SentRecorder {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl SentRecorder {
+    fn record(&self, data: &[u8]) {
+        //UNWRAP_SAFE: only poisoned if a test already panicked
+        self.data.lock().unwrap().extend_from_slice(data);
+    }
+
+    /// all bytes the client sent so far
+    pub fn sent_bytes(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// all complete lines the client sent so far (without the trailing `"\r\n"`)
+    ///
+    /// This includes the lines of a mail body (e.g. send after `DATA`),
+    /// non utf-8 data is replaced lossy.
+    pub fn sent_lines(&self) -> Vec<String> {
+        let data = self.data.lock().unwrap();
+        let mut lines = Vec::new();
+        let mut rest = &data[..];
+        while let Some(eol) = rest.windows(2).position(|pair| pair == b"\r\n") {
+            lines.push(String::from_utf8_lossy(&rest[..eol]).into_owned());
+            rest = &rest[eol + 2..];
+        }
+        lines
+    }
 }
 
 /// MockSocket going through a pre-coded interlocked client-server conversation
@@ -189,6 +390,11 @@ impl MockSocket {
             conversation,
             check_shutdown,
             fake_secure: false,
+            tls_info: None,
+            sent: None,
+            response_delay: None,
+            delayed_until: None,
+            mock_clock: None,
             state: State::NeedNewAction {
                 buffer: BytesMut::new(),
                 waker: delayed_waker(),
@@ -196,6 +402,42 @@ impl MockSocket {
         }
     }
 
+    /// makes the socket pretend to be secure, using `info` as tls session info
+    pub fn with_tls_info(mut self, info: TlsInfo) -> Self {
+        self.fake_secure = true;
+        self.tls_info = Some(info);
+        self
+    }
+
+    /// delays each `Server` action by (at least) `delay`
+    ///
+    /// The delay starts when the client starts reading after its last
+    /// action, i.e. it emulates the time the server needs to respond.
+    /// Time is measured with `tokio::clock::now`, without `with_mock_clock`
+    /// this means waiting for the delay in real time.
+    pub fn with_response_delay(mut self, delay: Duration) -> Self {
+        self.response_delay = Some(delay);
+        self
+    }
+
+    /// makes the socket advance `clock` instead of waiting for a response delay
+    ///
+    /// `clock` should be the default clock of the thread polling the socket
+    /// (see `MockClock::set_default`), then a `Server` action is read exactly
+    /// `delay` (see `with_response_delay`) after it was prepared.
+    pub fn with_mock_clock(mut self, clock: MockClock) -> Self {
+        self.mock_clock = Some(clock);
+        self
+    }
+
+    /// starts recording all data the client writes, returning a handle to it
+    ///
+    /// The handle stays valid after the socket was moved into an `Io`
+    /// (or dropped), so the data can be checked at the end of a test.
+    pub fn record_sent(&mut self) -> SentRecorder {
+        self.sent.get_or_insert_with(SentRecorder::default).clone()
+    }
+
     /// sets the state to `ShutdownOrPoison` and clears the conversation
     pub fn clear(&mut self) {
         self.conversation.clear();
@@ -246,6 +488,7 @@ impl MockSocket {
 
         match actor {
             Actor::Server => {
+                self.delayed_until = self.response_delay.map(|delay| clock::now() + delay);
                 // 1. data into() buffer
                 assert!(
                     buffer.is_empty(),
@@ -327,6 +570,14 @@ impl MockStream for MockSocket {
     fn set_is_secure(&mut self, secure: bool) {
         self.fake_secure = secure;
     }
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        if self.fake_secure {
+            self.tls_info.clone()
+        } else {
+            None
+        }
+    }
 }
 
 macro_rules! try_ready_or_would_block {
@@ -435,6 +686,17 @@ impl AsyncRead for MockSocket {
                 waker,
                 mut to_be_read,
             } => {
+                if let Some(until) = self.delayed_until {
+                    if let Some(mock_clock) = self.mock_clock.as_ref() {
+                        mock_clock.advance_to(until);
+                    }
+                    if clock::now() < until {
+                        self.state = State::ServerIsWorking { waker, to_be_read };
+                        self.schedule_delayed_wake();
+                        return Ok(Async::NotReady);
+                    }
+                    self.delayed_until = None;
+                }
                 let rem = to_be_read.len();
                 let can_write = buf.len();
                 let should_write = random_amount(min(rem, can_write));
@@ -491,6 +753,9 @@ impl AsyncWrite for MockSocket {
                 }
                 let actual_write = buf.split_at(amount).0;
                 input.put(actual_write);
+                if let Some(sent) = self.sent.as_ref() {
+                    sent.record(actual_write);
+                }
 
                 self.state = State::ClientIsWorking {
                     expected,
@@ -0,0 +1,139 @@
+//! Provides `mail_exchangers` for finding the servers accepting mail for a domain
+//!
+//! This crate does not contain a DNS resolver, instead the `MX` records are
+//! looked up through a `MxLookup` implementation (e.g. wrapping the resolver
+//! the application already uses). This module only implements the rules for
+//! interpreting the records (RFC 5321 Section 5.1 and RFC 7505).
+use std::io as std_io;
+
+use futures::Future;
+
+use crate::data_types::Domain;
+use crate::error::MxLookupFailed;
+
+/// A `MX` record
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MxRecord {
+    /// the preference, lower values are preferred
+    pub preference: u16,
+    /// the host of the mail exchanger, `"."` for a null MX
+    pub exchange: Domain,
+}
+
+impl MxRecord {
+    pub fn new(preference: u16, exchange: Domain) -> Self {
+        MxRecord {
+            preference,
+            exchange,
+        }
+    }
+
+    /// true if this is a null MX (RFC 7505), i.e. the domain doesn't accept mail
+    pub fn is_null_mx(&self) -> bool {
+        let exchange = self.exchange.as_str();
+        exchange.is_empty() || exchange == "."
+    }
+}
+
+/// Looks up the `MX` records of a domain
+pub trait MxLookup {
+    /// returns all `MX` records of `domain` (empty if there are none)
+    fn lookup_mx(
+        &self,
+        domain: &Domain,
+    ) -> Box<dyn Future<Item = Vec<MxRecord>, Error = std_io::Error> + Send>;
+}
+
+/// returns the hosts of the mail exchangers of `domain`, most preferred first
+///
+/// - If there is a null MX (`MX 0 .`) `MxLookupFailed::DoesNotAcceptMail`
+///   is returned, there is _no_ fallback to the address of the domain.
+/// - If there are no `MX` records the domain itself is returned (the
+///   "implicit MX", i.e. the domains `A`/`AAAA` records are used).
+/// - Records with the same preference keep the order they were returned in.
+pub fn mail_exchangers<L>(
+    domain: Domain,
+    lookup: &L,
+) -> impl Future<Item = Vec<Domain>, Error = MxLookupFailed> + Send
+where
+    L: MxLookup,
+{
+    lookup
+        .lookup_mx(&domain)
+        .map_err(MxLookupFailed::Lookup)
+        .and_then(move |records| exchangers_from_records(domain, records))
+}
+
+fn exchangers_from_records(
+    domain: Domain,
+    mut records: Vec<MxRecord>,
+) -> Result<Vec<Domain>, MxLookupFailed> {
+    if records.iter().any(MxRecord::is_null_mx) {
+        return Err(MxLookupFailed::DoesNotAcceptMail(domain));
+    }
+    if records.is_empty() {
+        return Ok(vec![domain]);
+    }
+    records.sort_by_key(|record| record.preference);
+    Ok(records.into_iter().map(|record| record.exchange).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use futures::future;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct StubLookup(Vec<MxRecord>);
+
+    impl MxLookup for StubLookup {
+        fn lookup_mx(
+            &self,
+            _domain: &Domain,
+        ) -> Box<dyn Future<Item = Vec<MxRecord>, Error = std_io::Error> + Send> {
+            Box::new(future::ok(self.0.clone()))
+        }
+    }
+
+    fn record(preference: u16, exchange: &str) -> MxRecord {
+        MxRecord::new(preference, Domain::from_unchecked(exchange))
+    }
+
+    fn domain() -> Domain {
+        Domain::from_unchecked("example.test")
+    }
+
+    #[test]
+    fn null_mx_means_no_mail_is_accepted() {
+        let lookup = StubLookup(vec![record(0, ".")]);
+        match mail_exchangers(domain(), &lookup).wait() {
+            Err(MxLookupFailed::DoesNotAcceptMail(domain)) => {
+                assert_eq!(domain.as_str(), "example.test")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_domain_without_mx_records() {
+        let lookup = StubLookup(Vec::new());
+        let hosts = mail_exchangers(domain(), &lookup).wait().unwrap();
+        assert_eq!(hosts, vec![domain()]);
+    }
+
+    #[test]
+    fn sorts_by_preference() {
+        let lookup = StubLookup(vec![
+            record(20, "mx2.example.test"),
+            record(10, "mx1.example.test"),
+            record(20, "mx3.example.test"),
+        ]);
+        let hosts = mail_exchangers(domain(), &lookup).wait().unwrap();
+        let hosts = hosts.iter().map(Domain::as_str).collect::<Vec<_>>();
+        assert_eq!(
+            hosts,
+            ["mx1.example.test", "mx2.example.test", "mx3.example.test"]
+        );
+    }
+}
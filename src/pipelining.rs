@@ -0,0 +1,434 @@
+//! Provides the `pipeline` function for sending commands pipelined (RFC 2920)
+//!
+//! With pipelining a batch of commands is written to the server at once and
+//! only then all responses are read (in the same order). This saves round
+//! trips, but means that a failure of one command is only noticed after all
+//! commands are already send.
+use std::error::Error;
+use std::fmt;
+use std::io as std_io;
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes};
+use futures::future::{self, Either, Future, Loop};
+use futures::stream::Stream;
+
+use crate::chain::HandleErrorInChain;
+use crate::chain::OnError;
+use crate::command::{self, Bdat, Mail, Recipient, Reset};
+use crate::error::{LogicError, MissingCapabilities};
+use crate::event::ConnectionEvent;
+use crate::io::{SmtpResult, TransactionState};
+use crate::{Connection, EhloData, ExecFuture, Io, Response};
+
+/// the ehlo keyword of the PIPELINING extension
+pub const CAP_PIPELINING: &str = "PIPELINING";
+
+/// Result of a pipelined batch
+///
+/// If the batch could not be send at all (e.g. because the server does not
+/// support pipelining) `Err` with the index of the offending command is
+/// returned, else wise there is a `SmtpResult` for each command.
+pub type PipelineResult = Result<Vec<SmtpResult>, (usize, LogicError)>;
+
+/// Trait implemented by commands which can be part of a pipelined batch
+///
+/// Unlike `Cmd` this is object safe, the commands are just written to the
+/// output buffer, the engine takes care of flushing it and reading the
+/// responses.
+pub trait PipelinableCmd: Send + 'static {
+    /// see `Cmd::check_cmd_availability`
+    fn check_cmd_availability(&self, _caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        Ok(())
+    }
+
+    /// see `Cmd::verb`
+    fn verb(&self) -> &'static str;
+
+    /// see `Cmd::is_sensitive`
+    fn is_sensitive(&self) -> bool {
+        false
+    }
+
+    /// writes the command line to the output buffer of `io` without flushing it
+    fn write_cmd(&self, io: &mut Io);
+
+    /// maps the result of the command before it's used for events and returned (default: unchanged)
+    ///
+    /// Used to return the same errors as `Cmd::exec`, e.g. `Mail` returns a
+    /// `AuthenticationRequired` error in both cases. Like `success_event` it's
+    /// not used for commands finished through `exec_continuation`.
+    fn map_result(&self, result: SmtpResult) -> SmtpResult {
+        result
+    }
+
+    /// the event emitted if the server accepted the command (default: none)
+    ///
+    /// Not used for commands finished through `exec_continuation`.
+    fn success_event(&self) -> Option<ConnectionEvent> {
+        None
+    }
+
+    /// updates the `TransactionState` (if tracked) with the result of the command (default: no change)
+    ///
+    /// Like `success_event` it's not used for commands finished through
+    /// `exec_continuation`.
+    fn update_transaction(&self, _state: &mut TransactionState, _result: &SmtpResult) {}
+
+    /// true if the command has to be the last one of a batch (default: false)
+    ///
+    /// E.g. after `DATA` the client has to wait for the `354` response
+    /// before sending the body, so no command can be pipelined after it.
+    fn must_be_last(&self) -> bool {
+        false
+    }
+
+    /// called if the server answered with an intermediate (3xx) response
+    ///
+    /// E.g. for `DATA` this sends the mail body after the `354` response.
+    /// By default the response is treated as unexpected.
+    ///
+    /// As this continues the command on the connection this is only
+    /// valid for the last command of a batch.
+    fn exec_continuation(self: Box<Self>, io: Io, response: Response) -> ExecFuture {
+        Box::new(future::ok((io, Err(LogicError::UnexpectedCode(response)))))
+    }
+}
+
+impl PipelinableCmd for Mail {
+    fn verb(&self) -> &'static str {
+        "MAIL"
+    }
+
+    fn write_cmd(&self, io: &mut Io) {
+        self.write_line(io)
+    }
+
+    fn map_result(&self, result: SmtpResult) -> SmtpResult {
+        Mail::map_result(result)
+    }
+
+    fn success_event(&self) -> Option<ConnectionEvent> {
+        Some(ConnectionEvent::TransactionStarted)
+    }
+
+    fn update_transaction(&self, state: &mut TransactionState, result: &SmtpResult) {
+        if result.is_ok() {
+            state.start();
+        }
+    }
+}
+
+impl PipelinableCmd for Recipient {
+    fn verb(&self) -> &'static str {
+        "RCPT"
+    }
+
+    fn write_cmd(&self, io: &mut Io) {
+        self.write_line(io)
+    }
+
+    fn update_transaction(&self, state: &mut TransactionState, result: &SmtpResult) {
+        if result.is_ok() {
+            state.add_recipient();
+        }
+    }
+}
+
+impl PipelinableCmd for Reset {
+    fn verb(&self) -> &'static str {
+        "RSET"
+    }
+
+    fn write_cmd(&self, io: &mut Io) {
+        io.write_line_from_parts(&["RSET"])
+    }
+
+    fn update_transaction(&self, state: &mut TransactionState, result: &SmtpResult) {
+        if result.is_ok() {
+            state.reset();
+        }
+    }
+}
+
+impl PipelinableCmd for command::Noop {
+    fn verb(&self) -> &'static str {
+        "NOOP"
+    }
+
+    fn write_cmd(&self, io: &mut Io) {
+        io.write_line_from_parts(&["NOOP"])
+    }
+}
+
+impl PipelinableCmd for Bdat {
+    fn check_cmd_availability(&self, caps: Option<&EhloData>) -> Result<(), MissingCapabilities> {
+        crate::Cmd::check_cmd_availability(self, caps)
+    }
+
+    fn verb(&self) -> &'static str {
+        "BDAT"
+    }
+
+    fn is_sensitive(&self) -> bool {
+        true
+    }
+
+    fn write_cmd(&self, io: &mut Io) {
+        Bdat::write_cmd(self, io)
+    }
+
+    fn success_event(&self) -> Option<ConnectionEvent> {
+        if self.is_last() {
+            Some(ConnectionEvent::TransactionCompleted)
+        } else {
+            None
+        }
+    }
+
+    fn update_transaction(&self, state: &mut TransactionState, _result: &SmtpResult) {
+        if self.is_last() {
+            state.reset();
+        }
+    }
+}
+
+impl<S: 'static> PipelinableCmd for command::Data<S>
+where
+    S: Stream<Error = std_io::Error> + Send,
+    S::Item: Buf,
+{
+    fn verb(&self) -> &'static str {
+        "DATA"
+    }
+
+    fn is_sensitive(&self) -> bool {
+        true
+    }
+
+    fn write_cmd(&self, io: &mut Io) {
+        io.write_line_from_parts(&["DATA"])
+    }
+
+    fn must_be_last(&self) -> bool {
+        true
+    }
+
+    fn exec_continuation(self: Box<Self>, io: Io, response: Response) -> ExecFuture {
+        if response.code() != crate::response::codes::START_MAIL_DATA {
+            return Box::new(future::ok((io, Err(LogicError::UnexpectedCode(response)))));
+        }
+        Box::new(self.exec_body(io))
+    }
+}
+
+/// sends all commands pipelined and then reads the response for each of them
+///
+/// All responses are read even if one of the first commands fails (a failure
+/// can't stop the server from processing the commands written after it), so
+/// that the connection stays in sync. Only after all responses are read
+/// `on_error` is called with the index and error of the first failed command.
+/// E.g. passing `chain::OnError::StopAndReset` will send a `RSET` in that case.
+///
+/// If the server answers the last command with an intermediate response (e.g.
+/// `354` to `DATA`) `PipelinableCmd::exec_continuation` is used to finish it,
+/// even if another command failed. (E.g. if only one of multiple `RCPT`
+/// commands fails, the mail is delivered to the remaining recipients.)
+///
+/// If any command is sensitive (see `Cmd::is_sensitive`) the output of the
+/// whole batch is redacted for the `ByteLogger`, as it's written at once.
+///
+/// The batch ends with the last command being written, so for `DATA` only
+/// the command line is pipelined, the body is send after all responses were
+/// read and the response to `DATA` was `354`.
+///
+/// Fails with a `MissingCapabilities` error without sending anything if the
+/// server doesn't advertise `PIPELINING` or a command is not available (or
+/// with a `EhloRequired` error if no EHLO was performed), or
+/// with a `MustBeLast` error if a command like `DATA` isn't the last one
+/// (see `PipelinableCmd::must_be_last`).
+pub fn pipeline<H>(
+    con: Connection,
+    cmds: Vec<Box<dyn PipelinableCmd>>,
+    on_error: H,
+) -> impl Future<Item = (Connection, PipelineResult), Error = std_io::Error> + Send
+where
+    H: HandleErrorInChain,
+{
+    if let Err(err) = check_availability(con.ehlo_data(), &cmds) {
+        return Either::A(future::ok((con, Err(err))));
+    }
+
+    let mut io: Io = con.into();
+    for cmd in cmds.iter() {
+        cmd.write_cmd(&mut io);
+    }
+    if let Some(cmd) = cmds.iter().find(|cmd| cmd.is_sensitive()) {
+        io.set_redact_output(Some(cmd.verb()));
+    }
+
+    let nr_cmds = cmds.len();
+    let mut cmds = cmds;
+    //stackify
+    cmds.reverse();
+
+    let on_error = Arc::new(on_error);
+    let fut = io
+        .flush()
+        .and_then(move |mut io| {
+            io.set_redact_output(None);
+            future::loop_fn(
+                (io, cmds, Vec::with_capacity(nr_cmds)),
+                |(io, mut cmds, mut results)| {
+                    let cmd = match cmds.pop() {
+                        Some(cmd) => cmd,
+                        None => return Either::A(future::ok(Loop::Break((io, results)))),
+                    };
+                    let is_last = cmds.is_empty();
+                    let fut = io
+                        .parse_response()
+                        .and_then(move |(mut io, result)| match result {
+                            Ok(response) if is_last && response.code().is_intermediate() => {
+                                if cmd.is_sensitive() {
+                                    io.set_redact_output(Some(cmd.verb()));
+                                }
+                                let fut = cmd.exec_continuation(io, response).map(
+                                    move |(mut io, result)| {
+                                        io.set_redact_output(None);
+                                        results.push(result);
+                                        Loop::Continue((io, cmds, results))
+                                    },
+                                );
+                                Either::A(fut)
+                            }
+                            result => {
+                                let result = cmd.map_result(result);
+                                if let (Ok(_), Some(event)) = (&result, cmd.success_event()) {
+                                    io.emit_event(event);
+                                }
+                                io.update_transaction_state(|state| {
+                                    cmd.update_transaction(state, &result)
+                                });
+                                results.push(result);
+                                Either::B(future::ok(Loop::Continue((io, cmds, results))))
+                            }
+                        });
+                    Either::B(fut)
+                },
+            )
+        })
+        .and_then(move |(io, results)| {
+            let con = Connection::from(io);
+            let first_err = results
+                .iter()
+                .enumerate()
+                .find_map(|(idx, result)| result.as_ref().err().map(|err| (idx, err)));
+            if let Some((idx, err)) = first_err {
+                let fut = on_error
+                    .handle_error(con, idx, err)
+                    .map(move |(con, _stop)| (con, Ok(results)));
+                Either::A(fut)
+            } else {
+                Either::B(future::ok((con, Ok(results))))
+            }
+        });
+
+    Either::B(fut)
+}
+
+/// sends the chunks of a mail as pipelined `BDAT` commands
+///
+/// The last chunk is send with `BDAT <size> LAST`. Each chunk gets its own
+/// response, but only the response to the last chunk is the result of the
+/// mail transaction, so on success it is returned. If any chunk is rejected
+/// (the server then also rejects all following chunks) the index and error
+/// of the first rejected chunk is returned and `RSET` is send.
+///
+/// Requires `PIPELINING` and `CHUNKING`, `MAIL` and `RCPT` have to be send
+/// before.
+pub fn pipeline_chunks(
+    con: Connection,
+    chunks: Vec<Bytes>,
+) -> impl Future<Item = (Connection, Result<Response, (usize, LogicError)>), Error = std_io::Error> + Send
+{
+    let nr_chunks = chunks.len();
+    let cmds = chunks
+        .into_iter()
+        .enumerate()
+        .map(|(idx, chunk)| {
+            Box::new(Bdat::new(chunk, idx + 1 == nr_chunks)) as Box<dyn PipelinableCmd>
+        })
+        .collect();
+
+    pipeline(con, cmds, OnError::StopAndReset).map(|(con, result)| {
+        let result = result.and_then(|results| {
+            let mut last = None;
+            for (idx, result) in results.into_iter().enumerate() {
+                last = Some(result.map_err(|err| (idx, err))?);
+            }
+            last.ok_or_else(|| {
+                let err = LogicError::Custom(Box::new(NoChunks));
+                (0, err)
+            })
+        });
+        (con, result)
+    })
+}
+
+/// error returned by `pipeline_chunks` if called without any chunk
+#[derive(Debug, Clone, Copy)]
+pub struct NoChunks;
+
+impl fmt::Display for NoChunks {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fter, "no BDAT chunks given")
+    }
+}
+
+impl Error for NoChunks {}
+
+/// error returned by `pipeline` if a command which has to end a batch isn't the last one
+#[derive(Debug, Clone, Copy)]
+pub struct MustBeLast {
+    /// the verb of the command
+    pub verb: &'static str,
+}
+
+impl fmt::Display for MustBeLast {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            fter,
+            "{} has to be the last command of a pipelined batch",
+            self.verb
+        )
+    }
+}
+
+impl Error for MustBeLast {}
+
+fn check_availability(
+    caps: Option<&EhloData>,
+    cmds: &[Box<dyn PipelinableCmd>],
+) -> Result<(), (usize, LogicError)> {
+    let has_pipelining = caps
+        .map(|caps| caps.has_capability(CAP_PIPELINING))
+        .unwrap_or(false);
+    if !has_pipelining {
+        let err = MissingCapabilities::new_from_unchecked(CAP_PIPELINING);
+        let err = if caps.is_none() {
+            LogicError::EhloRequired(err)
+        } else {
+            LogicError::MissingCapabilities(err)
+        };
+        return Err((0, err));
+    }
+    for (idx, cmd) in cmds.iter().enumerate() {
+        cmd.check_cmd_availability(caps)
+            .map_err(|err| (idx, err.into()))?;
+        if cmd.must_be_last() && idx + 1 != cmds.len() {
+            let err = MustBeLast { verb: cmd.verb() };
+            return Err((idx, LogicError::Custom(Box::new(err))));
+        }
+    }
+    Ok(())
+}
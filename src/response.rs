@@ -1,4 +1,6 @@
 //! Provides access to `Response`, `ResponseCode` and parsing parts (form impl `Cmd`'s)
+use std::fmt::{self, Display};
+
 /// response of a smtp server
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct Response {
@@ -32,9 +34,141 @@ impl Response {
     ///
     /// this will have at last one line, throuhg
     /// this line might be empty
+    ///
+    /// Lines are only decoded (as UTF-8) once they are complete, so text
+    /// received split over multiple reads (even in the middle of a multi-byte
+    /// character) is decoded correctly.
     pub fn msg(&self) -> &[String] {
         &self.lines
     }
+
+    /// returns the enhanced status code (RFC 3463) the message starts with, if there is one
+    pub fn enhanced_status_code(&self) -> Option<EnhancedStatusCode> {
+        EnhancedStatusCode::from_msg(&self.lines[0])
+    }
+
+    /// true if the server rejected a command because the client has to authenticate first
+    ///
+    /// This is the case for a `530` (RFC 4954), `503` or `5.7.0` response
+    /// mentioning authentication, e.g. `"530 5.7.0 Authentication required"`
+    /// or `"503 5.5.1 Authentication required"` (which some servers send
+    /// instead). Responses mentioning `STARTTLS` are excluded, as RFC 3207
+    /// uses `530 5.7.0` for "Must issue a STARTTLS command first", too.
+    pub fn requires_authentication(&self) -> bool {
+        let is_auth_status = self
+            .enhanced_status_code()
+            .map(|status| (status.class, status.subject, status.detail) == (5, 7, 0))
+            .unwrap_or(false);
+        let has_auth_code = self.code == codes::AUTHENTICATION_REQUIRED
+            || self.code == codes::BAD_COMMAND_SEQUENCE
+            || is_auth_status;
+        if !has_auth_code {
+            return false;
+        }
+        let text = self.lines.join(" ").to_ascii_lowercase();
+        text.contains("authenticat") && !text.contains("starttls")
+    }
+
+    /// classifies the rejection of a `RCPT` command
+    ///
+    /// Like `EnhancedStatusCode::rejection_kind` but a security/policy code
+    /// (`X.7.X`, normally `5.7.1`) mentioning relaying in the message is
+    /// classified as `RejectionKind::RelayDenied`. Servers use it if the
+    /// client isn't allowed to send mail to the (foreign) recipient, which
+    /// is a problem of the setup (e.g. a missing authentication) and not of
+    /// the recipient.
+    pub fn rcpt_rejection_kind(&self) -> Option<RejectionKind> {
+        let kind = self.enhanced_status_code()?.rejection_kind()?;
+        let mentions_relaying = self
+            .lines
+            .iter()
+            .any(|line| line.to_ascii_lowercase().contains("relay"));
+        if kind == RejectionKind::Policy && mentions_relaying {
+            Some(RejectionKind::RelayDenied)
+        } else {
+            Some(kind)
+        }
+    }
+}
+
+/// A enhanced status code as defined by RFC 3463, e.g. `5.7.1`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct EnhancedStatusCode {
+    /// `2` (success), `4` (persistent transient failure) or `5` (permanent failure)
+    pub class: u8,
+    /// the subject, e.g. `7` for "Security or Policy Status"
+    pub subject: u16,
+    /// the detail, meaning depends on the subject
+    pub detail: u16,
+}
+
+impl EnhancedStatusCode {
+    /// parses a enhanced status code from the begin of a response message
+    ///
+    /// Returns `None` if the message doesn't start with a enhanced
+    /// status code followed by a space or the end of the message.
+    pub fn from_msg(msg: &str) -> Option<Self> {
+        let code = msg.split(' ').next()?;
+        let mut parts = code.split('.');
+        let class = parse_code_part(parts.next()?, 1)?;
+        let subject = parse_code_part(parts.next()?, 3)?;
+        let detail = parse_code_part(parts.next()?, 3)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        match class {
+            2 | 4 | 5 => Some(EnhancedStatusCode {
+                class: class as u8,
+                subject,
+                detail,
+            }),
+            _ => None,
+        }
+    }
+
+    /// classifies the reason of a rejection based on the subject of the code
+    ///
+    /// Returns `None` for the success class (`2.X.X`).
+    pub fn rejection_kind(self) -> Option<RejectionKind> {
+        if self.class == 2 {
+            return None;
+        }
+        let kind = match self.subject {
+            1 | 2 => RejectionKind::Mailbox,
+            4 => RejectionKind::Network,
+            6 => RejectionKind::Content,
+            7 => RejectionKind::Policy,
+            _ => RejectionKind::System,
+        };
+        Some(kind)
+    }
+}
+
+fn parse_code_part(part: &str, max_len: usize) -> Option<u16> {
+    if part.is_empty() || part.len() > max_len || !part.bytes().all(|bch| bch.is_ascii_digit()) {
+        return None;
+    }
+    part.parse().ok()
+}
+
+/// The kind of reason a server rejected something, based on a `EnhancedStatusCode`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum RejectionKind {
+    /// security or policy reasons, e.g. SPF/DKIM/DMARC failures or bad reputation (`X.7.X`)
+    Policy,
+    /// problems with the addresses or mailboxes (`X.1.X`, `X.2.X`)
+    Mailbox,
+    /// problems with the message content or media (`X.6.X`)
+    Content,
+    /// problems of the mail system or protocol, including unknown subjects (`X.0.X`, `X.3.X`, `X.5.X`)
+    System,
+    /// network or routing problems (`X.4.X`)
+    Network,
+    /// the client is not allowed to relay to the recipient, see `Response::rcpt_rejection_kind`
+    ///
+    /// This normally means the client needs to authenticate (or isn't
+    /// authorized to send mail through this server).
+    RelayDenied,
 }
 
 /// the response code of used by smtp server
@@ -71,6 +205,71 @@ impl ResponseCode {
     pub fn as_byte_string(self) -> [u8; 3] {
         self.0
     }
+
+    /// the class of the code (its first digit), e.g. `2` for `250`
+    pub fn class(self) -> u8 {
+        self.0[0] - b'0'
+    }
+
+    /// the code as number, e.g. `250`
+    pub fn as_u16(self) -> u16 {
+        self.0
+            .iter()
+            .fold(0, |code, digit| code * 10 + u16::from(digit - b'0'))
+    }
+}
+
+impl From<ResponseCode> for u16 {
+    fn from(code: ResponseCode) -> u16 {
+        code.as_u16()
+    }
+}
+
+impl PartialEq<u16> for ResponseCode {
+    fn eq(&self, other: &u16) -> bool {
+        self.as_u16() == *other
+    }
+}
+
+/// a response code (or class of codes) expected by `Connection::exec_expect`
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum ExpectedCode {
+    /// expects exactly the given code
+    Exact(ResponseCode),
+    /// expects any code starting with the given digit, e.g. `Class(2)` for any `2xx` code
+    ///
+    /// Only `1` to `5` are meaningful, any other class matches no code.
+    Class(u8),
+}
+
+impl ExpectedCode {
+    /// true if the code is the expected one
+    pub fn matches(self, code: ResponseCode) -> bool {
+        match self {
+            ExpectedCode::Exact(expected) => expected == code,
+            ExpectedCode::Class(class) => b'0'.checked_add(class) == Some(code.0[0]),
+        }
+    }
+}
+
+impl Display for ExpectedCode {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ExpectedCode::Exact(code) => write!(fter, "{}", code),
+            ExpectedCode::Class(class) => write!(fter, "{}xx", class),
+        }
+    }
+}
+
+impl Display for ResponseCode {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [kind, category, detail] = self.0;
+        write!(
+            fter,
+            "{}{}{}",
+            kind as char, category as char, detail as char
+        )
+    }
 }
 
 pub mod parser {
@@ -114,7 +313,18 @@ pub mod parser {
         pub msg: String,
     }
 
+    /// parses a response line (without the trailing `"\r\n"`)
+    ///
+    /// The text is allowed to be empty, i.e. `"250-"`, `"250 "` and
+    /// `"250"` (only valid as last line) are all accepted.
     pub fn parse_line(line: &[u8]) -> Result<ResponseLine, ParseError> {
+        if line.len() == 3 {
+            return Ok(ResponseLine {
+                code: parse_code(line[0], line[1], line[2])?,
+                last_line: true,
+                msg: String::new(),
+            });
+        }
         if line.len() < 4 {
             return Err(ParseError::LineLength);
         }
@@ -235,6 +445,9 @@ pub mod codes {
     /// RFC 5321: <domain> Service closing transmission channel
     pub static CLOSING_CHANNEL: ResponseCode = ResponseCode(*b"221");
 
+    /// RFC 4954: Authentication Succeeded
+    pub static AUTH_SUCCEEDED: ResponseCode = ResponseCode(*b"235");
+
     /// RFC 5321: Requested mail action okay, completed
     pub static OK: ResponseCode = ResponseCode(*b"250");
 
@@ -285,6 +498,12 @@ pub mod codes {
     /// RFC 7504: Server does not accept mail
     pub static SERVER_DOES_NOT_ACCEPT_MAIL: ResponseCode = ResponseCode(*b"521");
 
+    /// RFC 4954: Authentication required
+    pub static AUTHENTICATION_REQUIRED: ResponseCode = ResponseCode(*b"530");
+
+    /// RFC 4954: Authentication credentials invalid
+    pub static AUTH_CREDENTIALS_INVALID: ResponseCode = ResponseCode(*b"535");
+
     /// RFC 5321: Requested action not taken: mailbox unavailable (e.g., mailbox
     /// not found, no access, or command rejected for policy reasons)
     pub static MAILBOX_UNAVAILABLE: ResponseCode = ResponseCode(*b"550");
@@ -311,3 +530,158 @@ pub mod codes {
     ///  with it at all
     pub static TARGET_DOES_NOT_ACCEPT_MAIL: ResponseCode = ResponseCode(*b"556");
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn response_code_helpers() {
+        assert_eq!(codes::OK.class(), 2);
+        assert_eq!(codes::OK.as_u16(), 250);
+        assert_eq!(codes::OK, 250);
+        assert!(codes::OK.is_positive());
+        assert!(!codes::OK.is_intermediate());
+
+        assert_eq!(u16::from(codes::START_MAIL_DATA), 354);
+        assert_eq!(codes::START_MAIL_DATA.class(), 3);
+        assert!(codes::START_MAIL_DATA.is_intermediate());
+
+        assert_eq!(codes::SERVICE_UNAVAILABLE.class(), 4);
+        assert!(codes::SERVICE_UNAVAILABLE.is_transient_failure());
+        assert!(codes::SYNTAX_ERROR.is_permanent_failure());
+        assert_eq!(codes::SYNTAX_ERROR.to_string(), "500");
+    }
+
+    #[test]
+    fn expected_code_matches() {
+        assert!(ExpectedCode::Exact(codes::OK).matches(codes::OK));
+        assert!(!ExpectedCode::Exact(codes::OK).matches(codes::OK_NOT_LOCAL));
+        assert!(ExpectedCode::Class(2).matches(codes::OK_NOT_LOCAL));
+        assert!(!ExpectedCode::Class(2).matches(codes::SYNTAX_ERROR));
+        assert_eq!(ExpectedCode::Class(5).to_string(), "5xx");
+        assert!(!ExpectedCode::Class(250).matches(codes::OK));
+        assert!(!ExpectedCode::Class(255).matches(codes::OK));
+    }
+
+    #[test]
+    fn parse_line_ignores_codes_in_the_text() {
+        let line = parser::parse_line(b"250-said \"250 done\" 250-").unwrap();
+        assert!(!line.last_line);
+        assert_eq!(line.msg, "said \"250 done\" 250-");
+
+        let line = parser::parse_line(b"250 quoted \"250-more\"\n250-").unwrap();
+        assert!(line.last_line);
+        assert_eq!(line.msg, "quoted \"250-more\"\n250-");
+    }
+
+    #[test]
+    fn parse_line_with_empty_text() {
+        let line = parser::parse_line(b"250-").unwrap();
+        assert!(!line.last_line);
+        assert_eq!(line.msg, "");
+
+        let line = parser::parse_line(b"250").unwrap();
+        assert_eq!(line.code, codes::OK);
+        assert!(line.last_line);
+        assert_eq!(line.msg, "");
+
+        assert!(parser::parse_line(b"25").is_err());
+    }
+
+    fn response(msg: &str) -> Response {
+        Response::new(codes::MAILBOX_UNAVAILABLE, vec![msg.to_owned()])
+    }
+
+    #[test]
+    fn detects_required_authentication() {
+        let with_code = |code, msg: &str| Response::new(code, vec![msg.to_owned()]);
+        assert!(with_code(
+            codes::AUTHENTICATION_REQUIRED,
+            "5.7.0 Authentication required"
+        )
+        .requires_authentication());
+        assert!(
+            with_code(codes::BAD_COMMAND_SEQUENCE, "5.5.1 Authentication required")
+                .requires_authentication()
+        );
+        assert!(response("5.7.0 Authentication required").requires_authentication());
+        assert!(
+            !with_code(codes::BAD_COMMAND_SEQUENCE, "5.5.1 Send EHLO first")
+                .requires_authentication()
+        );
+        assert!(!response("5.7.1 rejected by DMARC policy").requires_authentication());
+    }
+
+    #[test]
+    fn starttls_required_is_not_authentication_required() {
+        let response = Response::new(
+            codes::AUTHENTICATION_REQUIRED,
+            vec!["5.7.0 Must issue a STARTTLS command first".to_owned()],
+        );
+        assert!(!response.requires_authentication());
+
+        let response = Response::new(
+            codes::AUTHENTICATION_REQUIRED,
+            vec!["5.7.0 Authentication required, issue STARTTLS first".to_owned()],
+        );
+        assert!(!response.requires_authentication());
+    }
+
+    #[test]
+    fn not_authorized_is_not_authentication_required() {
+        assert!(!response("5.7.0 sender not authorized").requires_authentication());
+        let response = Response::new(
+            codes::AUTHENTICATION_REQUIRED,
+            vec!["5.7.0 not authorized".to_owned()],
+        );
+        assert!(!response.requires_authentication());
+    }
+
+    #[test]
+    fn policy_rejection() {
+        let code = response("5.7.1 rejected by DMARC policy")
+            .enhanced_status_code()
+            .unwrap();
+        assert_eq!(
+            code,
+            EnhancedStatusCode {
+                class: 5,
+                subject: 7,
+                detail: 1
+            }
+        );
+        assert_eq!(code.rejection_kind(), Some(RejectionKind::Policy));
+    }
+
+    #[test]
+    fn policy_rejection_at_rcpt_is_not_relay_denied() {
+        let kind = response("5.7.1 rejected by DMARC policy").rcpt_rejection_kind();
+        assert_eq!(kind, Some(RejectionKind::Policy));
+    }
+
+    #[test]
+    fn mailbox_rejection() {
+        let code = response("5.1.1 no such user")
+            .enhanced_status_code()
+            .unwrap();
+        assert_eq!(code.rejection_kind(), Some(RejectionKind::Mailbox));
+    }
+
+    #[test]
+    fn no_enhanced_status_code() {
+        assert_eq!(response("no such user").enhanced_status_code(), None);
+        assert_eq!(response("5.1 no such user").enhanced_status_code(), None);
+        assert_eq!(response("3.1.1 no such user").enhanced_status_code(), None);
+        assert_eq!(
+            response("5.1.1.1 no such user").enhanced_status_code(),
+            None
+        );
+    }
+
+    #[test]
+    fn success_is_no_rejection() {
+        let code = response("2.1.5 ok").enhanced_status_code().unwrap();
+        assert_eq!(code.rejection_kind(), None);
+    }
+}
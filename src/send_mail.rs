@@ -86,22 +86,29 @@
 //! # fn mock_run_with_tokio(f: impl Future<Item=(), Error=()>) { unimplemented!() }
 //! ```
 //!
+use std::collections::hash_map::{Entry, HashMap};
+use std::error::Error as ErrorTrait;
+use std::fmt::{self, Display};
 use std::io as std_io;
 use std::mem::replace;
 
 use bytes::Bytes;
-use futures::future::{self, Either, Future};
+use futures::future::{self, Either, Future, Loop};
 use futures::stream::Stream;
 use futures::{Async, IntoFuture, Poll, try_ready};
 use vec1::Vec1;
 
 use crate::chain::{chain, HandleErrorInChain, OnError};
-use crate::command::{self, params_with_smtputf8};
-use crate::common::SetupTls;
+use crate::command::dsn::{self, Notify, Ret};
+use crate::command::{self, params_with_size, params_with_smtputf8};
+use crate::common::{SetupTls, SizeLimit};
 use crate::connect::ConnectionConfig;
 use crate::data_types::{ForwardPath, ReversePath};
-use crate::error::{GeneralError, LogicError, MissingCapabilities};
-use crate::{Cmd, Connection};
+use crate::error::{
+    ConnectingFailed, GeneralError, LogicError, MessageTooLarge, MissingCapabilities,
+};
+use crate::response::Response;
+use crate::{BoxedCmd, Cmd, Connection};
 
 /// Specifies if the mail requires SMTPUTF8 (or Mime8bit)
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
@@ -180,6 +187,7 @@ impl EnvelopData {
 pub struct MailEnvelop {
     envelop_data: EnvelopData,
     mail: Mail,
+    collapsed_duplicates: Vec<MailAddress>,
 }
 
 impl MailEnvelop {
@@ -191,6 +199,7 @@ impl MailEnvelop {
                 to,
             },
             mail,
+            collapsed_duplicates: Vec::new(),
         }
     }
 
@@ -199,6 +208,7 @@ impl MailEnvelop {
         MailEnvelop {
             envelop_data: EnvelopData { from: None, to },
             mail,
+            collapsed_duplicates: Vec::new(),
         }
     }
 
@@ -214,6 +224,23 @@ impl MailEnvelop {
         &self.mail
     }
 
+    /// removes duplicate recipients (default: duplicates are kept)
+    ///
+    /// This changes the recipients of the envelop, so it applies to all
+    /// ways of sending it (e.g. `send_mail` or `fan_out_mail`). The removed
+    /// duplicates are available through `collapsed_duplicates`.
+    pub fn dedup_recipients(mut self, comparison: RecipientComparison) -> Self {
+        let (to, duplicates) = dedup_recipients(self.envelop_data.to, comparison);
+        self.envelop_data.to = to;
+        self.collapsed_duplicates.extend(duplicates);
+        self
+    }
+
+    /// the recipients removed as duplicates, see `MailEnvelop::dedup_recipients`
+    pub fn collapsed_duplicates(&self) -> &[MailAddress] {
+        &self.collapsed_duplicates
+    }
+
     /// true if any mail address is internationalized or the mail body needs it
     pub fn needs_smtputf8(&self) -> bool {
         self.envelop_data.needs_smtputf8() || self.mail.needs_smtputf8()
@@ -222,13 +249,19 @@ impl MailEnvelop {
 
 impl From<(Mail, EnvelopData)> for MailEnvelop {
     fn from((mail, envelop_data): (Mail, EnvelopData)) -> Self {
-        MailEnvelop { envelop_data, mail }
+        MailEnvelop {
+            envelop_data,
+            mail,
+            collapsed_duplicates: Vec::new(),
+        }
     }
 }
 
 impl From<MailEnvelop> for (Mail, EnvelopData) {
     fn from(me: MailEnvelop) -> Self {
-        let MailEnvelop { mail, envelop_data } = me;
+        let MailEnvelop {
+            mail, envelop_data, ..
+        } = me;
         (mail, envelop_data)
     }
 }
@@ -363,6 +396,10 @@ where
         )));
     }
 
+    if let Err(err) = check_size_limit(&con, mail.raw_data().len()) {
+        return Either::B(future::ok((con, Err((0, err)))));
+    }
+
     let reverse_path = from
         .map(ReversePath::from)
         .unwrap_or_else(|| ReversePath::from_unchecked(""));
@@ -386,6 +423,83 @@ where
     Either::A(chain(con, cmd_chain, on_error))
 }
 
+/// fails with `MessageTooLarge` if the server declared a size limit smaller than `size`
+///
+/// If the server declared no limit (no `SIZE`) or has no fixed limit
+/// (`SIZE 0`) nothing is checked.
+fn check_size_limit(con: &Connection, size: usize) -> Result<(), LogicError> {
+    match con.ehlo_data().and_then(|ehlo| ehlo.size_limit()) {
+        Some(SizeLimit::Limit(limit)) if size > limit => Err(LogicError::Custom(Box::new(
+            MessageTooLarge::new(size, limit),
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The result of sending a mail to one batch of recipients, see `fan_out_mail`
+#[derive(Debug)]
+pub struct FanOutBatch {
+    /// the recipients the mail was send to through the same connection
+    pub recipients: Vec1<MailAddress>,
+    /// the result of connecting, sending the mail and quitting
+    ///
+    /// Failing to quit after the mail was send is not treated as an error.
+    pub result: Result<(), GeneralError>,
+}
+
+/// Sends a mail to many recipients using up to `max_connections` connections in parallel
+///
+/// The recipients are split into (nearly) equally sized batches, one per
+/// connection. For each batch a connection is opened using `connect`, the
+/// mail is send to the recipients of the batch (as it's own mail transaction)
+/// and then the connection is closed. This can improve the throughput for
+/// servers limiting the recipients per transaction or the speed per connection.
+///
+/// The future resolves once all batches are done, it never fails, instead
+/// the result of each batch is returned (in the order of the recipients).
+/// A `max_connections` of 0 is treated as 1.
+pub fn fan_out_mail<F, CF>(
+    mut connect: F,
+    envelop: MailEnvelop,
+    max_connections: usize,
+    //FIXME[futures/v>=2.0] use Never instead of ()
+) -> impl Future<Item = Vec<FanOutBatch>, Error = ()> + Send
+where
+    F: FnMut() -> CF,
+    CF: Future<Item = Connection, Error = ConnectingFailed> + Send + 'static,
+{
+    let (mail, EnvelopData { from, to }) = envelop.into();
+    let to = to.into_vec();
+    let max_connections = max_connections.max(1);
+    let batch_size = to.len().div_ceil(max_connections);
+
+    let batches = to
+        .chunks(batch_size)
+        .map(|recipients| {
+            //UNWRAP_SAFE: chunks are never empty
+            let recipients = Vec1::try_from_vec(recipients.to_vec()).unwrap();
+            let envelop = MailEnvelop::from((
+                mail.clone(),
+                EnvelopData {
+                    from: from.clone(),
+                    to: recipients.clone(),
+                },
+            ));
+
+            connect()
+                .map_err(GeneralError::from)
+                .and_then(|con| con.send_mail(envelop).map_err(GeneralError::from))
+                .and_then(|(con, result)| {
+                    con.quit()
+                        .then(|_| result.map_err(|(_idx, err)| GeneralError::Cmd(err)))
+                })
+                .then(|result| Ok(FanOutBatch { recipients, result }))
+        })
+        .collect::<Vec<_>>();
+
+    future::join_all(batches)
+}
+
 impl Connection {
     /// Sends a mail specified through `MailEnvelop` through this connection.
     ///
@@ -742,6 +856,506 @@ where
     }
 }
 
+/// DSN options used by a `SendOperation`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Dsn {
+    /// the `RET` parameter send with `MAIL`
+    pub ret: Option<Ret>,
+    /// the `NOTIFY` parameter send with every `RCPT`
+    ///
+    /// Recipients added with `SendOperationBuilder::to_with_notify` use
+    /// their own `NOTIFY` instead.
+    pub notify: Option<Notify>,
+    /// the `ENVID` parameter send with `MAIL`
+    pub envid: Option<String>,
+    /// generate a `ENVID` (see `dsn::generate_envid`) if `envid` is `None`
+    ///
+    /// The generated id can be accessed through `SendOperation::envid` and
+    /// is also returned in the `SendOperationResponses`.
+    pub auto_envid: bool,
+}
+
+/// How recipients are compared to detect duplicates, see `dedup_recipients`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RecipientComparison {
+    /// the domain is compared case-insensitive, the local part case-sensitive
+    ///
+    /// This is the default as RFC 5321 allows servers to treat the local
+    /// part as case-sensitive, while domains never are.
+    #[default]
+    CaseInsensitiveDomain,
+    /// the whole address is compared case-insensitive
+    CaseInsensitive,
+    /// the addresses are compared as they are
+    Exact,
+}
+
+impl RecipientComparison {
+    /// the key used to compare the given address
+    fn key(self, addr: &MailAddress) -> String {
+        let raw = addr.as_str();
+        match self {
+            RecipientComparison::Exact => raw.to_owned(),
+            RecipientComparison::CaseInsensitive => raw.to_lowercase(),
+            RecipientComparison::CaseInsensitiveDomain => match raw.rfind('@') {
+                Some(idx) => format!("{}{}", &raw[..idx], raw[idx..].to_lowercase()),
+                None => raw.to_owned(),
+            },
+        }
+    }
+}
+
+/// removes duplicate recipients, keeping the first occurrence of each
+///
+/// Returns the remaining recipients (in their original order) and the
+/// removed duplicates.
+pub fn dedup_recipients(
+    recipients: Vec1<MailAddress>,
+    comparison: RecipientComparison,
+) -> (Vec1<MailAddress>, Vec<MailAddress>) {
+    let recipients = recipients.into_iter().map(|addr| (addr, None)).collect();
+    let (unique, duplicates) = dedup_recipients_with_notify(recipients, comparison);
+    let unique = unique.into_iter().map(|(addr, _)| addr).collect();
+    //UNWRAP_SAFE: the first recipient is never a duplicate
+    (Vec1::try_from_vec(unique).unwrap(), duplicates)
+}
+
+/// like `dedup_recipients` but for recipients with a `NOTIFY` override
+///
+/// If the kept recipient has no override it gets the one of the first
+/// of its duplicates which has one.
+fn dedup_recipients_with_notify(
+    recipients: Vec<(MailAddress, Option<Notify>)>,
+    comparison: RecipientComparison,
+) -> (Vec<(MailAddress, Option<Notify>)>, Vec<MailAddress>) {
+    let mut index_of_key = HashMap::new();
+    let mut unique: Vec<(MailAddress, Option<Notify>)> = Vec::new();
+    let mut duplicates = Vec::new();
+    for (addr, notify) in recipients {
+        match index_of_key.entry(comparison.key(&addr)) {
+            Entry::Vacant(entry) => {
+                entry.insert(unique.len());
+                unique.push((addr, notify));
+            }
+            Entry::Occupied(entry) => {
+                let kept_notify = &mut unique[*entry.get()].1;
+                if kept_notify.is_none() {
+                    *kept_notify = notify;
+                }
+                duplicates.push(addr);
+            }
+        }
+    }
+    (unique, duplicates)
+}
+
+/// A validated mail transaction (`MAIL`, `RCPT`(s), `DATA`)
+///
+/// Use `SendOperation::new()` to get a `SendOperationBuilder`
+/// which is used to create it.
+///
+/// # Example
+///
+/// ```
+/// use new_tokio_smtp::command::dsn::Notify;
+/// use new_tokio_smtp::send_mail::{Dsn, MailAddress, SendOperation};
+///
+/// let op = SendOperation::new()
+///     .from(MailAddress::from_unchecked("t1@test.test"))
+///     .to(vec![MailAddress::from_unchecked("t2@test.test")])
+///     .dsn(Dsn { notify: Some(Notify::Never), auto_envid: true, ..Default::default() })
+///     .size_hint(1024)
+///     .body("Subject: hy\r\n\r\nthere\r\n")
+///     .build()
+///     .expect("invalid send operation");
+/// assert!(op.envid().is_some());
+/// ```
+#[derive(Debug, Clone)]
+pub struct SendOperation {
+    from: Option<MailAddress>,
+    to: Vec1<MailAddress>,
+    dsn: Option<Dsn>,
+    size_hint: Option<usize>,
+    mail: Mail,
+    collapsed_duplicates: Vec<MailAddress>,
+    /// the `NOTIFY` override of each recipient, in the order of `to`
+    notify_overrides: Vec<Option<Notify>>,
+}
+
+/// Builder for a `SendOperation`
+#[derive(Debug, Clone, Default)]
+pub struct SendOperationBuilder {
+    from: Option<MailAddress>,
+    /// the recipients, each with its `NOTIFY` override (if any)
+    to: Vec<(MailAddress, Option<Notify>)>,
+    dsn: Option<Dsn>,
+    size_hint: Option<usize>,
+    encoding_requirement: Option<EncodingRequirement>,
+    body: Option<Bytes>,
+    dedup: Option<RecipientComparison>,
+}
+
+impl SendOperationBuilder {
+    /// sets the reverse path (default: `<>`, i.e. an empty reverse path)
+    pub fn from(mut self, from: MailAddress) -> Self {
+        self.from = Some(from);
+        self
+    }
+
+    /// adds the given addresses to the recipients
+    pub fn to<I>(mut self, to: I) -> Self
+    where
+        I: IntoIterator<Item = MailAddress>,
+    {
+        self.to.extend(to.into_iter().map(|addr| (addr, None)));
+        self
+    }
+
+    /// adds a recipient using the given `NOTIFY` instead of the one of `Dsn::notify`
+    ///
+    /// This requires `DSN` even if `dsn` is not used.
+    pub fn to_with_notify(mut self, to: MailAddress, notify: Notify) -> Self {
+        self.to.push((to, Some(notify)));
+        self
+    }
+
+    /// removes duplicate recipients when building (default: duplicates are kept)
+    ///
+    /// The removed duplicates are available through `SendOperation::collapsed_duplicates`.
+    /// If the kept recipient has no `NOTIFY` override (see `to_with_notify`)
+    /// it uses the one of the first removed duplicate which has one.
+    pub fn dedup_recipients(mut self, comparison: RecipientComparison) -> Self {
+        self.dedup = Some(comparison);
+        self
+    }
+
+    /// requests delivery status notifications (requires `DSN`)
+    pub fn dsn(mut self, dsn: Dsn) -> Self {
+        self.dsn = Some(dsn);
+        self
+    }
+
+    /// sets the size send with `MAIL` if the server supports `SIZE`
+    ///
+    /// The hint has to be at last as large as the body.
+    pub fn size_hint(mut self, size: usize) -> Self {
+        self.size_hint = Some(size);
+        self
+    }
+
+    /// sets the encoding requirement of the body (default: `EncodingRequirement::None`)
+    pub fn encoding_requirement(mut self, requirement: EncodingRequirement) -> Self {
+        self.encoding_requirement = Some(requirement);
+        self
+    }
+
+    /// sets the body, i.e. the mail (without dot-stashing)
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = Some(body.into());
+        self
+    }
+
+    /// validates all settings and creates the `SendOperation`
+    pub fn build(self) -> Result<SendOperation, SendOperationError> {
+        let SendOperationBuilder {
+            from,
+            to,
+            dsn,
+            size_hint,
+            encoding_requirement,
+            body,
+            dedup,
+        } = self;
+
+        if to.is_empty() {
+            return Err(SendOperationError::NoRecipients);
+        }
+        let (to, collapsed_duplicates) = match dedup {
+            Some(comparison) => dedup_recipients_with_notify(to, comparison),
+            None => (to, Vec::new()),
+        };
+        let (to, notify_overrides): (Vec<_>, Vec<_>) = to.into_iter().unzip();
+        //UNWRAP_SAFE: checked above, dedup keeps at least one recipient
+        let to = Vec1::try_from_vec(to).unwrap();
+        let body = body.ok_or(SendOperationError::NoBody)?;
+
+        if notify_overrides
+            .iter()
+            .flatten()
+            .any(|notify| !notify.is_valid())
+        {
+            return Err(SendOperationError::InvalidNotify);
+        }
+
+        let mut dsn = dsn;
+        if let Some(dsn) = dsn.as_mut() {
+            if let Some(notify) = dsn.notify {
+                if !notify.is_valid() {
+                    return Err(SendOperationError::InvalidNotify);
+                }
+            }
+            if dsn.envid.is_none() && dsn.auto_envid {
+                dsn.envid = Some(dsn::generate_envid());
+            }
+            if let Some(envid) = dsn.envid.as_ref() {
+                if dsn::encode_xtext(envid).len() > dsn::MAX_ENVID_LEN {
+                    return Err(SendOperationError::EnvIdTooLong);
+                }
+            }
+        }
+
+        if let Some(size_hint) = size_hint {
+            if size_hint < body.len() {
+                return Err(SendOperationError::SizeHintTooSmall {
+                    size_hint,
+                    body_size: body.len(),
+                });
+            }
+        }
+
+        let requirement = encoding_requirement.unwrap_or(EncodingRequirement::None);
+        Ok(SendOperation {
+            from,
+            to,
+            dsn,
+            size_hint,
+            mail: Mail::new(requirement, body),
+            collapsed_duplicates,
+            notify_overrides,
+        })
+    }
+}
+
+/// Error returned by `SendOperationBuilder::build`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SendOperationError {
+    /// no recipient was given
+    NoRecipients,
+    /// no body was given
+    NoBody,
+    /// `Notify::On` was used without enabling any event
+    InvalidNotify,
+    /// the (xtext encoded) `ENVID` is longer than `dsn::MAX_ENVID_LEN`
+    EnvIdTooLong,
+    /// the size hint is smaller than the body
+    SizeHintTooSmall { size_hint: usize, body_size: usize },
+}
+
+impl Display for SendOperationError {
+    fn fmt(&self, fter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::SendOperationError::*;
+        match *self {
+            NoRecipients => write!(fter, "send operation without recipients"),
+            NoBody => write!(fter, "send operation without body"),
+            InvalidNotify => write!(fter, "DSN NOTIFY without any event"),
+            EnvIdTooLong => write!(fter, "DSN ENVID is too long"),
+            SizeHintTooSmall {
+                size_hint,
+                body_size,
+            } => write!(
+                fter,
+                "size hint ({}) is smaller than the body ({})",
+                size_hint, body_size
+            ),
+        }
+    }
+}
+
+impl ErrorTrait for SendOperationError {}
+
+/// The responses of a successful `SendOperation`
+#[derive(Debug, Clone)]
+pub struct SendOperationResponses {
+    /// the response to `MAIL`
+    pub mail: Response,
+    /// the responses to `RCPT`, in the order the recipients where given
+    /// (without collapsed duplicates)
+    pub recipients: Vec<Response>,
+    /// the (final) response to `DATA`
+    pub data: Response,
+    /// the `ENVID` send with `MAIL` (if any), e.g. a generated one
+    pub envid: Option<String>,
+}
+
+/// The result of a `SendOperation`
+///
+/// Like `MailSendResult` the error contains the index of the
+/// failing command (`0` for `MAIL` and for missing capabilities).
+pub type SendOperationResult = Result<SendOperationResponses, (usize, LogicError)>;
+
+impl SendOperation {
+    /// creates a new `SendOperationBuilder`
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new() -> SendOperationBuilder {
+        SendOperationBuilder::default()
+    }
+
+    /// the reverse path, `None` represents `<>`
+    pub fn from_address(&self) -> Option<&MailAddress> {
+        self.from.as_ref()
+    }
+
+    /// the recipients
+    pub fn to_address(&self) -> &Vec1<MailAddress> {
+        &self.to
+    }
+
+    /// the mail
+    pub fn mail(&self) -> &Mail {
+        &self.mail
+    }
+
+    /// the recipients removed as duplicates, see `SendOperationBuilder::dedup_recipients`
+    pub fn collapsed_duplicates(&self) -> &[MailAddress] {
+        &self.collapsed_duplicates
+    }
+
+    /// the `ENVID` which will be send, if any (including a generated one)
+    pub fn envid(&self) -> Option<&str> {
+        self.dsn.as_ref().and_then(|dsn| dsn.envid.as_deref())
+    }
+
+    /// runs the operation on the given connection
+    ///
+    /// If any command fails, `RSET` is send and the index of the
+    /// failed command is returned together with the error.
+    pub fn exec(
+        self,
+        con: Connection,
+    ) -> impl Future<Item = (Connection, SendOperationResult), Error = std_io::Error> + Send {
+        let SendOperation {
+            from,
+            to,
+            dsn,
+            size_hint,
+            mail,
+            collapsed_duplicates: _,
+            notify_overrides,
+        } = self;
+
+        let envelop_data = EnvelopData { from, to };
+        let use_smtputf8 = envelop_data.needs_smtputf8() || mail.needs_smtputf8();
+        let use_mime8bit =
+            !use_smtputf8 && mail.encoding_requirement() == EncodingRequirement::Mime8bit;
+
+        let missing = if use_smtputf8 && !con.has_capability("SMTPUTF8") {
+            Some("SMTPUTF8")
+        } else if use_mime8bit && !con.has_capability("8BITMIME") {
+            Some("8BITMIME")
+        } else if (dsn.is_some() || notify_overrides.iter().any(Option::is_some))
+            && !con.has_capability(dsn::CAP_DSN)
+        {
+            Some(dsn::CAP_DSN)
+        } else {
+            None
+        };
+
+        if let Some(missing) = missing {
+            let err = MissingCapabilities::new_from_unchecked(missing).into();
+            return Either::B(future::ok((con, Err((0, err)))));
+        }
+
+        let size = size_hint.unwrap_or_else(|| mail.raw_data().len());
+        if let Err(err) = check_size_limit(&con, size) {
+            return Either::B(future::ok((con, Err((0, err)))));
+        }
+
+        let EnvelopData { from, to: tos } = envelop_data;
+        let dsn = dsn.unwrap_or_default();
+
+        let mut mail_params = Default::default();
+        if use_smtputf8 {
+            mail_params = params_with_smtputf8(mail_params);
+        }
+        if let Some(size) = size_hint {
+            if con.has_capability("SIZE") {
+                mail_params = params_with_size(mail_params, size);
+            }
+        }
+        if let Some(ret) = dsn.ret {
+            mail_params = dsn::params_with_ret(mail_params, ret);
+        }
+        if let Some(envid) = dsn.envid.as_ref() {
+            mail_params = dsn::params_with_envid(mail_params, envid);
+        }
+        let envid = dsn.envid.clone();
+
+        let reverse_path = from
+            .map(ReversePath::from)
+            .unwrap_or_else(ReversePath::empty);
+
+        let mut cmds = vec![command::Mail {
+            reverse_path,
+            params: mail_params,
+        }
+        .boxed()];
+
+        for (to, notify) in tos.into_iter().zip(notify_overrides) {
+            let notify = notify.or(dsn.notify);
+            let mut rcpt = command::Recipient::new(to.into());
+            if let Some(notify) = notify {
+                rcpt.params = dsn::params_with_notify(rcpt.params, notify);
+            }
+            cmds.push(rcpt.boxed());
+        }
+
+        cmds.push(command::Data::from_buf(mail.into_raw_data()).boxed());
+
+        let fut = send_collecting_responses(con, cmds).map(move |(con, result)| {
+            let result = result.map(|mut responses| {
+                //UNWRAP_SAFE: there is always a MAIL and DATA response on success
+                let data = responses.pop().unwrap();
+                let mail = responses.remove(0);
+                SendOperationResponses {
+                    mail,
+                    recipients: responses,
+                    data,
+                    envid,
+                }
+            });
+            (con, result)
+        });
+
+        Either::A(fut)
+    }
+}
+
+/// like `chain` with `OnError::StopAndReset` but keeps the responses
+fn send_collecting_responses(
+    con: Connection,
+    cmds: Vec<BoxedCmd>,
+) -> impl Future<
+    Item = (Connection, Result<Vec<Response>, (usize, LogicError)>),
+    Error = std_io::Error,
+> + Send {
+    let mut cmds = cmds;
+    //stackify
+    cmds.reverse();
+
+    future::loop_fn((con, Vec::new()), move |(con, mut responses)| {
+        if let Some(next_cmd) = cmds.pop() {
+            let fut = con
+                .send(next_cmd)
+                .and_then(move |(con, result)| match result {
+                    Ok(response) => {
+                        responses.push(response);
+                        Either::A(future::ok(Loop::Continue((con, responses))))
+                    }
+                    Err(err) => {
+                        let index = responses.len();
+                        let fut = con
+                            .send(command::Reset)
+                            .map(move |(con, _)| Loop::Break((con, Err((index, err)))));
+                        Either::B(fut)
+                    }
+                });
+            Either::A(fut)
+        } else {
+            Either::B(future::ok(Loop::Break((con, Ok(responses)))))
+        }
+    })
+}
+
 #[cfg(test)]
 mod test {
     use crate::error::GeneralError;
@@ -1,7 +1,7 @@
 #![allow(non_snake_case)]
 
 
-use new_tokio_smtp::{command, ClientId};
+use new_tokio_smtp::{command, ClientId, Domain};
 
 use new_tokio_smtp::mock::{ActionData, Actor};
 
@@ -22,6 +22,54 @@ mod Ehlo {
     use super::*;
     use futures::Future;
 
+    #[test]
+    fn accepts_empty_continuation_lines() {
+        let con = mock(vec![
+            (Client, Lines(vec!["EHLO me.test"])),
+            (Server, Lines(vec!["250-they.test", "250-", "250 SMTPUTF8"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+
+        let (con, res) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+        res.unwrap();
+        assert!(con.has_capability("SMTPUTF8"));
+        assert_eq!(con.ehlo_data().unwrap().capability_map().len(), 1);
+        con.quit().wait().unwrap();
+    }
+
+    #[test]
+    fn single_line_response_has_no_capabilities() {
+        use new_tokio_smtp::error::LogicError;
+
+        let con = mock(vec![
+            (Client, Lines(vec!["EHLO me.test"])),
+            (Server, Lines(vec!["250 mail.example.com"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+
+        let (con, res) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+        res.unwrap();
+        let ehlo_data = con.ehlo_data().unwrap();
+        assert_eq!(ehlo_data.domain(), "mail.example.com");
+        assert!(ehlo_data.capability_map().is_empty());
+
+        let (con, res) = con
+            .send(command::StartTls::new(Domain::from_unchecked(
+                "mail.example.com",
+            )))
+            .wait()
+            .unwrap();
+        match res {
+            Err(LogicError::MissingCapabilities(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "STARTTLS")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        con.quit().wait().unwrap();
+    }
+
     #[test]
     fn parsed_response_into_ehlo_data() {
         let con = mock(vec![
@@ -60,6 +108,96 @@ mod Ehlo {
 
         con.shutdown().wait().unwrap();
     }
+
+    #[test]
+    fn lists_unknown_capabilities() {
+        let con = mock(vec![
+            (Client, Lines(vec!["EHLO me.test"])),
+            (
+                Server,
+                Lines(vec![
+                    "250-they.test greets you",
+                    "250-SMTPUTF8",
+                    "250-XVENDOR",
+                    "250 size 1000",
+                ]),
+            ),
+        ]);
+
+        let fut = con
+            .send(command::Ehlo::new(client_id()))
+            .map(|(con, result)| match result {
+                Ok(_) => con,
+                Err(e) => panic!("unexpected ehlo failed: {:?}", e),
+            });
+
+        let con = fut.wait().unwrap();
+        {
+            let unknown = con.ehlo_data().unwrap().unknown_capabilities();
+            assert_eq!(unknown.len(), 1);
+            assert_eq!(unknown[0].as_str(), "XVENDOR");
+        }
+
+        con.shutdown().wait().unwrap();
+    }
+
+    #[test]
+    fn auth_mechanisms_are_refreshed_by_ehlo_after_starttls() {
+        let con = mock(vec![
+            (Client, Lines(vec!["EHLO me.test"])),
+            (
+                Server,
+                Lines(vec!["250-they.test", "250-STARTTLS", "250 AUTH PLAIN"]),
+            ),
+            // the mock socket only pretends to upgrade, nothing is send
+            (Client, Lines(vec!["EHLO me.test"])),
+            (
+                Server,
+                Lines(vec!["250-they.test", "250 AUTH PLAIN XOAUTH2"]),
+            ),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        assert!(con.auth_mechanisms().is_empty());
+
+        let (con, res) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+        res.unwrap();
+        assert_eq!(con.auth_mechanisms(), &["PLAIN"]);
+
+        let (con, res) = con
+            .send(command::StartTls::new(Domain::from_unchecked("they.test")))
+            .wait()
+            .unwrap();
+        res.unwrap();
+        let (con, res) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+        res.unwrap();
+        assert_eq!(con.auth_mechanisms(), &["PLAIN", "XOAUTH2"]);
+
+        con.quit().wait().unwrap();
+    }
+}
+
+mod StartTls {
+    use super::*;
+    use futures::Future;
+    use new_tokio_smtp::error::LogicError;
+
+    #[test]
+    fn requires_ehlo() {
+        let con = mock(vec![]);
+
+        let (con, res) = con
+            .send(command::StartTls::new(Domain::from_unchecked("they.test")))
+            .wait()
+            .unwrap();
+        match res {
+            Err(LogicError::EhloRequired(err)) => {
+                assert_eq!(err.capabilities()[0].as_str(), "STARTTLS")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        con.shutdown().wait().unwrap();
+    }
 }
 
 mod Reset {
@@ -85,13 +223,548 @@ mod Reset {
 }
 
 mod Data {
-    //TODO test
+    use super::*;
+    use futures::{stream, Future};
+    use new_tokio_smtp::io::ByteLogger;
+    use std::io::{self as std_io, Cursor};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Default)]
+    struct Log {
+        output: Vec<u8>,
+        input: Vec<u8>,
+        redacted: Vec<(&'static str, usize)>,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    struct SharedLogger(Arc<Mutex<Log>>);
+
+    impl ByteLogger for SharedLogger {
+        fn log_output(&mut self, data: &[u8]) {
+            self.0.lock().unwrap().output.extend_from_slice(data);
+        }
+
+        fn log_input(&mut self, data: &[u8]) {
+            self.0.lock().unwrap().input.extend_from_slice(data);
+        }
+
+        fn log_redacted_output(&mut self, verb: &'static str, len: usize) {
+            self.0.lock().unwrap().redacted.push((verb, len));
+        }
+    }
+
+    #[test]
+    fn send_accepted_parses_the_final_response() {
+        let con = mock(vec![
+            (Client, Lines(vec!["DATA"])),
+            (Server, Lines(vec!["354 go ahead"])),
+            (Client, Lines(vec!["body", "."])),
+            (Server, Lines(vec!["250 2.0.0 Ok: queued as XYZ"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+
+        let (con, res) = command::Data::from_buf("body\r\n")
+            .send_accepted(con)
+            .wait()
+            .unwrap();
+        let accepted = res.unwrap();
+        assert_eq!(accepted.code, new_tokio_smtp::response::codes::OK);
+        let status = accepted.enhanced_status.unwrap();
+        assert_eq!((status.class, status.subject, status.detail), (2, 0, 0));
+        assert_eq!(accepted.queue_id.as_deref(), Some("XYZ"));
+        con.quit().wait().unwrap();
+    }
+
+    #[test]
+    fn body_is_not_passed_to_the_byte_logger() {
+        let mut con = mock(vec![
+            (Client, Lines(vec!["DATA"])),
+            (Server, Lines(vec!["354 go ahead"])),
+            (Client, Lines(vec!["secret body", "."])),
+            (Server, Lines(vec!["250 ok"])),
+            (Client, Lines(vec!["RSET"])),
+            (Server, Lines(vec!["250 ok"])),
+        ]);
+        let logger = SharedLogger::default();
+        con.set_byte_logger(Box::new(logger.clone()));
+
+        let fut = con
+            .send(command::Data::from_buf("secret body\r\n"))
+            .and_then(|(con, res)| {
+                res.unwrap();
+                con.send(command::Reset)
+            })
+            .and_then(|(con, res)| {
+                res.unwrap();
+                con.shutdown()
+            });
+        fut.wait().unwrap();
+
+        let log = logger.0.lock().unwrap();
+        assert_eq!(log.output, b"RSET\r\n");
+        assert!(!log.redacted.is_empty());
+        assert!(log.redacted.iter().all(|&(verb, _)| verb == "DATA"));
+        let redacted_len: usize = log.redacted.iter().map(|&(_, len)| len).sum();
+        assert_eq!(redacted_len, "DATA\r\nsecret body\r\n.\r\n".len());
+        assert_eq!(log.input, b"354 go ahead\r\n250 ok\r\n250 ok\r\n");
+    }
+
+    #[test]
+    fn reports_progress_per_chunk() {
+        let con = mock(vec![
+            (Client, Lines(vec!["DATA"])),
+            (Server, Lines(vec!["354 go ahead"])),
+            (
+                Client,
+                Lines(vec!["line one", "..line two", "line three", "."]),
+            ),
+            (Server, Lines(vec!["250 ok"])),
+        ]);
+        let chunks: Vec<&'static [u8]> = vec![b"line one\r\n", b".line two\r\n", b"line three\r\n"];
+        let total = chunks.iter().map(|chunk| chunk.len()).sum::<usize>();
+        let source = stream::iter_ok::<_, std_io::Error>(chunks.into_iter().map(Cursor::new));
+
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reports_out = reports.clone();
+        let data =
+            command::Data::new(source)
+                .with_total(total)
+                .with_progress(move |written, total| {
+                    reports_out.lock().unwrap().push((written, total))
+                });
+
+        let fut = con.send(data).and_then(|(con, res)| {
+            res.unwrap();
+            con.shutdown()
+        });
+        fut.wait().unwrap();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 3);
+        assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(reports
+            .iter()
+            .all(|&(_, report_total)| report_total == Some(total)));
+        assert_eq!(reports.last().unwrap().0, total);
+    }
+
+    #[test]
+    fn body_with_8bit_data_fails_before_data_if_not_7bit_clean() {
+        use new_tokio_smtp::error::{LogicError, Not7BitClean};
+
+        // DATA is never send, so the connection stays usable
+        let con = mock(vec![
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+
+        let body = b"Subject: hy\r\n\r\nna\x80ve\r\n".to_vec();
+        let (con, res) = con
+            .send(command::Data::from_buf(body).check_7bit_clean())
+            .wait()
+            .unwrap();
+        match res {
+            Err(LogicError::Custom(err)) => {
+                let clean_err = err.downcast_ref::<Not7BitClean>().unwrap();
+                assert_eq!(clean_err.offset(), 17);
+                assert_eq!(clean_err.byte(), 0x80);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        con.quit().wait().unwrap();
+    }
+
+    #[test]
+    fn streamed_8bit_data_fails_after_data_if_not_7bit_clean() {
+        use new_tokio_smtp::error::Not7BitClean;
+
+        // the second chunk is never written, so the conversation ends after the first
+        let con = mock_no_shutdown(vec![
+            (Client, Lines(vec!["DATA"])),
+            (Server, Lines(vec!["354 go ahead"])),
+            (Client, Lines(vec!["Subject: hy", ""])),
+        ]);
+
+        let chunks: Vec<&'static [u8]> = vec![b"Subject: hy\r\n\r\n", b"na\x80ve\r\n"];
+        let source = stream::iter_ok::<_, std_io::Error>(chunks.into_iter().map(Cursor::new));
+        let err = con
+            .send(command::Data::new(source).check_7bit_clean())
+            .wait()
+            .map(|_| ())
+            .unwrap_err();
+
+        let clean_err = Not7BitClean::from_io_error(&err).expect("7-bit error not found");
+        assert_eq!(clean_err.offset(), 17);
+        assert_eq!(clean_err.byte(), 0x80);
+    }
+
+    #[test]
+    fn fails_client_side_if_no_recipient_was_accepted() {
+        use new_tokio_smtp::error::{LogicError, NoAcceptedRecipients};
+        use new_tokio_smtp::mock::MockSocket;
+        use new_tokio_smtp::{Connection, ForwardPath, Io, ReversePath};
+
+        let mut socket = MockSocket::new(vec![
+            (Client, Lines(vec!["MAIL FROM:<a@test.test>"])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["RCPT TO:<b@test.test>"])),
+            (Server, Lines(vec!["550 No such user"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        let sent = socket.record_sent();
+        let mut con = Connection::from(Io::from(socket));
+        con.enable_transaction_tracking();
+
+        let mail = command::Mail::new(ReversePath::from_unchecked("a@test.test"));
+        let rcpt = command::Recipient::new(ForwardPath::from_unchecked("b@test.test"));
+        let (con, res) = con
+            .send(mail)
+            .and_then(|(con, res)| {
+                res.unwrap();
+                con.send(rcpt)
+            })
+            .wait()
+            .unwrap();
+        assert!(res.is_err());
+        let state = con.transaction_state().unwrap();
+        assert!(state.started);
+        assert_eq!(state.accepted_recipients, 0);
+
+        let bytes_before = sent.sent_bytes().len();
+        let (con, res) = con
+            .send(command::Data::from_buf("body\r\n"))
+            .wait()
+            .unwrap();
+        match res {
+            Err(LogicError::Custom(err)) => assert!(err.is::<NoAcceptedRecipients>()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(sent.sent_bytes().len(), bytes_before);
+
+        con.quit().wait().unwrap();
+        assert_eq!(
+            sent.sent_lines(),
+            ["MAIL FROM:<a@test.test>", "RCPT TO:<b@test.test>", "QUIT"]
+        );
+    }
 }
 
 mod Mail {
-    //TODO test
+    use super::*;
+    use futures::Future;
+    use new_tokio_smtp::ReversePath;
+
+    #[test]
+    fn emits_extra_param() {
+        let con = mock(vec![
+            (Client, Lines(vec!["MAIL FROM:<a> XFOO=bar"])),
+            (Server, Lines(vec!["250 ok"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        let cmd = command::Mail::new(ReversePath::from_unchecked("a"))
+            .extra_param("XFOO", Some("bar"))
+            .unwrap();
+
+        let (con, res) = con.send(cmd).wait().unwrap();
+        res.unwrap();
+        con.quit().wait().unwrap();
+    }
+
+    #[test]
+    fn fails_with_authentication_required() {
+        use new_tokio_smtp::error::{AuthenticationRequired, LogicError};
+
+        let con = mock(vec![
+            (Client, Lines(vec!["MAIL FROM:<a>"])),
+            (Server, Lines(vec!["530 5.7.0 Authentication required"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+
+        let (con, res) = con
+            .send(command::Mail::new(ReversePath::from_unchecked("a")))
+            .wait()
+            .unwrap();
+        match res {
+            Err(LogicError::Custom(err)) => {
+                let err = err.downcast_ref::<AuthenticationRequired>().unwrap();
+                assert_eq!(err.response().code().as_u16(), 530);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        con.quit().wait().unwrap();
+    }
+
+    #[test]
+    fn validates_extra_param() {
+        let mail = || command::Mail::new(ReversePath::from_unchecked("a"));
+        assert!(mail().extra_param("X FOO", None).is_err());
+        assert!(mail().extra_param("XFOO", Some("bar\r\nRSET")).is_err());
+        assert!(mail().extra_param("XFOO", Some("a=b")).is_err());
+        assert!(mail().extra_param("XFOO", None).is_ok());
+    }
+}
+
+mod auth {
+    use super::*;
+    use futures::{future, Future};
+    use new_tokio_smtp::command::auth::{self, CredentialProvider, XOAuth2};
+    use new_tokio_smtp::error::LogicError;
+    use new_tokio_smtp::response::codes;
+
+    struct RefreshingProvider;
+
+    impl CredentialProvider for RefreshingProvider {
+        type Cmd = XOAuth2;
+
+        fn fetch(
+            &mut self,
+            rejected: bool,
+        ) -> Box<dyn Future<Item = XOAuth2, Error = LogicError> + Send> {
+            let token = if rejected { "new-token" } else { "old-token" };
+            Box::new(future::ok(XOAuth2::new("me@example.com", token)))
+        }
+    }
+
+    #[test]
+    fn login_ignores_prompt_text() {
+        let con = mock(vec![
+            (Client, Lines(vec!["EHLO me.test"])),
+            (Server, Lines(vec!["250-they.test", "250 AUTH LOGIN"])),
+            (Client, Lines(vec!["AUTH LOGIN"])),
+            // base64 of "Benutzername:"
+            (Server, Lines(vec!["334 QmVudXR6ZXJuYW1lOg=="])),
+            (Client, Lines(vec!["dXNlcg=="])),
+            // empty prompt instead of "Password:"
+            (Server, Lines(vec!["334"])),
+            (Client, Lines(vec!["cHc="])),
+            (Server, Lines(vec!["235 2.7.0 accepted"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        let (con, res) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+        res.unwrap();
+
+        let (con, res) = con.send(auth::Login::new("user", "pw")).wait().unwrap();
+        assert_eq!(res.unwrap().code(), codes::AUTH_SUCCEEDED);
+        con.quit().wait().unwrap();
+    }
+
+    #[test]
+    fn refreshes_rejected_token_once() {
+        let con = mock(vec![
+            (Client, Lines(vec!["EHLO me.test"])),
+            (Server, Lines(vec!["250-they.test", "250 AUTH XOAUTH2"])),
+            (
+                Client,
+                Lines(vec![
+                    "AUTH XOAUTH2 dXNlcj1tZUBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciBvbGQtdG9rZW4BAQ==",
+                ]),
+            ),
+            (Server, Lines(vec!["334 eyJzdGF0dXMiOiI0MDEifQ=="])),
+            (Client, Lines(vec![""])),
+            (Server, Lines(vec!["535 5.7.8 token expired"])),
+            (
+                Client,
+                Lines(vec![
+                    "AUTH XOAUTH2 dXNlcj1tZUBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciBuZXctdG9rZW4BAQ==",
+                ]),
+            ),
+            (Server, Lines(vec!["235 2.7.0 accepted"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        let (con, res) = con.send(command::Ehlo::new(client_id())).wait().unwrap();
+        res.unwrap();
+
+        let (con, res) = auth::authenticate(con, RefreshingProvider).wait().unwrap();
+        assert_eq!(res.unwrap().code(), codes::AUTH_SUCCEEDED);
+        con.quit().wait().unwrap();
+    }
 }
 
 mod Recipient {
-    //todo test
+    use super::*;
+    use futures::Future;
+    use new_tokio_smtp::error::LogicError;
+    use new_tokio_smtp::response::RejectionKind;
+    use new_tokio_smtp::ForwardPath;
+
+    #[test]
+    fn emits_source_routed_path() {
+        let con = mock(vec![
+            (
+                Client,
+                Lines(vec!["RCPT TO:<@relay1.test,@relay2.test:user@final.test>"]),
+            ),
+            (Server, Lines(vec!["250 ok"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        let fpath =
+            ForwardPath::with_source_route(&["relay1.test", "relay2.test"], "user@final.test")
+                .unwrap();
+
+        let (con, res) = con.send(command::Recipient::new(fpath)).wait().unwrap();
+        res.unwrap();
+        con.quit().wait().unwrap();
+    }
+
+    #[test]
+    fn classifies_relaying_denied() {
+        let con = mock(vec![
+            (Client, Lines(vec!["RCPT TO:<user@foreign.test>"])),
+            (
+                Server,
+                Lines(vec!["554 5.7.1 <user@foreign.test>: Relay access denied"]),
+            ),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        let fpath = ForwardPath::from_unchecked("user@foreign.test");
+
+        let (con, res) = con.send(command::Recipient::new(fpath)).wait().unwrap();
+        let response = match res {
+            Err(LogicError::Code(response)) => response,
+            other => panic!("unexpected result: {:?}", other),
+        };
+        assert_eq!(
+            response.rcpt_rejection_kind(),
+            Some(RejectionKind::RelayDenied)
+        );
+        con.quit().wait().unwrap();
+    }
+}
+
+mod verb {
+    use super::*;
+    use new_tokio_smtp::command::auth;
+    use new_tokio_smtp::{Cmd, ForwardPath, ReversePath};
+
+    #[test]
+    fn commands_provide_their_verb() {
+        assert_eq!(command::Ehlo::new(client_id()).verb(), "EHLO");
+        assert_eq!(
+            command::Mail::new(ReversePath::from_unchecked("t@test.test")).verb(),
+            "MAIL"
+        );
+        assert_eq!(
+            command::Recipient::new(ForwardPath::from_unchecked("t@test.test")).verb(),
+            "RCPT"
+        );
+        assert_eq!(command::Data::from_buf("body\r\n").verb(), "DATA");
+        assert_eq!(auth::Login::new("user", "pw").verb(), "AUTH");
+        assert_eq!(command::Reset.verb(), "RSET");
+    }
+
+    #[test]
+    fn verb_defaults_to_unknown() {
+        use new_tokio_smtp::error::MissingCapabilities;
+        use new_tokio_smtp::{EhloData, ExecFuture, Io};
+
+        // a command written against the trait without `verb`
+        struct Custom;
+
+        impl Cmd for Custom {
+            fn check_cmd_availability(
+                &self,
+                _caps: Option<&EhloData>,
+            ) -> Result<(), MissingCapabilities> {
+                Ok(())
+            }
+
+            fn exec(self, io: Io) -> ExecFuture {
+                io.exec_simple_cmd(&["XCUSTOM"])
+            }
+        }
+
+        assert_eq!(Custom.verb(), "UNKNOWN");
+    }
+
+    #[test]
+    fn commands_send_their_verb_in_uppercase() {
+        use futures::Future;
+        use new_tokio_smtp::mock::MockSocket;
+        use new_tokio_smtp::{BoxedCmd, Connection, Io};
+
+        let mut socket = MockSocket::new(vec![
+            (Client, Lines(vec!["EHLO me.test"])),
+            (
+                Server,
+                Lines(vec!["250-they.test", "250-AUTH PLAIN", "250 CHUNKING"]),
+            ),
+            (Client, Lines(vec!["AUTH PLAIN dXNlcgB1c2VyAHB3"])),
+            (Server, Lines(vec!["235 Ok"])),
+            (Client, Lines(vec!["MAIL FROM:<a@test.test>"])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["RCPT TO:<b@test.test>"])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["DATA"])),
+            (Server, Lines(vec!["354 go ahead"])),
+            (Client, Lines(vec!["body", "."])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Blob(b"BDAT 4 LAST\r\nbody".to_vec())),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["RSET"])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["NOOP"])),
+            (Server, Lines(vec!["250 Ok"])),
+            (Client, Lines(vec!["VRFY b@test.test"])),
+            (Server, Lines(vec!["252 maybe"])),
+            (Client, Lines(vec!["HELP"])),
+            (Server, Lines(vec!["214 see the rfc"])),
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]);
+        let sent = socket.record_sent();
+        let mut con = Connection::from(Io::from(socket));
+
+        let cmds: Vec<BoxedCmd> = vec![
+            command::Ehlo::new(client_id()).boxed(),
+            auth::Plain::from_username("user", "pw").unwrap().boxed(),
+            command::Mail::new(ReversePath::from_unchecked("a@test.test")).boxed(),
+            command::Recipient::new(ForwardPath::from_unchecked("b@test.test")).boxed(),
+            command::Data::from_buf("body\r\n").boxed(),
+            command::Bdat::new("body", true).boxed(),
+            command::Reset.boxed(),
+            command::Noop.boxed(),
+            command::Verify {
+                query: "b@test.test".to_owned(),
+            }
+            .boxed(),
+            command::Help { topic: None }.boxed(),
+        ];
+        let mut checked = 0;
+        let mut check_new_output = |verb: &str| {
+            let output = sent.sent_bytes();
+            assert_eq!(verb, verb.to_ascii_uppercase());
+            assert!(
+                output[checked..].starts_with(verb.as_bytes()),
+                "{:?} doesn't start with {}",
+                String::from_utf8_lossy(&output[checked..]),
+                verb
+            );
+            checked = output.len();
+        };
+        for cmd in cmds {
+            let verb = cmd.verb();
+            let (new_con, res) = con.send(cmd).wait().unwrap();
+            res.unwrap();
+            check_new_output(verb);
+            con = new_con;
+        }
+        con.quit().wait().unwrap();
+        check_new_output("QUIT");
+    }
+
+    #[test]
+    fn boxed_commands_keep_their_verb() {
+        assert_eq!(command::Noop.boxed().verb(), "NOOP");
+        let either: command::EitherCmd<command::Noop, command::Reset> =
+            command::EitherCmd::B(command::Reset);
+        assert_eq!(either.verb(), "RSET");
+    }
 }
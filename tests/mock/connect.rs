@@ -0,0 +1,674 @@
+use std::io::{self as std_io, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use socket2::SockRef;
+use tokio::runtime::current_thread::{Builder, Runtime};
+
+use new_tokio_smtp::command::Noop;
+use new_tokio_smtp::error::{
+    AffinityMismatch, AllCandidatesFailed, ConnectError, ConnectingFailed, LogicError,
+};
+use new_tokio_smtp::event::{ConnectionEvent, EventSubscriber};
+use new_tokio_smtp::io::Keepalive;
+use new_tokio_smtp::limit::DestinationLimiter;
+use new_tokio_smtp::mock::{ActionData, Actor, MockClock, MockSocket};
+use new_tokio_smtp::response::codes;
+use new_tokio_smtp::{
+    ClientId, Connection, ConnectionBuilder, ConnectionConfig, DefaultTlsSetup, Io,
+    ReuseTlsConnector, SecureTlsSetup, ServerIdentity, SetupOptions, SetupTls, TlsConfig,
+};
+
+use self::ActionData::*;
+use self::Actor::*;
+
+fn client_id() -> ClientId {
+    ClientId::Domain("me.test".parse().unwrap())
+}
+
+#[test]
+fn sends_ehlo_only_after_the_full_greeting() {
+    // each server action is delivered separately, so the
+    // greeting arrives in chunks (split mid-line and between lines)
+    let io: Io = MockSocket::new(vec![
+        (Server, Blob(b"220-they.test ESMTP gree".to_vec())),
+        (Server, Blob(b"ting you\r\n220-please be pat".to_vec())),
+        (Server, Blob(b"ient\r\n".to_vec())),
+        (Server, Lines(vec!["220 they.test ready"])),
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250-they.test", "250 SMTPUTF8"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ])
+    .into();
+
+    let con = Connection::_setup(io, client_id(), SetupOptions::default())
+        .wait()
+        .unwrap();
+    assert!(con.has_capability("SMTPUTF8"));
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn can_send_ehlo_before_reading_the_greeting() {
+    let io: Io = MockSocket::new(vec![
+        (Client, Lines(vec!["EHLO me.test"])),
+        (
+            Server,
+            Lines(vec!["220 they.test ready", "250-they.test", "250 SMTPUTF8"]),
+        ),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ])
+    .into();
+
+    let opts = SetupOptions {
+        ehlo_before_greeting: true,
+        ..Default::default()
+    };
+    let con = Connection::_setup(io, client_id(), opts).wait().unwrap();
+    assert!(con.has_capability("SMTPUTF8"));
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn early_ehlo_falls_back_to_normal_ehlo_if_rejected() {
+    let io: Io = MockSocket::new(vec![
+        (Client, Lines(vec!["EHLO me.test"])),
+        (
+            Server,
+            Lines(vec![
+                "220 they.test ready",
+                "503 5.5.1 wait for the greeting",
+            ]),
+        ),
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250-they.test", "250 SMTPUTF8"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ])
+    .into();
+
+    let opts = SetupOptions {
+        ehlo_before_greeting: true,
+        ..Default::default()
+    };
+    let con = Connection::_setup(io, client_id(), opts).wait().unwrap();
+    assert!(con.has_capability("SMTPUTF8"));
+    con.quit().wait().unwrap();
+}
+
+fn setup_with_ehlo_domain(domain: &'static str, rest: Vec<(Actor, ActionData)>) -> Connection {
+    let mut conv = vec![
+        (Server, Lines(vec!["220 ready"])),
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec![domain])),
+    ];
+    conv.extend(rest);
+    let io: Io = MockSocket::new(conv).into();
+    Connection::_setup(io, client_id(), SetupOptions::default())
+        .wait()
+        .unwrap()
+}
+
+#[test]
+fn affinity_check_fails_if_reconnect_reaches_another_server() {
+    let quit = || {
+        vec![
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ]
+    };
+    let first = setup_with_ehlo_domain("250 backend1.test", quit());
+    let identity = ServerIdentity::of(&first).unwrap();
+    assert_eq!(identity.domain().as_str(), "backend1.test");
+    first.quit().wait().unwrap();
+
+    let same = setup_with_ehlo_domain("250 BACKEND1.test", quit());
+    let same = identity.verify(same).wait().unwrap();
+    same.quit().wait().unwrap();
+
+    let other = setup_with_ehlo_domain("250 backend2.test", quit());
+    match identity.verify(other).wait() {
+        Err(ConnectingFailed::Setup(LogicError::Custom(err))) => {
+            let err = err.downcast_ref::<AffinityMismatch>().unwrap();
+            assert_eq!(err.expected().as_str(), "backend1.test");
+            assert_eq!(err.found().unwrap().as_str(), "backend2.test");
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecordEvents(Mutex<Vec<String>>);
+
+impl EventSubscriber for RecordEvents {
+    fn on_event(&self, event: &ConnectionEvent) {
+        self.0.lock().unwrap().push(format!("{:?}", event));
+    }
+}
+
+#[test]
+fn emits_lifecycle_events() {
+    let io: Io = MockSocket::new(vec![
+        (Server, Lines(vec!["220 they.test ready"])),
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250-they.test", "250 SMTPUTF8"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ])
+    .into();
+
+    let events = Arc::new(RecordEvents::default());
+    let opts = SetupOptions {
+        event_subscriber: Some(events.clone()),
+        ..Default::default()
+    };
+    Connection::_setup(io, client_id(), opts)
+        .and_then(|con| con.send(Noop).map_err(ConnectingFailed::Io))
+        .and_then(|(con, result)| {
+            result.unwrap();
+            con.quit().map_err(ConnectingFailed::Io)
+        })
+        .wait()
+        .unwrap();
+
+    assert_eq!(
+        *events.0.lock().unwrap(),
+        vec![
+            "Connected",
+            "GreetingReceived",
+            "EhloCompleted",
+            "Closed(None)"
+        ]
+    );
+}
+
+/// starts a server on localhost which sends the greeting and answers EHLO
+fn greeting_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        serve_greeting_and_ehlo(stream);
+    });
+    port
+}
+
+fn serve_greeting_and_ehlo(stream: TcpStream) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    writer.write_all(b"220 they.test ready\r\n").unwrap();
+    let mut line = String::new();
+    for response in &["250 they.test", "250 ok"] {
+        line.clear();
+        if reader.read_line(&mut line).unwrap() == 0 {
+            return;
+        }
+        writer.write_all(response.as_bytes()).unwrap();
+        writer.write_all(b"\r\n").unwrap();
+    }
+}
+
+fn greeting_timeout_opts() -> SetupOptions {
+    SetupOptions {
+        greeting_timeout: Some(Duration::from_secs(10)),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn greeting_timeout_allows_slow_greetings() {
+    let clock = MockClock::new();
+    let io: Io = MockSocket::new(vec![
+        (Server, Lines(vec!["220 they.test ready"])),
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250 they.test"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ])
+    .with_response_delay(Duration::from_secs(5))
+    .with_mock_clock(clock.clone())
+    .into();
+
+    let mut runtime = Builder::new().clock(clock.to_clock()).build().unwrap();
+    let con = runtime
+        .block_on(Connection::_setup(io, client_id(), greeting_timeout_opts()))
+        .unwrap();
+    runtime.block_on(con.quit()).unwrap();
+}
+
+#[test]
+fn enables_keepalive_on_the_tcp_stream() {
+    let port = greeting_server();
+    let connecting = ConnectionConfig::builder_local_unencrypted()
+        .port(port)
+        .client_id(client_id())
+        .keepalive(Duration::from_secs(120))
+        .connect();
+
+    let mut runtime = Runtime::new().unwrap();
+    let con = runtime.block_on(connecting).unwrap();
+    let io = con.into_inner();
+    let stream = io.socket().tcp_stream().unwrap();
+    assert_eq!(stream.keepalive().unwrap(), Some(Duration::from_secs(120)));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sets_the_keepalive_probe_interval() {
+    let port = greeting_server();
+    let connecting = ConnectionConfig::builder_local_unencrypted()
+        .port(port)
+        .client_id(client_id())
+        .keepalive(Keepalive::new(Duration::from_secs(120)).with_interval(Duration::from_secs(15)))
+        .connect();
+
+    let mut runtime = Runtime::new().unwrap();
+    let con = runtime.block_on(connecting).unwrap();
+    let io = con.into_inner();
+    let socket = SockRef::from(io.socket().tcp_stream().unwrap());
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(120));
+    assert_eq!(
+        socket.keepalive_interval().unwrap(),
+        Duration::from_secs(15)
+    );
+}
+
+#[test]
+fn connect_with_greeting_returns_the_banner() {
+    let port = greeting_server();
+    let config = ConnectionConfig::builder_local_unencrypted()
+        .port(port)
+        .client_id(client_id())
+        .build();
+
+    let mut runtime = Runtime::new().unwrap();
+    let (con, greeting) = runtime
+        .block_on(Connection::connect_with_greeting(
+            config,
+            SetupOptions::default(),
+        ))
+        .unwrap();
+    assert_eq!(greeting.code(), codes::READY);
+    assert_eq!(greeting.msg(), ["they.test ready"]);
+    assert_eq!(con.greeting().unwrap().msg(), greeting.msg());
+}
+
+/// answers each line read from `stream` with the next of `responses`
+fn serve_lines<S: Read + Write>(stream: S, responses: &[&str]) -> S {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    for response in responses {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        let writer = reader.get_mut();
+        writer.write_all(response.as_bytes()).unwrap();
+        writer.write_all(b"\r\n").unwrap();
+    }
+    reader.into_inner()
+}
+
+/// starts a server on localhost which upgrades to tls (self-signed) on STARTTLS
+fn starttls_server() -> u16 {
+    let acceptor = self_signed_acceptor();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.write_all(b"220 they.test ready\r\n").unwrap();
+        let stream = serve_lines(stream, &["250-they.test\r\n250 STARTTLS", "220 go ahead"]);
+        let stream = acceptor.accept(stream).unwrap();
+        // EHLO, the NOOP used as "no auth" and QUIT
+        let responses = ["250-they.test\r\n250 SIZE 1024", "250 ok", "221 bye"];
+        let mut stream = serve_lines(stream, &responses);
+        let _ = stream.shutdown();
+    });
+    port
+}
+
+#[test]
+fn greeting_survives_starttls() {
+    let addr = SocketAddr::from(([127, 0, 0, 1], starttls_server()));
+    let config = ConnectionBuilder::new_with_addr(addr, "localhost".parse().unwrap())
+        .use_tls_setup(Permissive(DefaultTlsSetup))
+        .use_start_tls()
+        .client_id(client_id())
+        .build();
+
+    let mut runtime = Runtime::new().unwrap();
+    let (con, greeting) = runtime
+        .block_on(Connection::connect_with_greeting(
+            config,
+            SetupOptions::default(),
+        ))
+        .unwrap();
+    // the EHLO after STARTTLS was used
+    assert!(con.has_capability("SIZE"));
+    assert_eq!(greeting.msg(), ["they.test ready"]);
+    assert_eq!(con.greeting().unwrap().msg(), greeting.msg());
+    let socket = runtime.block_on(con.quit()).unwrap();
+    assert!(socket.is_secure());
+}
+
+#[test]
+fn greeting_timeout_fails_connecting_if_elapsed() {
+    let clock = MockClock::new();
+    // the server never sends a greeting
+    let io: Io = MockSocket::new_no_check_shutdown(vec![]).into();
+
+    // the deadline is set when the future is created
+    let setup = {
+        let _guard = clock.set_default();
+        Connection::_setup(io, client_id(), greeting_timeout_opts())
+    };
+    clock.advance(Duration::from_secs(11));
+
+    let mut runtime = Builder::new().clock(clock.to_clock()).build().unwrap();
+    match runtime.block_on(setup) {
+        Err(ConnectingFailed::Io(err)) => {
+            assert_eq!(err.kind(), std_io::ErrorKind::TimedOut);
+            match ConnectError::from_io_error(&err) {
+                Some(ConnectError::Timeout) => (),
+                other => panic!("unexpected error: {:?}", other),
+            }
+        }
+        other => panic!("unexpected result: {:?}", other.map(|_| ())),
+    }
+}
+
+fn self_signed_acceptor() -> native_tls::TlsAcceptor {
+    let cert = include_bytes!("tls/self_signed.crt");
+    let key = include_bytes!("tls/self_signed.key");
+    let identity = native_tls::Identity::from_pkcs8(cert, key).unwrap();
+    native_tls::TlsAcceptor::new(identity).unwrap()
+}
+
+/// starts a tls server on localhost using a self-signed certificate
+fn self_signed_tls_server() -> u16 {
+    let acceptor = self_signed_acceptor();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        // the client is expected to abort the handshake
+        let _ = acceptor.accept(stream);
+    });
+    port
+}
+
+#[test]
+fn untrusted_certificate_is_a_tls_handshake_error() {
+    let port = self_signed_tls_server();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let config = TlsConfig::secure("localhost".parse().unwrap());
+
+    let mut runtime = Runtime::new().unwrap();
+    let err = runtime
+        .block_on(Io::connect_secure(&addr, config))
+        .map(|_| ())
+        .unwrap_err();
+
+    match ConnectError::from_io_error(&err) {
+        Some(err @ ConnectError::TlsHandshake(_)) => assert!(!err.is_transient()),
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+/// a setup making the builder accept any certificate, hostname and protocol
+/// version before handing it to the wrapped setup
+#[derive(Debug)]
+struct Permissive<S>(S);
+
+impl<S: SetupTls> SetupTls for Permissive<S> {
+    fn setup(
+        self,
+        mut builder: native_tls::TlsConnectorBuilder,
+    ) -> Result<native_tls::TlsConnector, native_tls::Error> {
+        builder
+            .min_protocol_version(None)
+            .danger_accept_invalid_certs(true)
+            .danger_accept_invalid_hostnames(true);
+        self.0.setup(builder)
+    }
+}
+
+#[test]
+fn permissive_builder_accepts_self_signed_certificate() {
+    // counterpart of the next test, showing the server is usable
+    // if certificate and hostname validation is disabled
+    let port = self_signed_tls_server();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let config = TlsConfig {
+        domain: "localhost".parse().unwrap(),
+        setup: Permissive(DefaultTlsSetup),
+    };
+
+    let mut runtime = Runtime::new().unwrap();
+    let io = runtime.block_on(Io::connect_secure(&addr, config)).unwrap();
+    assert!(io.is_secure());
+}
+
+#[test]
+fn secure_tls_setup_overrides_a_permissive_builder() {
+    let port = self_signed_tls_server();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let config = TlsConfig::secure("localhost".parse().unwrap());
+    assert_eq!(config.setup, SecureTlsSetup);
+    let config = TlsConfig {
+        domain: config.domain,
+        setup: Permissive(config.setup),
+    };
+
+    let mut runtime = Runtime::new().unwrap();
+    let err = runtime
+        .block_on(Io::connect_secure(&addr, config))
+        .map(|_| ())
+        .unwrap_err();
+
+    match ConnectError::from_io_error(&err) {
+        Some(ConnectError::TlsHandshake(_)) => (),
+        other => panic!("unexpected error: {:?}", other),
+    }
+}
+
+#[test]
+fn reused_tls_connector_can_connect_multiple_times() {
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    let setup = ReuseTlsConnector::new(connector);
+
+    let mut runtime = Runtime::new().unwrap();
+    for _ in 0..2 {
+        let addr = SocketAddr::from(([127, 0, 0, 1], self_signed_tls_server()));
+        let config = TlsConfig {
+            domain: "localhost".parse().unwrap(),
+            setup: setup.clone(),
+        };
+        let io = runtime.block_on(Io::connect_secure(&addr, config)).unwrap();
+        assert!(io.is_secure());
+    }
+}
+
+#[test]
+fn refused_connection_is_a_tcp_error() {
+    let port = {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let config = ConnectionConfig::builder_local_unencrypted()
+        .port(port)
+        .client_id(client_id())
+        .build();
+
+    let mut runtime = Runtime::new().unwrap();
+    let err = runtime
+        .block_on(Connection::connect(config))
+        .map(|_| ())
+        .unwrap_err();
+
+    match err.connect_error() {
+        Some(ConnectError::Tcp(cause)) => {
+            assert_eq!(cause.kind(), std_io::ErrorKind::ConnectionRefused)
+        }
+        other => panic!("unexpected error: {:?}", other),
+    }
+    assert!(err.connect_error().unwrap().is_transient());
+}
+
+/// starts a server on localhost which rejects the connection in the greeting
+fn rejecting_greeting_server() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = stream.write_all(b"554 they.test go away\r\n");
+        // the client might send QUIT, or just close the connection
+        let mut line = String::new();
+        let _ = BufReader::new(stream).read_line(&mut line);
+    });
+    port
+}
+
+fn refused_port() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+#[test]
+fn connect_to_first_uses_the_first_usable_candidate() {
+    let candidates = vec![
+        SocketAddr::from(([127, 0, 0, 1], refused_port())),
+        SocketAddr::from(([127, 0, 0, 1], rejecting_greeting_server())),
+        SocketAddr::from(([127, 0, 0, 1], greeting_server())),
+    ];
+    let config = ConnectionConfig::builder_local_unencrypted()
+        .client_id(client_id())
+        .build();
+
+    let mut runtime = Runtime::new().unwrap();
+    let con = runtime
+        .block_on(Connection::connect_to_first(
+            config,
+            SetupOptions::default(),
+            candidates,
+        ))
+        .unwrap();
+    assert_eq!(con.greeting().unwrap().msg(), ["they.test ready"]);
+}
+
+#[test]
+fn connect_to_first_lists_the_failure_of_each_candidate() {
+    let candidates = vec![
+        SocketAddr::from(([127, 0, 0, 1], refused_port())),
+        SocketAddr::from(([127, 0, 0, 1], rejecting_greeting_server())),
+    ];
+    let config = ConnectionConfig::builder_local_unencrypted()
+        .client_id(client_id())
+        .build();
+
+    let mut runtime = Runtime::new().unwrap();
+    let err: AllCandidatesFailed = runtime
+        .block_on(Connection::connect_to_first(
+            config,
+            SetupOptions::default(),
+            candidates.clone(),
+        ))
+        .map(|_| ())
+        .unwrap_err();
+
+    let failures = err.failures();
+    assert_eq!(failures.len(), 2);
+    assert_eq!(failures[0].0, candidates[0]);
+    assert!(failures[0].1.connect_error().is_some());
+    assert_eq!(failures[1].0, candidates[1]);
+    match &failures[1].1 {
+        ConnectingFailed::Setup(LogicError::Code(response)) => {
+            assert_eq!(response.code(), codes::TRANSACTION_FAILED)
+        }
+        other => panic!("unexpected failure: {:?}", other),
+    }
+}
+
+/// starts a server on localhost which accepts any number of connections and counts them
+fn counting_server() -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let stream = stream.unwrap();
+            thread::spawn(move || serve_greeting_and_ehlo(stream));
+        }
+    });
+    (port, accepted)
+}
+
+#[test]
+fn destination_limiter_delays_connecting_until_a_permit_is_released() {
+    let (port, accepted) = counting_server();
+    let config = ConnectionConfig::builder_local_unencrypted()
+        .port(port)
+        .client_id(client_id())
+        .build();
+    let addr = config.addr;
+    let limiter = DestinationLimiter::new(1);
+
+    let mut runtime = Runtime::new().unwrap();
+    let (first, permit) = runtime
+        .block_on(limiter.connect(config.clone(), SetupOptions::default()))
+        .unwrap();
+    assert_eq!(limiter.active(&addr), 1);
+
+    let mut second = limiter.connect(config, SetupOptions::default());
+    let ready = runtime
+        .block_on(future::lazy(|| {
+            Ok::<_, ()>(second.poll().map(|res| res.is_ready()))
+        }))
+        .unwrap();
+    assert!(!ready.unwrap());
+    assert_eq!(limiter.waiting(&addr), 1);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+    drop(first);
+    drop(permit);
+    let (_second, permit) = runtime.block_on(second).unwrap();
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    assert_eq!(permit.destination(), &addr);
+    assert_eq!(limiter.active(&addr), 1);
+    assert_eq!(limiter.waiting(&addr), 0);
+}
+
+#[test]
+fn destination_limiter_connects_with_greeting() {
+    let config = ConnectionConfig::builder_local_unencrypted()
+        .port(greeting_server())
+        .client_id(client_id())
+        .build();
+    let addr = config.addr;
+    let limiter = DestinationLimiter::new(1);
+
+    let mut runtime = Runtime::new().unwrap();
+    let (con, greeting, permit) = runtime
+        .block_on(limiter.connect_with_greeting(config, SetupOptions::default()))
+        .unwrap();
+    assert_eq!(greeting.msg(), ["they.test ready"]);
+    assert_eq!(permit.destination(), &addr);
+    assert_eq!(limiter.active(&addr), 1);
+
+    drop(con);
+    drop(permit);
+    assert_eq!(limiter.active(&addr), 0);
+}
@@ -0,0 +1,441 @@
+use std::io::{self as std_io, Cursor};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures::{future, stream, Future};
+
+use new_tokio_smtp::error::{
+    BodyUploadFailed, LineBreakInCommand, LogicError, TlsSessionFailed, UnexpectedResponse,
+    UploadInterruption, WriteQuotaExceeded,
+};
+use new_tokio_smtp::io::{BufferConfig, IoParts, TlsInfo};
+use new_tokio_smtp::mock::{ActionData, Actor, MockClock, MockSocket};
+use new_tokio_smtp::response::{codes, ExpectedCode};
+use new_tokio_smtp::{command, ClientId, CloseReason, Connection, ForwardPath, Io, ReversePath};
+
+use self::ActionData::*;
+use self::Actor::*;
+
+use super::{mock, mock_no_shutdown, ScriptedStream};
+
+#[test]
+fn detects_unsolicited_421_while_idle() {
+    let mut con = mock(vec![(
+        Server,
+        Lines(vec![
+            "421-they.test idle for too long",
+            "421 closing connection",
+        ]),
+    )]);
+
+    let response = future::poll_fn(|| con.poll_unsolicited()).wait().unwrap();
+    let response = response.expect("connection should not be closed without response");
+    assert_eq!(response.code(), codes::SERVICE_UNAVAILABLE);
+    assert_eq!(response.msg().len(), 2);
+
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn lines_are_only_split_at_crlf() {
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (
+            Server,
+            Blob(b"250-said \"250 done\"\n250 not a line\r\n250-also 250-".to_vec()),
+        ),
+        (Server, Blob(b"\r\n250 end \"250-more\"\r\n".to_vec())),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+    let response = result.unwrap();
+    assert_eq!(
+        response.msg(),
+        &[
+            "said \"250 done\"\n250 not a line",
+            "also 250-",
+            "end \"250-more\"",
+        ]
+    );
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn utf8_split_over_multiple_reads_is_decoded_correctly() {
+    // "grüße" with both "ü" and "ß" split between two reads
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Blob(b"250-gr\xc3".to_vec())),
+        (Server, Blob(b"\xbc\xc3".to_vec())),
+        (Server, Blob(b"\x9fe\r\n250 ok\r\n".to_vec())),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+    assert_eq!(result.unwrap().msg(), &["grüße", "ok"]);
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn into_parts_includes_tls_info_of_secure_connections() {
+    let info = TlsInfo::new(Some(b"fake der".to_vec()), None);
+    let socket = MockSocket::new(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ])
+    .with_tls_info(info.clone());
+    let con = Connection::from(Io::from(socket));
+
+    let (con, result) = con.send(command::Noop).wait().unwrap();
+    result.unwrap();
+
+    let IoParts {
+        socket,
+        buffers,
+        ehlo_data,
+        tls_info,
+    } = con.into_parts();
+    assert_eq!(tls_info, Some(info));
+    assert_eq!(tls_info.unwrap().peer_certificate(), Some(&b"fake der"[..]));
+
+    let con = Connection::from(Io::from((socket, buffers, ehlo_data)));
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn into_parts_has_no_tls_info_for_plain_connections() {
+    let con = mock(vec![]);
+    let parts = con.into_parts();
+    assert_eq!(parts.tls_info, None);
+    Connection::from(parts.socket).shutdown().wait().unwrap();
+}
+
+#[test]
+fn maps_tls_errors_mid_session() {
+    // tls errors are reported with `ErrorKind::Other`
+    let stream = ScriptedStream::new()
+        .secure()
+        .fail_reads(std_io::ErrorKind::Other);
+    let io = Io::from(stream);
+    let con = Connection::from(io);
+
+    let err = con.send(command::Noop).wait().unwrap_err();
+    assert_eq!(err.kind(), std_io::ErrorKind::Other);
+    let tls_err = TlsSessionFailed::from_io_error(&err).expect("tls error not mapped");
+    assert_eq!(tls_err.tls_error().kind(), std_io::ErrorKind::Other);
+}
+
+#[test]
+fn exec_expect_returns_matching_response() {
+    let con = mock(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+
+    let (con, res) = con
+        .exec_expect("NOOP", ExpectedCode::Exact(codes::OK))
+        .wait()
+        .unwrap();
+    assert_eq!(res.unwrap().code(), codes::OK);
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn exec_expect_fails_on_mismatch() {
+    let con = mock(vec![
+        (Client, Lines(vec!["XFOO"])),
+        (Server, Lines(vec!["500 unknown command"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+
+    let (con, res) = con
+        .exec_expect("XFOO", ExpectedCode::Exact(codes::OK))
+        .wait()
+        .unwrap();
+    match res {
+        Err(LogicError::Custom(err)) => {
+            let err = err.downcast_ref::<UnexpectedResponse>().unwrap();
+            assert_eq!(err.expected(), ExpectedCode::Exact(codes::OK));
+            assert_eq!(err.response().code(), codes::SYNTAX_ERROR);
+            assert_eq!(
+                err.to_string(),
+                "expected response code 250 but got 500 \"unknown command\""
+            );
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn raw_rejects_line_breaks() {
+    // nothing but the QUIT may be written
+    let con = mock(vec![
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+
+    let (con, res) = con
+        .send(command::Raw {
+            line: "NOOP\r\nRCPT TO:<evil@test.test>".to_owned(),
+        })
+        .wait()
+        .unwrap();
+    match res {
+        Err(LogicError::Custom(err)) => assert!(err.is::<LineBreakInCommand>()),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let (con, res) = con
+        .exec_expect("NOOP\nNOOP", ExpectedCode::Class(2))
+        .wait()
+        .unwrap();
+    match res {
+        Err(LogicError::Custom(err)) => assert!(err.is::<LineBreakInCommand>()),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn quit_fast_does_not_read_the_response() {
+    // reading would panic as the conversation is over after QUIT
+    let con = mock(vec![(Client, Lines(vec!["QUIT"]))]);
+    con.quit_fast().wait().unwrap();
+}
+
+#[test]
+fn reports_bytes_sent_before_body_upload_failed() {
+    let io = Io::from(ScriptedStream::new().reset_writes_after(20));
+    let chunks = vec!["line 1\r\n", "line 2\r\n", "line 3\r\n"];
+    let body = stream::iter_ok(chunks.into_iter().map(Cursor::new));
+
+    let err = io.write_dot_stashed(body).wait().unwrap_err();
+    assert_eq!(err.kind(), std_io::ErrorKind::ConnectionReset);
+    let upload_err = BodyUploadFailed::from_io_error(&err).expect("error not mapped");
+    assert_eq!(upload_err.bytes_sent(), 16);
+    assert_eq!(upload_err.interruption(), UploadInterruption::Reset);
+}
+
+#[test]
+fn tls_errors_during_body_upload_are_no_reset() {
+    let stream = ScriptedStream::new()
+        .secure()
+        .fail_writes_after(20, std_io::ErrorKind::Other);
+    let io = Io::from(stream);
+    let chunks = vec!["line 1\r\n", "line 2\r\n", "line 3\r\n"];
+    let body = stream::iter_ok(chunks.into_iter().map(Cursor::new));
+
+    let err = io.write_dot_stashed(body).wait().unwrap_err();
+    let upload_err = BodyUploadFailed::from_io_error(&err).expect("error not mapped");
+    assert_eq!(upload_err.interruption(), UploadInterruption::Other);
+    assert!(TlsSessionFailed::from_io_error(upload_err.io_error()).is_some());
+}
+
+#[test]
+fn rejected_recipients_do_not_count_as_too_many_errors() {
+    let mut conv = vec![
+        (Client, Lines(vec!["MAIL FROM:<a@test.test>"])),
+        (Server, Lines(vec!["250 ok"])),
+    ];
+    for _ in 0..3 {
+        conv.push((Client, Lines(vec!["RCPT TO:<unknown@test.test>"])));
+        conv.push((Server, Lines(vec!["550 5.1.1 no such user"])));
+    }
+    conv.push((Client, Lines(vec!["NOOP"])));
+    conv.push((Server, Lines(vec!["421 4.4.2 they.test idle timeout"])));
+    let con = mock(conv);
+
+    let rcpt = || command::Recipient::new(ForwardPath::from_unchecked("unknown@test.test"));
+    let fut = con
+        .send(command::Mail::new(ReversePath::from_unchecked(
+            "a@test.test",
+        )))
+        .and_then(move |(con, _)| con.send(rcpt()))
+        .and_then(move |(con, _)| con.send(rcpt()))
+        .and_then(move |(con, _)| con.send(rcpt()))
+        .and_then(|(con, _)| {
+            assert_eq!(con.consecutive_errors(), 0);
+            con.send(command::Noop)
+        });
+    let (con, res) = fut.wait().unwrap();
+
+    let response = match res {
+        Err(LogicError::Code(response)) => response,
+        other => panic!("unexpected result: {:?}", other),
+    };
+    let reason = con.close_reason(Some(response));
+    assert!(!reason.is_terminal());
+    match reason {
+        CloseReason::ServiceUnavailable(response) => {
+            assert_eq!(response.code(), codes::SERVICE_UNAVAILABLE)
+        }
+        other => panic!("unexpected close reason: {:?}", other),
+    }
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn detects_server_giving_up_after_too_many_errors() {
+    let mut conv = Vec::new();
+    for _ in 0..3 {
+        conv.push((Client, Lines(vec!["XFOO"])));
+        conv.push((Server, Lines(vec!["500 5.5.2 unknown command"])));
+    }
+    conv.push((Client, Lines(vec!["NOOP"])));
+    conv.push((Server, Lines(vec!["421 4.7.0 they.test closing"])));
+    let con = mock(conv);
+
+    let raw = || command::Raw {
+        line: "XFOO".to_owned(),
+    };
+    let fut = con
+        .send(raw())
+        .and_then(move |(con, _)| con.send(raw()))
+        .and_then(move |(con, _)| con.send(raw()))
+        .and_then(|(con, _)| {
+            assert_eq!(con.consecutive_errors(), 3);
+            con.send(command::Noop)
+        });
+    let (con, res) = fut.wait().unwrap();
+
+    let response = match res {
+        Err(LogicError::Code(response)) => response,
+        other => panic!("unexpected result: {:?}", other),
+    };
+    let reason = con.close_reason(Some(response));
+    assert!(reason.is_terminal());
+    match reason {
+        CloseReason::TooManyErrors {
+            consecutive_errors, ..
+        } => assert_eq!(consecutive_errors, 3),
+        other => panic!("unexpected close reason: {:?}", other),
+    }
+    con.shutdown().wait().unwrap();
+}
+
+#[test]
+fn idle_421_is_not_terminal() {
+    let mut con = mock(vec![(Server, Lines(vec!["421 4.4.2 idle for too long"]))]);
+
+    let response = future::poll_fn(|| con.poll_unsolicited()).wait().unwrap();
+    let reason = con.close_reason(response);
+    assert!(!reason.is_terminal());
+    con.shutdown().wait().unwrap();
+}
+
+/// sends NOOP answered with a ~8KiB multi-line response and returns the number of reads
+fn reads_for_large_response(config: BufferConfig) -> usize {
+    let mut input = Vec::new();
+    for _ in 0..100 {
+        input.extend_from_slice(b"250-");
+        input.extend_from_slice(&[b'x'; 76]);
+        input.extend_from_slice(b"\r\n");
+    }
+    input.extend_from_slice(b"250 ok\r\n");
+
+    let stream = ScriptedStream::new().input(input);
+    let reads = stream.read_counter();
+    let mut io = Io::from(stream);
+    io.set_buffer_config(config);
+
+    let (_con, result) = Connection::from(io).send(command::Noop).wait().unwrap();
+    let response = result.unwrap();
+    assert_eq!(response.msg().len(), 101);
+    reads.load(Ordering::SeqCst)
+}
+
+#[test]
+fn larger_input_buffer_needs_less_reads() {
+    let default_reads = reads_for_large_response(BufferConfig::default());
+    let tuned_reads = reads_for_large_response(BufferConfig {
+        input_capacity: 16 * 1024,
+        input_increment: 4 * 1024,
+    });
+
+    assert_eq!(tuned_reads, 1);
+    assert!(
+        default_reads > tuned_reads,
+        "default reads: {}",
+        default_reads
+    );
+}
+
+#[test]
+fn records_the_lines_send_by_the_client() {
+    let mut socket = MockSocket::new(vec![
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250 they.test"])),
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+    let sent = socket.record_sent();
+    let con = Connection::from(Io::from(socket));
+
+    let client_id = ClientId::Domain("me.test".parse().unwrap());
+    con.send(command::Ehlo::from(client_id))
+        .and_then(|(con, _)| con.send(command::Noop))
+        .and_then(|(con, _)| con.quit())
+        .wait()
+        .unwrap();
+
+    assert_eq!(sent.sent_lines(), ["EHLO me.test", "NOOP", "QUIT"]);
+}
+
+#[test]
+fn body_exceeding_the_write_quota_fails_and_closes_the_connection() {
+    // the body is never written, so the conversation ends after the 354
+    let mut con = mock_no_shutdown(vec![
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 go ahead"])),
+    ]);
+    con.set_write_quota(Some(20));
+
+    let body = "a body longer than the quota\r\n";
+    let err = con
+        .send(command::Data::from_buf(body))
+        .wait()
+        .map(|_| ())
+        .unwrap_err();
+
+    let quota_err = WriteQuotaExceeded::from_io_error(&err).expect("quota error not found");
+    assert_eq!(quota_err.quota(), 20);
+    assert_eq!(quota_err.bytes_written(), "DATA\r\n".len());
+}
+
+#[test]
+fn records_the_think_time_of_the_server() {
+    let delay = Duration::from_secs(3);
+    let clock = MockClock::new();
+    let _guard = clock.set_default();
+    let socket = MockSocket::new(vec![
+        (Client, Lines(vec!["NOOP"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ])
+    .with_response_delay(delay)
+    .with_mock_clock(clock);
+    let mut con = Connection::from(Io::from(socket));
+    con.set_record_timing(true);
+
+    let (con, res) = con.send(command::Noop).wait().unwrap();
+    res.unwrap();
+
+    let timing = con.last_command_timing().unwrap();
+    assert_eq!(timing.verb, "NOOP");
+    assert_eq!(timing.think_time, Some(delay));
+    assert_eq!(timing.total, delay);
+
+    con.quit().wait().unwrap();
+}
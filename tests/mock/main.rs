@@ -11,13 +11,23 @@ extern crate futures;
 extern crate vec1;
 
 use std::collections::HashMap;
+use std::io::{self as std_io, Cursor};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
+use futures::{Async, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use new_tokio_smtp::io::{MockStream, Socket};
 use new_tokio_smtp::mock::{ActionData, Actor, MockSocket};
 use new_tokio_smtp::{Capability, Connection, Domain, EhloData, EsmtpKeyword, Io};
 
 mod chain;
 mod command;
+mod connect;
+mod connection;
+mod pipelining;
 #[cfg(feature = "send-mail")]
 mod send_mail;
 
@@ -46,3 +56,109 @@ fn with_capability(con: Connection, cap: &str) -> Connection {
 
     Connection::from(Io::from((socket, buffer, ehlo_data)))
 }
+
+/// a stream for cases `MockSocket` can't simulate (e.g. io errors)
+///
+/// Reads return `input` and then fail with the error set with `fail_reads`,
+/// or with `WouldBlock` if there is none. Writes are accepted until the
+/// limit set with `fail_writes_after` is reached, then they fail with
+/// the given error.
+#[derive(Debug, Default)]
+struct ScriptedStream {
+    input: Cursor<Vec<u8>>,
+    read_error: Option<std_io::ErrorKind>,
+    write_limit: Option<(usize, std_io::ErrorKind)>,
+    reads: Arc<AtomicUsize>,
+    secure: bool,
+}
+
+impl ScriptedStream {
+    fn new() -> Self {
+        Default::default()
+    }
+
+    fn input(mut self, input: Vec<u8>) -> Self {
+        self.input = Cursor::new(input);
+        self
+    }
+
+    fn fail_reads(mut self, kind: std_io::ErrorKind) -> Self {
+        self.read_error = Some(kind);
+        self
+    }
+
+    fn fail_writes_after(mut self, limit: usize, kind: std_io::ErrorKind) -> Self {
+        self.write_limit = Some((limit, kind));
+        self
+    }
+
+    fn reset_writes_after(self, limit: usize) -> Self {
+        self.fail_writes_after(limit, std_io::ErrorKind::ConnectionReset)
+    }
+
+    fn secure(mut self) -> Self {
+        self.secure = true;
+        self
+    }
+
+    /// counter of the (non empty) reads
+    fn read_counter(&self) -> Arc<AtomicUsize> {
+        self.reads.clone()
+    }
+}
+
+impl std_io::Read for ScriptedStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, std_io::Error> {
+        let read = std_io::Read::read(&mut self.input, buf)?;
+        if read == 0 {
+            return Err(match self.read_error {
+                Some(kind) => std_io::Error::new(kind, "scripted read failure"),
+                None => std_io::ErrorKind::WouldBlock.into(),
+            });
+        }
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        Ok(read)
+    }
+}
+
+impl AsyncRead for ScriptedStream {}
+
+impl std_io::Write for ScriptedStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std_io::Error> {
+        match self.write_limit.as_mut() {
+            Some((0, kind)) => Err(std_io::Error::new(*kind, "scripted write failure")),
+            Some((remaining, _)) => {
+                let len = buf.len().min(*remaining);
+                *remaining -= len;
+                Ok(len)
+            }
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), std_io::Error> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for ScriptedStream {
+    fn shutdown(&mut self) -> Poll<(), std_io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl MockStream for ScriptedStream {
+    fn is_secure(&self) -> bool {
+        self.secure
+    }
+
+    fn set_is_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+}
+
+impl From<ScriptedStream> for Io {
+    fn from(stream: ScriptedStream) -> Self {
+        Io::from(Socket::Mock(Box::new(stream)))
+    }
+}
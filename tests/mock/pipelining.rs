@@ -0,0 +1,263 @@
+use futures::Future;
+
+use new_tokio_smtp::chain::OnError;
+use new_tokio_smtp::error::LogicError;
+use new_tokio_smtp::mock::{ActionData, Actor};
+use new_tokio_smtp::pipelining::{pipeline, pipeline_chunks, MustBeLast, PipelinableCmd};
+use new_tokio_smtp::response::codes;
+use new_tokio_smtp::{command, ForwardPath, ReversePath};
+
+use self::ActionData::*;
+use self::Actor::*;
+
+use super::{mock, with_capability};
+
+#[test]
+fn drains_all_responses_and_resets_on_failure() {
+    let con = mock(vec![
+        (
+            Client,
+            Lines(vec![
+                "MAIL FROM:<test@sender.test>",
+                "RCPT TO:<bad@receiver.test>",
+                "RCPT TO:<good@receiver.test>",
+                "DATA",
+            ]),
+        ),
+        (
+            Server,
+            Lines(vec![
+                "250 ok",
+                "550 no such user",
+                "250 ok",
+                "554 no valid recipients",
+            ]),
+        ),
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["250 ok"])),
+    ]);
+    let con = with_capability(con, "PIPELINING");
+
+    let cmds: Vec<Box<dyn PipelinableCmd>> = vec![
+        Box::new(command::Mail::new(ReversePath::from_unchecked(
+            "test@sender.test",
+        ))),
+        Box::new(command::Recipient::new(ForwardPath::from_unchecked(
+            "bad@receiver.test",
+        ))),
+        Box::new(command::Recipient::new(ForwardPath::from_unchecked(
+            "good@receiver.test",
+        ))),
+        Box::new(command::Data::from_buf("body\r\n")),
+    ];
+
+    let fut = pipeline(con, cmds, OnError::StopAndReset).and_then(|(con, res)| {
+        let results = res.unwrap();
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert!(results[2].is_ok());
+        assert!(results[3].is_err());
+        con.shutdown()
+    });
+
+    fut.wait().unwrap();
+}
+
+#[test]
+fn sends_the_body_only_after_the_354() {
+    // the mock enforces the order, i.e. the body has to be written after
+    // all responses (including the 354) were read
+    let con = mock(vec![
+        (
+            Client,
+            Lines(vec![
+                "MAIL FROM:<test@sender.test>",
+                "RCPT TO:<good@receiver.test>",
+                "DATA",
+            ]),
+        ),
+        (Server, Lines(vec!["250 ok", "250 ok", "354 go ahead"])),
+        (Client, Lines(vec!["body", "."])),
+        (Server, Lines(vec!["250 queued"])),
+    ]);
+    let con = with_capability(con, "PIPELINING");
+
+    let cmds: Vec<Box<dyn PipelinableCmd>> = vec![
+        Box::new(command::Mail::new(ReversePath::from_unchecked(
+            "test@sender.test",
+        ))),
+        Box::new(command::Recipient::new(ForwardPath::from_unchecked(
+            "good@receiver.test",
+        ))),
+        Box::new(command::Data::from_buf("body\r\n")),
+    ];
+
+    let fut = pipeline(con, cmds, OnError::StopAndReset).and_then(|(con, res)| {
+        let results = res.unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2].as_ref().unwrap().msg()[0], "queued");
+        con.shutdown()
+    });
+
+    fut.wait().unwrap();
+}
+
+#[test]
+fn pipelined_mail_fails_with_authentication_required() {
+    use new_tokio_smtp::error::AuthenticationRequired;
+
+    let con = mock(vec![
+        (
+            Client,
+            Lines(vec![
+                "MAIL FROM:<test@sender.test>",
+                "RCPT TO:<good@receiver.test>",
+            ]),
+        ),
+        (
+            Server,
+            Lines(vec![
+                "530 5.7.0 Authentication required",
+                "503 5.5.1 need MAIL first",
+            ]),
+        ),
+    ]);
+    let con = with_capability(con, "PIPELINING");
+
+    let cmds: Vec<Box<dyn PipelinableCmd>> = vec![
+        Box::new(command::Mail::new(ReversePath::from_unchecked(
+            "test@sender.test",
+        ))),
+        Box::new(command::Recipient::new(ForwardPath::from_unchecked(
+            "good@receiver.test",
+        ))),
+    ];
+
+    let fut = pipeline(con, cmds, OnError::Stop).and_then(|(con, res)| {
+        let results = res.unwrap();
+        match results[0] {
+            Err(LogicError::Custom(ref err)) => assert!(err.is::<AuthenticationRequired>()),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+        con.shutdown()
+    });
+
+    fut.wait().unwrap();
+}
+
+#[test]
+fn data_has_to_be_the_last_pipelined_command() {
+    let con = with_capability(mock(vec![]), "PIPELINING");
+
+    let cmds: Vec<Box<dyn PipelinableCmd>> = vec![
+        Box::new(command::Data::from_buf("body\r\n")),
+        Box::new(command::Noop),
+    ];
+
+    let fut = pipeline(con, cmds, OnError::Stop).and_then(|(con, res)| {
+        match res {
+            Err((0, LogicError::Custom(err))) => assert!(err.is::<MustBeLast>()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        con.shutdown()
+    });
+
+    fut.wait().unwrap();
+}
+
+#[test]
+fn needs_pipelining_capability() {
+    let con = with_capability(mock(vec![]), "SIZE");
+
+    let cmds: Vec<Box<dyn PipelinableCmd>> = vec![Box::new(command::Noop)];
+
+    let fut = pipeline(con, cmds, OnError::Stop).and_then(|(con, res)| {
+        match res {
+            Err((0, LogicError::MissingCapabilities(_))) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        con.shutdown()
+    });
+
+    fut.wait().unwrap();
+}
+
+#[test]
+fn pipelining_requires_ehlo() {
+    let con = mock(vec![]);
+
+    let cmds: Vec<Box<dyn PipelinableCmd>> = vec![Box::new(command::Noop)];
+
+    let fut = pipeline(con, cmds, OnError::Stop).and_then(|(con, res)| {
+        match res {
+            Err((0, LogicError::EhloRequired(err))) => {
+                assert_eq!(err.capabilities()[0].as_str(), "PIPELINING")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        con.shutdown()
+    });
+
+    fut.wait().unwrap();
+}
+
+#[test]
+fn pipelined_bdat_chunk_rejected_mid_stream() {
+    let con = mock(vec![
+        (
+            Client,
+            Blob(b"BDAT 6\r\nchunk1BDAT 6\r\nchunk2BDAT 6 LAST\r\nchunk3".to_vec()),
+        ),
+        (
+            Server,
+            Lines(vec![
+                "250 2.0.0 6 octets received",
+                "552 5.3.4 message too big",
+                "503 5.5.1 bad sequence of commands",
+            ]),
+        ),
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["250 ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+    let con = with_capability(con, "PIPELINING");
+    let con = with_capability(con, "CHUNKING");
+
+    let chunks = vec!["chunk1".into(), "chunk2".into(), "chunk3".into()];
+    let (con, res) = pipeline_chunks(con, chunks).wait().unwrap();
+    match res {
+        Err((1, LogicError::Code(response))) => {
+            assert_eq!(response.code(), codes::EXCEEDED_STORAGE_ALLOCATION)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn pipelined_bdat_returns_last_response() {
+    let con = mock(vec![
+        (
+            Client,
+            Blob(b"BDAT 6\r\nchunk1BDAT 6 LAST\r\nchunk2".to_vec()),
+        ),
+        (
+            Server,
+            Lines(vec![
+                "250 2.0.0 6 octets received",
+                "250 2.0.0 queued as XYZ",
+            ]),
+        ),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+    let con = with_capability(con, "PIPELINING");
+    let con = with_capability(con, "CHUNKING");
+
+    let chunks = vec!["chunk1".into(), "chunk2".into()];
+    let (con, res) = pipeline_chunks(con, chunks).wait().unwrap();
+    assert_eq!(res.unwrap().msg()[0], "2.0.0 queued as XYZ");
+    con.quit().wait().unwrap();
+}
@@ -1,7 +1,13 @@
-use futures::Future;
+use futures::{future, Future};
 
+use new_tokio_smtp::command::dsn::Notify;
+use new_tokio_smtp::error::{LogicError, MessageTooLarge};
 use new_tokio_smtp::mock::{ActionData, Actor};
-use new_tokio_smtp::send_mail::{EncodingRequirement, Mail, MailAddress, MailEnvelop};
+use new_tokio_smtp::send_mail::{
+    fan_out_mail, Dsn, EncodingRequirement, Mail, MailAddress, MailEnvelop, RecipientComparison,
+    SendOperation, SendOperationError,
+};
+use new_tokio_smtp::{command, ClientId, Connection, SizeLimit};
 
 use self::ActionData::*;
 use self::Actor::*;
@@ -41,6 +47,47 @@ fn creates_the_right_chain() {
         .unwrap();
 }
 
+#[test]
+fn sends_mail_to_duplicate_recipients_only_once() {
+    let con = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (Client, Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let envelop = MailEnvelop::new(
+        MailAddress::from_unchecked("t1@test.test"),
+        vec1![
+            MailAddress::from_unchecked("t2@test.test"),
+            MailAddress::from_unchecked("t2@TEST.test"),
+        ],
+        Mail::new(EncodingRequirement::None, Vec::from("the data\r\n")),
+    )
+    .dedup_recipients(RecipientComparison::default());
+
+    let collapsed = envelop
+        .collapsed_duplicates()
+        .iter()
+        .map(MailAddress::as_str)
+        .collect::<Vec<_>>();
+    assert_eq!(collapsed, vec!["t2@TEST.test"]);
+
+    con.send_mail(envelop)
+        .and_then(|(con, result)| {
+            result.unwrap();
+            con.quit()
+        })
+        .wait()
+        .unwrap();
+}
+
 #[test]
 fn uses_smtputf8_for_internationalized_mail_addresses() {
     let con = mock(vec![
@@ -75,3 +122,414 @@ fn uses_smtputf8_for_internationalized_mail_addresses() {
         .wait()
         .unwrap();
 }
+
+#[test]
+fn runs_send_operation_with_dsn_notify() {
+    let con = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (
+            Client,
+            Lines(vec!["RCPT TO:<t2@test.test> NOTIFY=SUCCESS,FAILURE"]),
+        ),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (
+            Client,
+            Blob(Vec::from("the data\r\n..stashed\r\n.\r\n".to_owned())),
+        ),
+        (Server, Lines(vec!["250 Ok: queued"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let con = with_capability(con, "DSN");
+
+    let op = SendOperation::new()
+        .from(MailAddress::from_unchecked("t1@test.test"))
+        .to(vec![MailAddress::from_unchecked("t2@test.test")])
+        .dsn(Dsn {
+            ret: None,
+            notify: Some(Notify::On {
+                success: true,
+                failure: true,
+                delay: false,
+            }),
+            ..Default::default()
+        })
+        .size_hint(1024)
+        .body("the data\r\n.stashed\r\n")
+        .build()
+        .unwrap();
+
+    op.exec(con)
+        .and_then(|(con, result)| {
+            let responses = result.unwrap();
+            assert_eq!(responses.recipients.len(), 1);
+            assert_eq!(responses.data.msg()[0], "Ok: queued");
+            con.quit()
+        })
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn send_operation_generates_envid() {
+    let build_op = || {
+        SendOperation::new()
+            .from(MailAddress::from_unchecked("t1@test.test"))
+            .to(vec![MailAddress::from_unchecked("t2@test.test")])
+            .dsn(Dsn {
+                auto_envid: true,
+                ..Default::default()
+            })
+            .body("the data\r\n")
+            .build()
+            .unwrap()
+    };
+    let op = build_op();
+    let envid = op.envid().unwrap().to_owned();
+    assert_ne!(build_op().envid().unwrap(), envid);
+
+    let mail_line = format!("MAIL FROM:<t1@test.test> ENVID={}\r\n", envid);
+    let con = mock(vec![
+        (Client, Blob(mail_line.into_bytes())),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (Client, Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability(con, "DSN");
+
+    op.exec(con)
+        .and_then(move |(con, result)| {
+            let responses = result.unwrap();
+            assert_eq!(responses.envid, Some(envid));
+            con.quit()
+        })
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn send_operation_uses_notify_per_recipient() {
+    let con = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (
+            Client,
+            Lines(vec!["RCPT TO:<vip@test.test> NOTIFY=SUCCESS,FAILURE"]),
+        ),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test> NOTIFY=FAILURE"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t3@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (Client, Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server, Lines(vec!["250 Ok: queued"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability(con, "DSN");
+
+    let failure_only = Notify::On {
+        success: false,
+        failure: true,
+        delay: false,
+    };
+    let op = SendOperation::new()
+        .from(MailAddress::from_unchecked("t1@test.test"))
+        .to_with_notify(
+            MailAddress::from_unchecked("vip@test.test"),
+            Notify::On {
+                success: true,
+                failure: true,
+                delay: false,
+            },
+        )
+        .to_with_notify(MailAddress::from_unchecked("t2@test.test"), failure_only)
+        .to(vec![MailAddress::from_unchecked("t3@test.test")])
+        .body("the data\r\n")
+        .build()
+        .unwrap();
+
+    op.exec(con)
+        .and_then(|(con, result)| {
+            assert_eq!(result.unwrap().recipients.len(), 3);
+            con.quit()
+        })
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn send_operation_sends_duplicate_recipients_only_once() {
+    let con = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<T2@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (Client, Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server, Lines(vec!["250 Ok: queued"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+
+    let op = SendOperation::new()
+        .from(MailAddress::from_unchecked("t1@test.test"))
+        .to(vec![
+            MailAddress::from_unchecked("t2@test.test"),
+            MailAddress::from_unchecked("t2@TEST.test"),
+            MailAddress::from_unchecked("T2@test.test"),
+        ])
+        .dedup_recipients(RecipientComparison::default())
+        .body("the data\r\n")
+        .build()
+        .unwrap();
+
+    let collapsed = op
+        .collapsed_duplicates()
+        .iter()
+        .map(MailAddress::as_str)
+        .collect::<Vec<_>>();
+    assert_eq!(collapsed, vec!["t2@TEST.test"]);
+
+    op.exec(con)
+        .and_then(|(con, result)| {
+            assert_eq!(result.unwrap().recipients.len(), 2);
+            con.quit()
+        })
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn send_operation_keeps_notify_of_collapsed_duplicates() {
+    let con = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test> NOTIFY=FAILURE"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t3@test.test> NOTIFY=NEVER"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (Client, Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server, Lines(vec!["250 Ok: queued"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["250 Ok"])),
+    ]);
+    let con = with_capability(con, "DSN");
+
+    let failure_only = Notify::On {
+        success: false,
+        failure: true,
+        delay: false,
+    };
+    let op = SendOperation::new()
+        .from(MailAddress::from_unchecked("t1@test.test"))
+        .to(vec![MailAddress::from_unchecked("t2@test.test")])
+        .to_with_notify(MailAddress::from_unchecked("T2@TEST.test"), failure_only)
+        .to_with_notify(MailAddress::from_unchecked("t3@test.test"), Notify::Never)
+        .to_with_notify(MailAddress::from_unchecked("t3@test.test"), failure_only)
+        .dedup_recipients(RecipientComparison::CaseInsensitive)
+        .body("the data\r\n")
+        .build()
+        .unwrap();
+
+    let collapsed = op
+        .collapsed_duplicates()
+        .iter()
+        .map(MailAddress::as_str)
+        .collect::<Vec<_>>();
+    assert_eq!(collapsed, vec!["T2@TEST.test", "t3@test.test"]);
+
+    op.exec(con)
+        .and_then(|(con, result)| {
+            assert_eq!(result.unwrap().recipients.len(), 2);
+            con.quit()
+        })
+        .wait()
+        .unwrap();
+}
+
+#[test]
+fn send_operation_validates_up_front() {
+    let res = SendOperation::new()
+        .from(MailAddress::from_unchecked("t1@test.test"))
+        .body("the data\r\n")
+        .build();
+    assert_eq!(res.unwrap_err(), SendOperationError::NoRecipients);
+
+    let res = SendOperation::new()
+        .to(vec![MailAddress::from_unchecked("t2@test.test")])
+        .size_hint(2)
+        .body("the data\r\n")
+        .build();
+    assert_eq!(
+        res.unwrap_err(),
+        SendOperationError::SizeHintTooSmall {
+            size_hint: 2,
+            body_size: 10
+        }
+    );
+}
+
+fn mock_with_size(size: &'static str, conv: Vec<(Actor, ActionData)>) -> Connection {
+    let mut full_conv = vec![
+        (Client, Lines(vec!["EHLO me.test"])),
+        (Server, Lines(vec!["250-they.test", size])),
+    ];
+    full_conv.extend(conv);
+    let con = mock(full_conv);
+    let ehlo = command::Ehlo::new(ClientId::Domain("me.test".parse().unwrap()));
+    let (con, result) = con.send(ehlo).wait().unwrap();
+    result.unwrap();
+    con
+}
+
+fn sized_operation() -> SendOperation {
+    SendOperation::new()
+        .from(MailAddress::from_unchecked("t1@test.test"))
+        .to(vec![MailAddress::from_unchecked("t2@test.test")])
+        .body("the data\r\n")
+        .build()
+        .unwrap()
+}
+
+fn accepting_conversation(mail_line: &'static str) -> Vec<(Actor, ActionData)> {
+    vec![
+        (Client, Lines(vec![mail_line])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<t2@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (Client, Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]
+}
+
+#[test]
+fn send_operation_does_not_pre_check_without_size() {
+    let con = mock_with_size(
+        "250 SMTPUTF8",
+        accepting_conversation("MAIL FROM:<t1@test.test>"),
+    );
+    assert_eq!(con.ehlo_data().unwrap().size_limit(), None);
+
+    let (con, result) = sized_operation().exec(con).wait().unwrap();
+    result.unwrap();
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn send_operation_does_not_pre_check_with_size_zero() {
+    let con = mock_with_size(
+        "250 SIZE 0",
+        accepting_conversation("MAIL FROM:<t1@test.test>"),
+    );
+    assert_eq!(
+        con.ehlo_data().unwrap().size_limit(),
+        Some(SizeLimit::Unlimited)
+    );
+
+    let (con, result) = sized_operation().exec(con).wait().unwrap();
+    result.unwrap();
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn send_operation_enforces_size_limit() {
+    let con = mock_with_size(
+        "250 SIZE 8",
+        vec![
+            (Client, Lines(vec!["QUIT"])),
+            (Server, Lines(vec!["221 Bye"])),
+        ],
+    );
+    assert_eq!(
+        con.ehlo_data().unwrap().size_limit(),
+        Some(SizeLimit::Limit(8))
+    );
+
+    let (con, result) = sized_operation().exec(con).wait().unwrap();
+    match result {
+        Err((0, LogicError::Custom(err))) => {
+            let err = err.downcast_ref::<MessageTooLarge>().unwrap();
+            assert_eq!(*err, MessageTooLarge::new(10, 8));
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    con.quit().wait().unwrap();
+}
+
+#[test]
+fn fan_out_distributes_recipients_across_connections() {
+    let first = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<r1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<r2@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["DATA"])),
+        (Server, Lines(vec!["354 ..."])),
+        (Client, Blob(Vec::from("the data\r\n.\r\n".to_owned()))),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+    let second = mock(vec![
+        (Client, Lines(vec!["MAIL FROM:<t1@test.test>"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["RCPT TO:<r3@test.test>"])),
+        (Server, Lines(vec!["550 no such user"])),
+        (Client, Lines(vec!["RSET"])),
+        (Server, Lines(vec!["250 Ok"])),
+        (Client, Lines(vec!["QUIT"])),
+        (Server, Lines(vec!["221 Bye"])),
+    ]);
+
+    let envelop = MailEnvelop::new(
+        MailAddress::from_unchecked("t1@test.test"),
+        vec1![
+            MailAddress::from_unchecked("r1@test.test"),
+            MailAddress::from_unchecked("r2@test.test"),
+            MailAddress::from_unchecked("r3@test.test"),
+        ],
+        Mail::new(EncodingRequirement::None, Vec::from("the data\r\n")),
+    );
+
+    let mut cons = vec![first, second].into_iter();
+    let batches = fan_out_mail(move || future::ok(cons.next().unwrap()), envelop, 2)
+        .wait()
+        .unwrap();
+
+    assert_eq!(batches.len(), 2);
+    let recipients = |idx: usize| {
+        batches[idx]
+            .recipients
+            .iter()
+            .map(|addr| addr.as_str().to_owned())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(recipients(0), vec!["r1@test.test", "r2@test.test"]);
+    assert_eq!(recipients(1), vec!["r3@test.test"]);
+    assert!(batches[0].result.is_ok());
+    assert!(batches[1].result.is_err());
+}